@@ -0,0 +1,197 @@
+use expr::{Expr, Val};
+use runtime_error::RuntimeError;
+use std::result;
+
+pub type Result<T> = result::Result<T, RuntimeError>;
+
+// Functions provided by the interpreter. A builtin name only resolves when no
+// variable of that name is in scope, so programs are free to shadow them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+  Len,
+  Slice,
+  Str,
+}
+
+impl Builtin {
+  pub fn from_name(name: &str) -> Option<Builtin> {
+    match name {
+      "len" => Some(Builtin::Len),
+      "slice" => Some(Builtin::Slice),
+      "str" => Some(Builtin::Str),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Builtin::Len => "len",
+      Builtin::Slice => "slice",
+      Builtin::Str => "str",
+    }
+  }
+
+  fn arity(&self) -> usize {
+    match *self {
+      Builtin::Len => 1,
+      Builtin::Slice => 3,
+      Builtin::Str => 1,
+    }
+  }
+
+  pub fn call(&self, args: Vec<Val>) -> Result<Val> {
+    if args.len() != self.arity() {
+      return Err(RuntimeError::ArityMismatch(self.name().to_string(), self.arity(), args.len()));
+    }
+
+    let mut args = args.into_iter();
+    let mut arg = || args.next().unwrap_or(Val::Undefined);
+
+    match *self {
+      Builtin::Len => match arg() {
+        Val::Str(s) => Ok(Val::Int(s.chars().count() as isize)),
+        Val::List(xs) => Ok(Val::Int(xs.len() as isize)),
+        Val::Range(start, end) => Ok(Val::Int(end.saturating_sub(start).max(0))),
+        v => Err(expected("str, list or range", v)),
+      },
+      Builtin::Slice => match (arg(), arg(), arg()) {
+        (Val::Str(s), Val::Int(start), Val::Int(end)) => {
+          let chars: Vec<char> = s.chars().collect();
+          let start = bounded(start, chars.len())?;
+          let end = bounded(end, chars.len())?;
+
+          if start >= end {
+            return Ok(Val::Str(String::new()));
+          }
+
+          Ok(Val::Str(chars[start..end].iter().collect()))
+        },
+        (Val::List(xs), Val::Int(start), Val::Int(end)) => {
+          let start = bounded(start, xs.len())?;
+          let end = bounded(end, xs.len())?;
+
+          if start >= end {
+            return Ok(Val::List(Vec::new()));
+          }
+
+          Ok(Val::List(xs[start..end].to_vec()))
+        },
+        (Val::List(_), Val::Int(_), v) => Err(expected("int", v)),
+        (Val::List(_), v, _) => Err(expected("int", v)),
+        (Val::Str(_), Val::Int(_), v) => Err(expected("int", v)),
+        (Val::Str(_), v, _) => Err(expected("int", v)),
+        (v, _, _) => Err(expected("str or list", v)),
+      },
+      Builtin::Str => match arg() {
+        s @ Val::Str(_) => Ok(s),
+        v => Ok(Val::Str(format!("{}", v))),
+      },
+    }
+  }
+}
+
+// `v[i]`, counting in characters for strings
+pub fn index(v: Val, i: Val) -> Result<Val> {
+  match (v, i) {
+    (Val::List(mut xs), Val::Int(n)) => {
+      let i = in_bounds(n, xs.len())?;
+      Ok(xs.swap_remove(i))
+    },
+    (Val::List(_), i) => Err(expected("int", i)),
+    (Val::Str(s), Val::Int(n)) => {
+      let len = s.chars().count();
+
+      match s.chars().nth(in_bounds(n, len)?) {
+        Some(c) => Ok(Val::Str(c.to_string())),
+        None => Err(RuntimeError::IndexOutOfBounds(n, len)),
+      }
+    },
+    (Val::Str(_), i) => Err(expected("int", i)),
+    (v, _) => Err(expected("list or str", v)),
+  }
+}
+
+// `v.name`
+pub fn field(v: Val, name: &str) -> Result<Val> {
+  match v {
+    Val::Record(mut fields) => match fields.remove(name) {
+      Some(x) => Ok(x),
+      None => Err(RuntimeError::FieldNotFound(name.to_string())),
+    },
+    v => Err(expected("record", v)),
+  }
+}
+
+// `v.name = x`, returning the updated copy of `v`. Only existing fields can
+// be assigned, so a misspelled name is an error rather than a new field.
+pub fn set_field(v: Val, name: &str, x: Val) -> Result<Val> {
+  match v {
+    Val::Record(mut fields) => match fields.get_mut(name) {
+      Some(slot) => {
+        *slot = x;
+        Ok(Val::Record(fields))
+      },
+      None => Err(RuntimeError::FieldNotFound(name.to_string())),
+    },
+    v => Err(expected("record", v)),
+  }
+}
+
+// `v[i] = x`, returning the updated copy of `v`
+pub fn set_index(v: Val, i: Val, x: Val) -> Result<Val> {
+  match (v, i) {
+    (Val::List(mut xs), Val::Int(n)) => {
+      let i = in_bounds(n, xs.len())?;
+      xs[i] = x;
+      Ok(Val::List(xs))
+    },
+    (Val::List(_), i) => Err(expected("int", i)),
+    (v, _) => Err(expected("list", v)),
+  }
+}
+
+// The item at an offset into a collection a `for` loop can iterate over,
+// and the offset of the item after it, or None when there are no more.
+// Strings iterate by character, at byte offsets.
+pub fn item_at(v: &Val, at: usize) -> Result<Option<(Val, usize)>> {
+  match *v {
+    Val::Range(start, end) => match start.checked_add(at as isize) {
+      Some(n) if n < end => Ok(Some((Val::Int(n), at + 1))),
+      _ => Ok(None),
+    },
+    Val::List(ref xs) => Ok(xs.get(at).map(|x| (x.clone(), at + 1))),
+    Val::Str(ref s) => Ok(s[at..].chars().next().map(|c| (Val::Str(c.to_string()), at + c.len_utf8()))),
+    ref v => Err(expected("list", v.clone())),
+  }
+}
+
+// what is left of a collection from an offset given by item_at
+pub fn items_from(v: &Val, at: usize) -> Val {
+  match *v {
+    Val::Range(start, end) => Val::Range(start.saturating_add(at as isize), end),
+    Val::List(ref xs) => Val::List(xs[at.min(xs.len())..].to_vec()),
+    Val::Str(ref s) => Val::Str(s[at.min(s.len())..].to_string()),
+    ref v => v.clone(),
+  }
+}
+
+fn in_bounds(n: isize, len: usize) -> Result<usize> {
+  if n < 0 || n as usize >= len {
+    return Err(RuntimeError::IndexOutOfBounds(n, len));
+  }
+
+  Ok(n as usize)
+}
+
+// a slice bound may point one past the last element
+fn bounded(n: isize, len: usize) -> Result<usize> {
+  if n < 0 || n as usize > len {
+    return Err(RuntimeError::IndexOutOfBounds(n, len));
+  }
+
+  Ok(n as usize)
+}
+
+fn expected(t: &str, v: Val) -> RuntimeError {
+  RuntimeError::InvalidTypeConversion(t.to_string(), Expr::Val(v))
+}
@@ -0,0 +1,341 @@
+use builtins;
+use expr::{BinOp, Dec, Expr, Val};
+use interpreter::{
+    apply_bop, apply_uop, assign_to, lookup, match_pattern, place_base, read_place, Interpreter, Limits, Result,
+};
+use parser::parser::parse;
+use runtime_error::RuntimeError;
+use state::Env;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+// Evaluates programs, keeping the bindings each one makes for the next, like
+// a REPL. Implementations agree on the value or error of every program.
+pub trait Evaluator {
+    fn eval(&mut self, input: &str) -> Result<Expr>;
+}
+
+impl Evaluator for Interpreter {
+    fn eval(&mut self, input: &str) -> Result<Expr> {
+        Interpreter::eval(self, input)
+    }
+}
+
+// Evaluates a program by recursing over it, passing the environment down
+// instead of rewriting the program a step at a time. Unlike an Interpreter,
+// it has no fuel, timeout or cancellation and its errors have no backtraces.
+// Like the small step engine, it recurses on the native stack for each
+// function call, up to the default max_depth of an Interpreter.
+#[derive(Default)]
+pub struct BigStep {
+    pub env: Env,
+    limits: Limits,
+}
+
+impl BigStep {
+    pub fn new() -> BigStep {
+        BigStep::default()
+    }
+}
+
+impl Evaluator for BigStep {
+    fn eval(&mut self, input: &str) -> Result<Expr> {
+        let e = parse(input)?;
+        let calls = Calls {
+            limits: &self.limits,
+            depth: 0,
+        };
+
+        eval_expr(&e, &self.env, calls).map(Expr::Val)
+    }
+}
+
+// the function calls in progress, which may be no more than the limits allow
+#[derive(Clone, Copy)]
+struct Calls<'a> {
+    limits: &'a Limits,
+    depth: usize,
+}
+
+// the loop body's value is dropped, and break and continue are handled by
+// the loop
+enum Flow {
+    Next,
+    Break,
+}
+
+fn loop_body(body: &Expr, env: &Env, calls: Calls) -> Result<Flow> {
+    match eval_expr(body, env, calls) {
+        Ok(_) | Err(RuntimeError::Continue) => Ok(Flow::Next),
+        Err(RuntimeError::Break) => Ok(Flow::Break),
+        Err(err) => Err(err),
+    }
+}
+
+fn loop_condition(cond: &Expr, env: &Env, calls: Calls) -> Result<bool> {
+    match eval_expr(cond, env, calls)? {
+        Val::Bool(b) => Ok(b),
+        v => Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v))),
+    }
+}
+
+// an assignment target with its indexes evaluated, from its base outwards
+fn eval_place(place: &Expr, env: &Env, calls: Calls) -> Result<Expr> {
+    match *place {
+        Expr::Index(ref p, ref i) => {
+            let p = eval_place(p, env, calls)?;
+            let i = eval_expr(i, env, calls)?;
+            Ok(Expr::Index(Box::new(p), Box::new(Expr::Val(i))))
+        }
+        Expr::Field(ref p, ref name) => Ok(Expr::Field(Box::new(eval_place(p, env, calls)?), name.clone())),
+        ref e => Ok(e.clone()),
+    }
+}
+
+fn eval_expr(e: &Expr, env: &Env, calls: Calls) -> Result<Val> {
+    match *e {
+        Expr::Val(ref v) => Ok(v.clone()),
+        Expr::Var(ref x) => lookup(env, x),
+        // errors keep the span of the innermost expression that raised them
+        Expr::Located(span, ref e1) => eval_expr(e1, env, calls).map_err(|err| err.at(span)),
+        Expr::Uop(ref op, ref e1) => apply_uop(op.clone(), eval_expr(e1, env, calls)?),
+        Expr::Bop(ref op, ref e1, ref e2) => eval_bop(op, e1, e2, env, calls),
+        Expr::AssignOp(ref op, ref place, ref e2) => match place_base(place) {
+            Expr::Var(_) => {
+                let place = eval_place(place, env, calls)?;
+                let v1 = read_place(env, &place, lookup)?;
+                let v = apply_bop(op.clone(), v1, eval_expr(e2, env, calls)?)?;
+                assign_to(env, &place, v.clone())?;
+                Ok(v)
+            }
+            base => Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), base.clone())),
+        },
+        Expr::Decl(ref dt, ref addr, ref e1, ref e2) => {
+            let v = Expr::Val(eval_expr(e1, env, calls)?);
+
+            match (dt, &**addr) {
+                (&Dec::DConst, Expr::Var(x)) => env.alloc_const(x.clone(), v)?,
+                (&Dec::DVar, Expr::Var(x)) => env.alloc(x.clone(), v)?,
+                _ => return Err(RuntimeError::SteppingOnValue(v)),
+            }
+
+            eval_expr(e2, env, calls)
+        }
+        Expr::Ternary(ref e1, ref e2, ref e3) => match eval_expr(e1, env, calls)? {
+            Val::Bool(true) => eval_expr(e2, env, calls),
+            Val::Bool(false) => eval_expr(e3, env, calls),
+            v => Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+        },
+        // the loop body runs in the same frame, like the condition
+        Expr::While(_, ref cond, _, ref body, ref rest) => {
+            while loop_condition(cond, env, calls)? {
+                if let Flow::Break = loop_body(body, env, calls)? {
+                    break;
+                }
+            }
+
+            eval_expr(rest, env, calls)
+        }
+        // each iteration binds the loop variable in a fresh frame, so closures
+        // created in the body see their own value
+        Expr::ForIn(ref x, ref iter, ref body, ref rest) => {
+            let items = eval_expr(iter, env, calls)?;
+            let mut at = 0;
+
+            while let Some((item, next)) = builtins::item_at(&items, at)? {
+                let inner = env.extend();
+
+                match **x {
+                    Expr::Var(ref x) => inner.alloc(x.clone(), Expr::Val(item))?,
+                    ref e => return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone())),
+                }
+
+                if let Flow::Break = loop_body(body, &inner, calls)? {
+                    break;
+                }
+
+                at = next;
+            }
+
+            eval_expr(rest, env, calls)
+        }
+        Expr::For(_, ref cond, ref update, ref body, ref rest) => {
+            while loop_condition(cond, env, calls)? {
+                if let Flow::Break = loop_body(body, &env.extend(), calls)? {
+                    break;
+                }
+
+                eval_expr(update, env, calls)?;
+            }
+
+            eval_expr(rest, env, calls)
+        }
+        Expr::Match(ref e1, ref arms) => {
+            let v = eval_expr(e1, env, calls)?;
+
+            for (pattern, guard, body) in arms {
+                let mut bindings = Vec::new();
+
+                if !match_pattern(pattern, &v, &mut bindings) {
+                    continue;
+                }
+
+                // like a function call, the bindings live in their own frame
+                let inner = env.extend();
+
+                for (x, v) in bindings {
+                    inner.alloc(x, Expr::Val(v))?;
+                }
+
+                let matched = match *guard {
+                    Some(ref guard) => match eval_expr(guard, &inner, calls)? {
+                        Val::Bool(b) => b,
+                        v => return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v))),
+                    },
+                    None => true,
+                };
+
+                if matched {
+                    return eval_expr(body, &inner, calls);
+                }
+            }
+
+            Err(RuntimeError::NoMatchingArm(Expr::Val(v)))
+        }
+        Expr::Throw(ref e1) => Err(RuntimeError::Thrown(eval_expr(e1, env, calls)?)),
+        Expr::Try(ref body, ref x, ref handler) => match eval_expr(body, env, calls) {
+            Ok(v) => Ok(v),
+            Err(err) => match (err.to_val(), &**x) {
+                (Some(v), Expr::Var(x)) => {
+                    let inner = env.extend();
+                    inner.alloc(x.clone(), Expr::Val(v))?;
+                    eval_expr(handler, &inner, calls)
+                }
+                _ => Err(err),
+            },
+        },
+        Expr::Return(ref e1) => Err(RuntimeError::Return(eval_expr(e1, env, calls)?)),
+        Expr::Break => Err(RuntimeError::Break),
+        Expr::Continue => Err(RuntimeError::Continue),
+        Expr::Print(ref e1) => {
+            println!("{}", eval_expr(e1, env, calls)?);
+            Ok(Val::Undefined)
+        }
+        Expr::Lambda(ref name, ref body, ref params) => {
+            Ok(Val::Func(name.clone(), Rc::new((**body).clone()), params.clone(), env.clone()))
+        }
+        Expr::FnCall(ref f, ref args) => {
+            // the callee is checked before its arguments are evaluated
+            let f = match eval_expr(f, env, calls)? {
+                f @ Val::Func(..) | f @ Val::Builtin(_) => f,
+                v => return Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+            };
+
+            let mut vs = Vec::new();
+            for arg in args {
+                vs.push(eval_expr(arg, env, calls)?);
+            }
+
+            call(f, vs, calls)
+        }
+        Expr::List(ref es) => {
+            let mut vs = Vec::new();
+            for e in es {
+                vs.push(eval_expr(e, env, calls)?);
+            }
+
+            Ok(Val::List(vs))
+        }
+        Expr::Record(ref fields) => {
+            let mut record = BTreeMap::new();
+            for (k, e) in fields {
+                record.insert(k.clone(), eval_expr(e, env, calls)?);
+            }
+
+            Ok(Val::Record(record))
+        }
+        Expr::Index(ref e1, ref e2) => {
+            let v = eval_expr(e1, env, calls)?;
+            builtins::index(v, eval_expr(e2, env, calls)?)
+        }
+        Expr::Field(ref e1, ref name) => builtins::field(eval_expr(e1, env, calls)?, name),
+        // only made by the small step interpreter while it runs
+        Expr::Items(..) | Expr::Iteration(..) | Expr::Scope(_) | Expr::Block(_) | Expr::MatchArm(..) => {
+            Err(RuntimeError::UnexpectedExpr("parsed expression".to_string(), e.clone()))
+        }
+    }
+}
+
+fn eval_bop(op: &BinOp, e1: &Expr, e2: &Expr, env: &Env, calls: Calls) -> Result<Val> {
+    match (op, e1) {
+        (&BinOp::Seq, _) => {
+            eval_expr(e1, env, calls)?;
+            eval_expr(e2, env, calls)
+        }
+        // && and || only evaluate their right side when the left side doesn't
+        // already decide the result
+        (&BinOp::And, _) | (&BinOp::Or, _) => match (op, eval_expr(e1, env, calls)?) {
+            (&BinOp::And, Val::Bool(false)) => Ok(Val::Bool(false)),
+            (&BinOp::Or, Val::Bool(true)) => Ok(Val::Bool(true)),
+            (op, v1 @ Val::Bool(_)) => apply_bop(op.clone(), v1, eval_expr(e2, env, calls)?),
+            (_, v1) => Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v1))),
+        },
+        (&BinOp::Assign, Expr::Var(x)) => {
+            let v = eval_expr(e2, env, calls)?;
+            env.assign(x.clone(), Expr::Val(v.clone()))?;
+            Ok(v)
+        }
+        // the indexes of an assignment target are evaluated before the value
+        (&BinOp::Assign, Expr::Index(..)) | (&BinOp::Assign, Expr::Field(..)) => match place_base(e1) {
+            Expr::Var(_) => {
+                let place = eval_place(e1, env, calls)?;
+                let v = eval_expr(e2, env, calls)?;
+                assign_to(env, &place, v.clone())?;
+                Ok(v)
+            }
+            base => Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), base.clone())),
+        },
+        _ => {
+            let v1 = eval_expr(e1, env, calls)?;
+            apply_bop(op.clone(), v1, eval_expr(e2, env, calls)?)
+        }
+    }
+}
+
+// binds the arguments like the small step interpreter does, without
+// checking their number
+fn call(f: Val, args: Vec<Val>, calls: Calls) -> Result<Val> {
+    match f {
+        Val::Func(name, body, params, env) => {
+            calls.limits.check_depth(calls.depth)?;
+            let calls = Calls {
+                depth: calls.depth + 1,
+                ..calls
+            };
+
+            let inner = env.extend();
+
+            for (x, v) in params.iter().zip(args) {
+                match *x {
+                    Expr::Var(ref x) => inner.alloc(x.clone(), Expr::Val(v))?,
+                    ref e => return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone())),
+                }
+            }
+
+            // a named function can call itself
+            if let Some(ref n) = name {
+                if let Expr::Var(ref s) = **n {
+                    let f = Val::Func(name.clone(), body.clone(), params.clone(), env.clone());
+                    inner.alloc(s.clone(), Expr::Val(f))?;
+                }
+            }
+
+            match eval_expr(&body, &inner, calls) {
+                Err(RuntimeError::Return(v)) => Ok(v),
+                result => result,
+            }
+        }
+        Val::Builtin(b) => b.call(args),
+        v => Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+    }
+}
@@ -1,9 +1,16 @@
+use builtins::Builtin;
+use span::Span;
+use state::Env;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::rc::Rc;
+use unparser;
 
 #[derive(Clone, Debug, PartialEq)] 
 pub enum UnOp {
   Not,
   Neg,
+  BitNot,
 }
 
 #[derive(Clone, Debug, PartialEq)] 
@@ -21,8 +28,16 @@ pub enum BinOp {
   And,
   Or,
   Mod,
+  Pow,
+  BitAnd,
+  BitOr,
+  BitXor,
+  Shl,
+  Shr,
   Seq,
   Assign,
+  Range,
+  RangeInclusive,
 }
 
 #[derive(Clone, Debug, PartialEq)] 
@@ -34,31 +49,215 @@ pub enum Dec {
 #[derive(Clone, Debug, PartialEq)] 
 pub enum Val {
   Int(isize),
+  Float(f64),
   Bool(bool),
+  Str(String),
+  List(Vec<Val>),
+  Record(BTreeMap<String, Val>),
+  // the integers from start up to but not including end
+  Range(isize, isize),
   Undefined,
-  Func(Option<Box<Expr>>, Box<Expr>, Vec<Expr>),
+  // name, body, params, and the environment captured where it was defined.
+  // The body is shared, so the vm can cache the code it compiles it to.
+  Func(Option<Box<Expr>>, Rc<Expr>, Vec<Expr>, Env),
+  Builtin(Builtin),
 }
 
-#[derive(Clone, Debug, PartialEq)] 
+// the left hand side of a match arm
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+  // `_` matches anything
+  Wildcard,
+  // a name matches anything, and binds it
+  Bind(String),
+  // an int, float, bool or string literal matches an equal value
+  Lit(Val),
+  // `[p1, p2]` matches a list of exactly two items, and `[p1, ..rest]` a list
+  // of at least one, with the remaining items matched against `rest`
+  List(Vec<Pattern>, Option<Box<Pattern>>),
+  // `{ x: p1, y }` matches a record with at least the fields x and y
+  Record(Vec<(String, Pattern)>),
+}
+
+// PartialEq is implemented by hand below so that spans are ignored
+#[derive(Clone, Debug)] 
 pub enum Expr {
   Val(Val),
   Var(String),
   Bop(BinOp, Box<Expr>, Box<Expr>),
   Uop(UnOp, Box<Expr>),
+  // `place op= e`, which steps to `place = place op e` once the indexes in
+  // place are values, so that they are only evaluated once
+  AssignOp(BinOp, Box<Expr>, Box<Expr>),
+  // a function literal; steps to a Val::Func closing over the current scope
+  Lambda(Option<Box<Expr>>, Box<Expr>, Vec<Expr>),
   Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
   While(Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
+  // `for (x in e) body; rest`: the loop variable, the collection to iterate
+  // over, the body and the rest of the program
+  ForIn(Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
+  // a ForIn whose collection is a value, with the offset of the next item
+  // in it, so that each iteration doesn't copy what is left
+  Items(Box<Expr>, Box<Val>, usize, Box<Expr>, Box<Expr>),
+  // `for (init; cond; update) body; rest`, after init has run. Like While,
+  // the condition being stepped is followed by the original to restart from.
+  For(Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
+  // a loop body running in its own frame, then the update to run after it
+  // and the loop to go back to
+  Iteration(Box<Expr>, Box<Expr>, Box<Expr>),
   Decl(Dec, Box<Expr>, Box<Expr>, Box<Expr>),
   FnCall(Box<Expr>, Vec<Expr>),
+  List(Vec<Expr>),
+  Record(Vec<(String, Expr)>),
+  Index(Box<Expr>, Box<Expr>),
+  Field(Box<Expr>, String),
   Scope(Box<Expr>),
   Print(Box<Expr>),
+  Return(Box<Expr>),
+  Break,
+  Continue,
+  Throw(Box<Expr>),
+  // `try { body } catch (x) { handler }`
+  Try(Box<Expr>, Box<Expr>, Box<Expr>),
+  // an expression running in its own frame, which is left however the
+  // expression finishes
+  Block(Box<Expr>),
+  // the value being matched, and the arms left to try: each a pattern, an
+  // optional guard and a body
+  Match(Box<Expr>, Vec<(Pattern, Option<Expr>, Expr)>),
+  // a match arm whose pattern matched, running in a frame holding its
+  // bindings: the guard, the body, and the match over the remaining arms to
+  // fall back to if the guard is false
+  MatchArm(Box<Expr>, Box<Expr>, Box<Expr>),
+  // the span of source the parser built an expression from
+  Located(Span, Box<Expr>),
+}
+
+impl Expr {
+  // the expression without its outermost spans
+  pub fn unlocated(self) -> Expr {
+    match self {
+      Expr::Located(_, e) => e.unlocated(),
+      e => e,
+    }
+  }
+
+  // An assignment target without spans along its spine, so `r.a[i] = x`
+  // matches on Field(Index(Var ..)) directly. The index expressions keep theirs.
+  pub fn unlocated_place(self) -> Expr {
+    match self.unlocated() {
+      Expr::Index(e1, e2) => Expr::Index(Box::new(e1.unlocated_place()), e2),
+      Expr::Field(e1, name) => Expr::Field(Box::new(e1.unlocated_place()), name),
+      e => e,
+    }
+  }
+}
+
+// Two expressions are equal when they have the same shape, wherever in the
+// source they came from.
+impl PartialEq for Expr {
+  fn eq(&self, other: &Expr) -> bool {
+    use self::Expr::*;
+
+    match (self, other) {
+      (Located(_, e1), e2) => **e1 == *e2,
+      (e1, Located(_, e2)) => *e1 == **e2,
+      (Val(v1), Val(v2)) => v1 == v2,
+      (Var(x1), Var(x2)) => x1 == x2,
+      (Bop(op1, a1, b1), Bop(op2, a2, b2)) => {
+        op1 == op2 && a1 == a2 && b1 == b2
+      },
+      (Uop(op1, a1), Uop(op2, a2)) => op1 == op2 && a1 == a2,
+      (AssignOp(op1, a1, b1), AssignOp(op2, a2, b2)) => {
+        op1 == op2 && a1 == a2 && b1 == b2
+      },
+      (Lambda(n1, b1, p1), Lambda(n2, b2, p2)) => {
+        n1 == n2 && b1 == b2 && p1 == p2
+      },
+      (Ternary(a1, b1, c1), Ternary(a2, b2, c2)) => {
+        a1 == a2 && b1 == b2 && c1 == c2
+      },
+      (While(a1, b1, c1, d1, e1), While(a2, b2, c2, d2, e2)) => {
+        a1 == a2 && b1 == b2 && c1 == c2 && d1 == d2 && e1 == e2
+      },
+      (ForIn(a1, b1, c1, d1), ForIn(a2, b2, c2, d2)) => {
+        a1 == a2 && b1 == b2 && c1 == c2 && d1 == d2
+      },
+      (Items(a1, v1, n1, c1, d1), Items(a2, v2, n2, c2, d2)) => {
+        a1 == a2 && v1 == v2 && n1 == n2 && c1 == c2 && d1 == d2
+      },
+      (For(a1, b1, c1, d1, e1), For(a2, b2, c2, d2, e2)) => {
+        a1 == a2 && b1 == b2 && c1 == c2 && d1 == d2 && e1 == e2
+      },
+      (Iteration(a1, b1, c1), Iteration(a2, b2, c2)) => a1 == a2 && b1 == b2 && c1 == c2,
+      (Decl(d1, a1, b1, c1), Decl(d2, a2, b2, c2)) => {
+        d1 == d2 && a1 == a2 && b1 == b2 && c1 == c2
+      },
+      (FnCall(f1, args1), FnCall(f2, args2)) => f1 == f2 && args1 == args2,
+      (List(xs1), List(xs2)) => xs1 == xs2,
+      (Record(fs1), Record(fs2)) => fs1 == fs2,
+      (Index(a1, i1), Index(a2, i2)) => a1 == a2 && i1 == i2,
+      (Field(a1, n1), Field(a2, n2)) => a1 == a2 && n1 == n2,
+      (Scope(e1), Scope(e2)) => e1 == e2,
+      (Print(e1), Print(e2)) => e1 == e2,
+      (Return(e1), Return(e2)) => e1 == e2,
+      (Break, Break) => true,
+      (Continue, Continue) => true,
+      (Throw(e1), Throw(e2)) => e1 == e2,
+      (Try(a1, b1, c1), Try(a2, b2, c2)) => a1 == a2 && b1 == b2 && c1 == c2,
+      (Block(e1), Block(e2)) => e1 == e2,
+      (Match(e1, arms1), Match(e2, arms2)) => e1 == e2 && arms1 == arms2,
+      (MatchArm(a1, b1, c1), MatchArm(a2, b2, c2)) => a1 == a2 && b1 == b2 && c1 == c2,
+      _ => false,
+    }
+  }
 }
 
 impl fmt::Display for Val {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match *self {
       Val::Int(n) => write!(f, "{}", n),
+      // Debug keeps the fractional part (`1.0`) and prints `inf` and `NaN`
+      Val::Float(n) => write!(f, "{:?}", n),
       Val::Bool(b) => write!(f, "{}", b),
-      _ => write!(f, "cannot print this thing")
+      Val::Str(ref s) => write!(f, "{}", s),
+      Val::List(ref xs) => {
+        write!(f, "[")?;
+        for (i, x) in xs.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          write_nested(f, x)?;
+        }
+        write!(f, "]")
+      },
+      Val::Record(ref fields) => {
+        write!(f, "{{")?;
+        for (i, (k, v)) in fields.iter().enumerate() {
+          if i > 0 {
+            write!(f, ",")?;
+          }
+          write!(f, " {}: ", k)?;
+          write_nested(f, v)?;
+        }
+        if fields.is_empty() {
+          write!(f, "}}")
+        } else {
+          write!(f, " }}")
+        }
+      },
+      Val::Range(start, end) => write!(f, "{}..{}", start, end),
+      Val::Undefined => write!(f, "undefined"),
+      Val::Func(ref name, ref body, ref params, _) => unparser::write_fn(f, name, body, params),
+      Val::Builtin(ref b) => write!(f, "{}", b.name()),
     }
   }
 }
+
+// quote nested strings so that `["a, b"]` reads unambiguously
+fn write_nested(f: &mut fmt::Formatter, v: &Val) -> fmt::Result {
+  match *v {
+    Val::Str(ref s) => write!(f, "{:?}", s),
+    ref v => write!(f, "{}", v),
+  }
+}
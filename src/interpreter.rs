@@ -1,122 +1,597 @@
+use builtins;
+use builtins::Builtin;
 use expr::BinOp::*;
 use expr::Dec::*;
+use expr::{BinOp, Dec, Pattern, UnOp, Val};
 use expr::Expr;
 use expr::Expr::*;
 use expr::UnOp::*;
 use expr::Val::*;
 use parser::parser::parse;
-use runtime_error::RuntimeError;
-use state::State;
+use runtime_error::{RuntimeError, StackFrame};
+use span::Span;
+use state::{Env, State};
 use std::mem;
+use std::rc::Rc;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vm::Vm;
 
 pub type Result<T> = result::Result<T, RuntimeError>;
 
+// No rule applies to these operands. Values are never coerced, so mixing
+// ints and floats (or numbers and bools) is an error.
+fn type_mismatch(op: &BinOp, v1: Val, v2: Val) -> RuntimeError {
+    let (expected, found) = match (op, v1) {
+        (&And, Bool(_)) | (&Or, Bool(_)) => ("bool", v2),
+        (&And, v1) | (&Or, v1) => ("bool", v1),
+        (_, Int(_)) => ("int", v2),
+        (_, Float(_)) => ("float", v2),
+        (_, Str(_)) => ("str", v2),
+        (_, Val::List(_)) => ("list", v2),
+        (_, Val::Record(_)) => ("record", v2),
+        (_, v1) => ("int", v1),
+    };
+
+    RuntimeError::InvalidTypeConversion(expected.to_string(), Expr::Val(found))
+}
+
+// exponentiation by squaring, for a non-negative exponent
+fn checked_pow(mut base: isize, mut exp: isize) -> Option<isize> {
+    let mut acc: isize = 1;
+
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = acc.checked_mul(base)?;
+        }
+
+        exp >>= 1;
+
+        // squaring once more than needed could overflow needlessly
+        if exp > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+
+    Some(acc)
+}
+
+// `<<` is checked like multiplication by a power of two, and `>>` keeps
+// the sign, so it is division by a power of two rounding down
+fn shift(op: BinOp, n1: isize, n2: isize) -> Result<Val> {
+    let e = || Bop(op.clone(), Box::new(Val(Int(n1))), Box::new(Val(Int(n2))));
+
+    if n2 < 0 || n2 >= isize::BITS as isize {
+        return Err(RuntimeError::InvalidShift(e()));
+    }
+
+    let n = match op {
+        Shl => n1 << n2,
+        _ => return Ok(Int(n1 >> n2)),
+    };
+
+    match n >> n2 == n1 {
+        true => Ok(Int(n)),
+        false => Err(RuntimeError::ShiftOverflow(e())),
+    }
+}
+
+// The result of a binary operator on two values. && and || only get here
+// when the left side doesn't already decide the result.
+pub fn apply_bop(op: BinOp, v1: Val, v2: Val) -> Result<Val> {
+    let v = match (op, v1, v2) {
+        (And, Bool(true), Bool(b2)) | (Or, Bool(false), Bool(b2)) => Bool(b2),
+        // lists and records compare structurally, records regardless of
+        // field order; values of different types are never equal
+        (Eq, v1, v2) => Bool(v1 == v2),
+        (Ne, v1, v2) => Bool(v1 != v2),
+        (op @ Div, Int(n1), Int(0)) | (op @ Mod, Int(n1), Int(0)) => {
+            let e = Bop(op, Box::new(Val(Int(n1))), Box::new(Val(Int(0))));
+            return Err(RuntimeError::DivisionByZero(e));
+        }
+        (Mod, Int(n1), Int(n2)) => {
+            // rust % gives the remainder, not modulus, so move a
+            // remainder with the wrong sign over by one divisor. This
+            // can't overflow, and isize::MIN % -1 is just 0.
+            let r = n1.wrapping_rem(n2);
+
+            match r != 0 && (r < 0) != (n2 < 0) {
+                true => Int(r + n2),
+                false => Int(r),
+            }
+        }
+        (Lt, Int(n1), Int(n2)) => Bool(n1 < n2),
+        (Gt, Int(n1), Int(n2)) => Bool(n1 > n2),
+        (Leq, Int(n1), Int(n2)) => Bool(n1 <= n2),
+        (Geq, Int(n1), Int(n2)) => Bool(n1 >= n2),
+        (Plus, Int(n1), Int(n2)) => match n1.checked_add(n2) {
+            Some(n) => Int(n),
+            None => return Err(RuntimeError::IntegerOverflow),
+        },
+        (Minus, Int(n1), Int(n2)) => match n1.checked_sub(n2) {
+            Some(n) => Int(n),
+            None => return Err(RuntimeError::IntegerUnderflow),
+        },
+        (Times, Int(n1), Int(n2)) => match n1.checked_mul(n2) {
+            Some(n) => Int(n),
+            None => return Err(RuntimeError::IntegerOverflow),
+        },
+        // with zero ruled out, the only failure is isize::MIN / -1
+        (Div, Int(n1), Int(n2)) => match n1.checked_div(n2) {
+            Some(n) => Int(n),
+            None => return Err(RuntimeError::IntegerOverflow),
+        },
+        (Pow, Int(n1), Int(n2)) if n2 < 0 => {
+            let e = Bop(Pow, Box::new(Val(Int(n1))), Box::new(Val(Int(n2))));
+            return Err(RuntimeError::NegativeExponent(e));
+        }
+        (Pow, Int(n1), Int(n2)) => match checked_pow(n1, n2) {
+            Some(n) => Int(n),
+            None => return Err(RuntimeError::IntegerOverflow),
+        },
+        (BitAnd, Int(n1), Int(n2)) => Int(n1 & n2),
+        (BitOr, Int(n1), Int(n2)) => Int(n1 | n2),
+        (BitXor, Int(n1), Int(n2)) => Int(n1 ^ n2),
+        (op @ Shl, Int(n1), Int(n2)) | (op @ Shr, Int(n1), Int(n2)) => shift(op, n1, n2)?,
+        // floats follow IEEE 754: no overflow errors, x / 0.0 is
+        // infinite and 0.0 / 0.0 is NaN
+        // the same sign correction as for ints; taking the remainder a
+        // second time would lose precision and make x % inf NaN
+        (Mod, Float(n1), Float(n2)) => {
+            let r = n1 % n2;
+
+            match r != 0.0 && (r < 0.0) != (n2 < 0.0) {
+                true => Float(r + n2),
+                false => Float(r),
+            }
+        }
+        (Lt, Float(n1), Float(n2)) => Bool(n1 < n2),
+        (Gt, Float(n1), Float(n2)) => Bool(n1 > n2),
+        (Leq, Float(n1), Float(n2)) => Bool(n1 <= n2),
+        (Geq, Float(n1), Float(n2)) => Bool(n1 >= n2),
+        (Plus, Float(n1), Float(n2)) => Float(n1 + n2),
+        (Minus, Float(n1), Float(n2)) => Float(n1 - n2),
+        (Times, Float(n1), Float(n2)) => Float(n1 * n2),
+        (Div, Float(n1), Float(n2)) => Float(n1 / n2),
+        (Pow, Float(n1), Float(n2)) => Float(n1.powf(n2)),
+        (Plus, Str(s1), Str(s2)) => Str(s1 + &s2),
+        (Lt, Str(s1), Str(s2)) => Bool(s1 < s2),
+        (Gt, Str(s1), Str(s2)) => Bool(s1 > s2),
+        (Leq, Str(s1), Str(s2)) => Bool(s1 <= s2),
+        (Geq, Str(s1), Str(s2)) => Bool(s1 >= s2),
+        (Plus, Val::List(mut xs), Val::List(ys)) => {
+            xs.extend(ys);
+            Val::List(xs)
+        }
+        (BinOp::Range, Int(n1), Int(n2)) => Val::Range(n1, n2),
+        (RangeInclusive, Int(n1), Int(n2)) => match n2.checked_add(1) {
+            Some(n2) => Val::Range(n1, n2),
+            None => return Err(RuntimeError::IntegerOverflow),
+        },
+        (op, v1, v2) => return Err(type_mismatch(&op, v1, v2)),
+    };
+
+    Ok(v)
+}
+
+pub fn apply_uop(op: UnOp, v: Val) -> Result<Val> {
+    match (op, v) {
+        (Not, Bool(b)) => Ok(Bool(!b)),
+        (Neg, Int(n)) => match n.checked_neg() {
+            Some(n) => Ok(Int(n)),
+            None => Err(RuntimeError::IntegerOverflow),
+        },
+        (Neg, Float(n)) => Ok(Float(-n)),
+        (BitNot, Int(n)) => Ok(Int(!n)),
+        (Not, v) => Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Val(v))),
+        (_, v) => Err(RuntimeError::InvalidTypeConversion("int".to_string(), Val(v))),
+    }
+}
+
+// where `break` continues from: the rest of the program after the loop
+fn after_loop(e: Expr) -> Expr {
+    match e {
+        Items(_, _, _, _, rest) | For(_, _, _, _, rest) => *rest,
+        e => e,
+    }
+}
+
+fn all_values(es: &[Expr]) -> bool {
+    es.iter().all(|e| matches!(*e, Val(_)))
+}
+
+fn into_values(es: Vec<Expr>) -> Vec<Val> {
+    es.into_iter()
+        .filter_map(|e| match e {
+            Val(v) => Some(v),
+            _ => None,
+        })
+        .collect()
+}
+
+// A call whose callee and arguments are all values, which the body of a
+// function has reduced to, maybe returning it. Its value is the function's
+// value, so the call can replace the function's frame instead of running
+// inside it.
+fn is_tail_call(e: &Expr) -> bool {
+    match *e {
+        Located(_, ref e1) | Return(ref e1) => is_tail_call(e1),
+        FnCall(ref f, ref args) => matches!(**f, Val(Func(..))) && all_values(args),
+        _ => false,
+    }
+}
+
+// the call out of a tail call, and the innermost span around it
+fn tail_call(e: Expr) -> (Option<Span>, Expr) {
+    match e {
+        Located(span, e1) => match tail_call(*e1) {
+            (None, e1) => (Some(span), e1),
+            located => located,
+        },
+        Return(e1) => tail_call(*e1),
+        e => (None, e),
+    }
+}
+
+// how backtraces refer to a function
+pub fn function_name(name: &Option<Box<Expr>>) -> Option<String> {
+    match *name {
+        Some(ref n) => match **n {
+            Var(ref s) => Some(s.clone()),
+            _ => None,
+        },
+        None => None,
+    }
+}
+
+// whether an assignment target like `xs[i].name` has all of its indexes evaluated
+fn place_is_value(place: &Expr) -> bool {
+    match *place {
+        Var(_) => true,
+        Field(ref p, _) => place_is_value(p),
+        Index(ref p, ref i) => place_is_value(p) && matches!(**i, Val(_)),
+        _ => false,
+    }
+}
+
+// whether a value matches a pattern, collecting the names it binds
+pub fn match_pattern(pattern: &Pattern, v: &Val, bindings: &mut Vec<(String, Val)>) -> bool {
+    match (pattern, v) {
+        (Pattern::Wildcard, _) => true,
+        (Pattern::Bind(x), v) => {
+            bindings.push((x.clone(), v.clone()));
+            true
+        }
+        (Pattern::Lit(lit), v) => lit == v,
+        (Pattern::List(ps, rest), Val::List(xs)) => {
+            let len_matches = match rest {
+                Some(_) => xs.len() >= ps.len(),
+                None => xs.len() == ps.len(),
+            };
+
+            len_matches
+                && ps.iter().zip(xs.iter()).all(|(p, x)| match_pattern(p, x, bindings))
+                && match rest {
+                    Some(rest) => match_pattern(rest, &Val::List(xs[ps.len()..].to_vec()), bindings),
+                    None => true,
+                }
+        }
+        (Pattern::Record(fields), Val::Record(record)) => fields.iter().all(|(key, p)| match record.get(key) {
+            Some(x) => match_pattern(p, x, bindings),
+            None => false,
+        }),
+        _ => false,
+    }
+}
+
+// a variable, or the builtin of that name
+pub fn lookup(env: &Env, x: &str) -> Result<Val> {
+    match env.get(x) {
+        Some(Val(v)) => Ok(v),
+        _ => match Builtin::from_name(x) {
+            Some(b) => Ok(Val::Builtin(b)),
+            None => Err(RuntimeError::VariableNotFound(x.to_string())),
+        },
+    }
+}
+
+// the expression an assignment target like `xs[i].name` indexes into
+pub fn place_base(place: &Expr) -> &Expr {
+    match *place {
+        Index(ref p, _) | Field(ref p, _) => place_base(p),
+        ref e => e,
+    }
+}
+
+// a variable, without falling back to the builtins
+fn variable(env: &Env, x: &str) -> Result<Val> {
+    match env.get(x) {
+        Some(Val(v)) => Ok(v),
+        _ => Err(RuntimeError::VariableNotFound(x.to_string())),
+    }
+}
+
+// The value at an evaluated assignment target, with its variable found by
+// `find`. A compound assignment like `xs[i] += 1` reads it with lookup, the
+// way the expression would be read, and assign_to with variable.
+pub fn read_place(env: &Env, place: &Expr, find: fn(&Env, &str) -> Result<Val>) -> Result<Val> {
+    match *place {
+        Var(ref x) => find(env, x),
+        Index(ref p, ref i) => match **i {
+            Val(ref i) => builtins::index(read_place(env, p, find)?, i.clone()),
+            ref e => Err(RuntimeError::UnexpectedExpr("value".to_string(), e.clone())),
+        },
+        Field(ref p, ref name) => builtins::field(read_place(env, p, find)?, name),
+        ref e => Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), e.clone())),
+    }
+}
+
+// Lists and records are values, so `xs[i] = v` builds the updated list and
+// assigns it back to `xs` through Env::assign, just like `xs = v` would.
+pub fn assign_to(env: &Env, place: &Expr, v: Val) -> Result<()> {
+    match *place {
+        Var(ref x) => env.assign(x.clone(), Val(v)),
+        Index(ref p, ref i) => match **i {
+            Val(ref i) => {
+                let updated = builtins::set_index(read_place(env, p, variable)?, i.clone(), v)?;
+                assign_to(env, p, updated)
+            }
+            ref e => Err(RuntimeError::UnexpectedExpr("value".to_string(), e.clone())),
+        },
+        Field(ref p, ref name) => {
+            let updated = builtins::set_field(read_place(env, p, variable)?, name, v)?;
+            assign_to(env, p, updated)
+        }
+        ref e => Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), e.clone())),
+    }
+}
+
+// the timeout and cancellation flag are checked once per this many steps
+const INTERRUPT_CHECK_INTERVAL: usize = 1024;
+
+// the most function calls in progress at once, unless set otherwise
+const DEFAULT_MAX_DEPTH: usize = 1_000;
+
+// how an Interpreter runs programs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    // rewriting the expression one small step at a time
+    SmallStep,
+    // compiling it to bytecode for a stack machine, which is much faster
+    Bytecode,
+}
+
+// the limits each eval runs within
+#[derive(Clone)]
+pub struct Limits {
+    // the most steps one eval may take
+    fuel: usize,
+    timeout: Option<Duration>,
+    cancel: Option<Arc<AtomicBool>>,
+    // the most function calls that may be in progress at once
+    max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            fuel: 1_000_000_000,
+            timeout: None,
+            cancel: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_depth(&self, calls: usize) -> Result<()> {
+        match calls >= self.max_depth {
+            true => Err(RuntimeError::StackOverflow(self.max_depth)),
+            false => Ok(()),
+        }
+    }
+}
+
+// Counts the steps one eval takes against its limits. For the small step
+// engine a step is a call to step, and for the bytecode engine it is an
+// instruction.
+pub struct Meter {
+    limits: Limits,
+    deadline: Option<Instant>,
+    used: usize,
+}
+
+impl Meter {
+    fn new(limits: Limits) -> Meter {
+        Meter {
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            limits,
+            used: 0,
+        }
+    }
+
+    // called before each step
+    pub fn tick(&mut self) -> Result<()> {
+        if self.used >= self.limits.fuel {
+            return Err(RuntimeError::TooManyIterations(self.used));
+        }
+
+        if self.used.is_multiple_of(INTERRUPT_CHECK_INTERVAL) {
+            self.check_interrupts()?;
+        }
+
+        self.used += 1;
+        Ok(())
+    }
+
+    // called before each function call, with the calls already in progress
+    pub fn check_depth(&self, calls: usize) -> Result<()> {
+        self.limits.check_depth(calls)
+    }
+
+    fn check_interrupts(&self) -> Result<()> {
+        if let Some(ref cancel) = self.limits.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(RuntimeError::Cancelled);
+            }
+        }
+
+        match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => Err(RuntimeError::Timeout(timeout)),
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct Interpreter {
     pub state: State,
+    engine: Engine,
+    limits: Limits,
+    // the bytecode engine, which keeps its stacks between evals
+    vm: Vm,
+    // the steps taken by the most recent eval
+    fuel_used: usize,
+    // the small step interpreter's function calls in progress, and the span
+    // of the innermost located expression being stepped, which is where a
+    // call starts from
+    calls: Vec<StackFrame>,
+    site: Option<Span>,
 }
 
-impl Interpreter {
-    pub fn new() -> Interpreter {
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+// Configures how an Interpreter evaluates programs, and the limits it does
+// so within for running code that can't be trusted to finish:
+//
+//   let cancel = Arc::new(AtomicBool::new(false));
+//   let mut interpreter = Interpreter::builder()
+//       .engine(Engine::Bytecode)
+//       .fuel(100_000)
+//       .timeout(Duration::from_millis(50))
+//       .cancel_flag(cancel.clone())
+//       .build();
+pub struct InterpreterBuilder {
+    engine: Engine,
+    limits: Limits,
+}
+
+impl InterpreterBuilder {
+    // the engine that runs programs, Engine::SmallStep unless set
+    pub fn engine(mut self, engine: Engine) -> InterpreterBuilder {
+        self.engine = engine;
+        self
+    }
+
+    // the most steps each eval may take before failing with TooManyIterations
+    pub fn fuel(mut self, fuel: usize) -> InterpreterBuilder {
+        self.limits.fuel = fuel;
+        self
+    }
+
+    // how long each eval may run before failing with Timeout
+    pub fn timeout(mut self, timeout: Duration) -> InterpreterBuilder {
+        self.limits.timeout = Some(timeout);
+        self
+    }
+
+    // A flag another thread can set to make a running eval fail with
+    // Cancelled. It stays set until it is cleared again, failing any eval
+    // started in the meantime.
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> InterpreterBuilder {
+        self.limits.cancel = Some(cancel);
+        self
+    }
+
+    // The most function calls each eval may have in progress at once before
+    // failing with StackOverflow, 1,000 unless set. The small step engine
+    // recurses on the native stack for each call, so it needs to run on a
+    // thread with a stack that has room for them all, like the one the CLI
+    // starts. The bytecode engine keeps its calls on the heap.
+    pub fn max_depth(mut self, max_depth: usize) -> InterpreterBuilder {
+        self.limits.max_depth = max_depth;
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
         Interpreter {
             state: State::new(),
+            engine: self.engine,
+            limits: self.limits,
+            vm: Vm::new(),
+            fuel_used: 0,
+            calls: Vec::new(),
+            site: None,
         }
     }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::builder().build()
+    }
 
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder {
+            engine: Engine::SmallStep,
+            limits: Limits::default(),
+        }
+    }
+
+    // the steps taken by the most recent eval, whether or not it succeeded
+    pub fn fuel_used(&self) -> usize {
+        self.fuel_used
+    }
+
+    // Steps an expression once. The expressions a call in progress can be
+    // nested in are each stepped by their own function, and this one only
+    // picks which, so that each level of nesting takes little native stack.
     pub fn step(&mut self, e: Expr) -> Result<Expr> {
         debug!("step(e) : {:?}", e);
         debug!("step(self.state) : {:?}", self.state.mem);
 
+        match e {
+            Located(span, e1) => self.step_located(span, *e1),
+            Bop(op, e1, e2) => self.step_bop(op, *e1, *e2),
+            FnCall(e1, args) => self.step_call(*e1, args),
+            Scope(e1) => self.step_scope(*e1),
+            e => self.step_other(e),
+        }
+    }
+
+    fn step_other(&mut self, e: Expr) -> Result<Expr> {
         let e1 = match e {
-            Var(x) => match self.state.get(x.clone()) {
-                Some(e) => e,
-                None => return Err(RuntimeError::VariableNotFound(x)),
-            },
+            Var(x) => Val(lookup(&self.state.mem, &x)?),
             // Values are ineligible for step
             Val(_) => {
                 debug!("stepping on a value {:?}", e);
                 return Err(RuntimeError::SteppingOnValue(e));
             }
-            Uop(op, e1) => match (op, *e1) {
-                (Not, Val(Bool(b))) => Val(Bool(!b)),
-                (Neg, Val(Int(n))) => Val(Int(-1 * n)),
-                (op, e1) => Uop(op, Box::new(self.step(e1)?)),
-            },
-            Bop(op, e1, e2) => match (op, *e1, *e2) {
-                (And, Val(Bool(b1)), Val(Bool(b2))) => Val(Bool(b1 && b2)),
-                (Or, Val(Bool(b1)), Val(Bool(b2))) => Val(Bool(b1 || b2)),
-                (Eq, Val(v1), Val(v2)) => Val(Bool(v1 == v2)),
-                (Ne, Val(v1), Val(v2)) => Val(Bool(v1 != v2)),
-                (Mod, Val(Int(n1)), Val(Int(n2))) => {
-                    // rust % gives the remainder, not modulus
-                    // let result = ((n1 % n2) + n2) % n2;
-                    let result = n1
-                        .checked_rem(n2)
-                        .ok_or(RuntimeError::IntegerUnderflow)?
-                        .checked_add(n2)
-                        .ok_or(RuntimeError::IntegerOverflow)?
-                        .checked_rem(n2)
-                        .ok_or(RuntimeError::IntegerUnderflow)?;
-
-                    Val(Int(result))
-                }
-                (Lt, Val(Int(n1)), Val(Int(n2))) => Val(Bool(n1 < n2)),
-                (Gt, Val(Int(n1)), Val(Int(n2))) => Val(Bool(n1 > n2)),
-                (Leq, Val(Int(n1)), Val(Int(n2))) => Val(Bool(n1 <= n2)),
-                (Geq, Val(Int(n1)), Val(Int(n2))) => Val(Bool(n1 >= n2)),
-                (Plus, Val(Int(n1)), Val(Int(n2))) => match n1.checked_add(n2) {
-                    Some(n) => Val(Int(n)),
-                    None => return Err(RuntimeError::IntegerOverflow),
-                },
-                (Minus, Val(Int(n1)), Val(Int(n2))) => match n1.checked_sub(n2) {
-                    Some(n) => Val(Int(n)),
-                    None => return Err(RuntimeError::IntegerUnderflow),
-                },
-                (Times, Val(Int(n1)), Val(Int(n2))) => match n1.checked_mul(n2) {
-                    Some(n) => Val(Int(n)),
-                    None => return Err(RuntimeError::IntegerOverflow),
-                },
-                (Div, Val(Int(n1)), Val(Int(n2))) => match n1.checked_div(n2) {
-                    Some(n) => Val(Int(n)),
-                    None => return Err(RuntimeError::IntegerUnderflow),
-                },
-                (Seq, Val(_), e1) => e1,
-                (Assign, Var(x), v @ Val(_)) => {
-                    self.state.assign(x, v.clone())?;
-                    debug!("done assigning {:?}", self.state.mem);
-                    v
-                }
-                // Search Cases
-                (op, v1 @ Val(_), e2) => Bop(op, Box::new(v1), Box::new(self.step(e2)?)),
-                (Assign, v1 @ Var(_), e2) => Bop(Assign, Box::new(v1), Box::new(self.step(e2)?)),
-                (op, e1, e2) => Bop(op, Box::new(self.step(e1)?), Box::new(e2)),
+            Located(span, e1) => self.step_located(span, *e1)?,
+            Uop(op, e1) => match *e1 {
+                Val(v) => Val(apply_uop(op, v)?),
+                e1 => Uop(op, Box::new(self.step(e1)?)),
             },
-            Decl(dt, addr, e1, e2) => match (dt, *addr, *e1, *e2) {
-                (DConst, Var(x), v1 @ Val(_), e1) => {
-                    self.state.alloc_const(x, v1)?;
-                    e1
-                }
-                (DVar, Var(x), v1 @ Val(_), e1) => {
-                    debug!("allocing {:?}", v1);
-                    self.state.alloc(x, v1)?;
-                    e1
-                }
-                (dt, addr, e1, e2) => {
-                    Decl(dt, Box::new(addr), Box::new(self.step(e1)?), Box::new(e2))
-                }
+            Bop(op, e1, e2) => self.step_bop(op, *e1, *e2)?,
+            AssignOp(op, place, e2) => match place_is_value(&place) {
+                true => Bop(Assign, place.clone(), Box::new(Bop(op, place, e2))),
+                false => AssignOp(op, Box::new(self.step_place(*place)?), e2),
             },
-            While(e1, e1o, e2, e2o, e3) => match (*e1, e1o, *e2, e2o, e3) {
-                (Val(Bool(b)), e1o, _, e2o, e3) => match b {
-                    true => While(e1o.clone(), e1o, e2o.clone(), e2o, e3),
-                    false => *e3,
-                },
-                (e1, e1o, v @ Val(_), e2o, e3) => {
-                    While(Box::new(self.step(e1)?), e1o, Box::new(v), e2o, e3)
-                }
-                (e1, e1o, e2, e2o, e3) => {
-                    While(Box::new(e1), e1o, Box::new(self.step(e2)?), e2o, e3)
-                }
+            Decl(dt, addr, e1, e2) => self.step_decl(dt, *addr, *e1, *e2)?,
+            e @ While(..) | e @ ForIn(..) | e @ Items(..) | e @ For(..) | e @ Iteration(..) => self.step_loop(e)?,
+            e @ Match(..) | e @ MatchArm(..) => self.step_match(e)?,
+            e @ Throw(..) | e @ Try(..) | e @ Block(..) => self.step_try(e)?,
+            Return(e1) => match *e1 {
+                Val(v) => return Err(RuntimeError::Return(v)),
+                e1 => Return(Box::new(self.step(e1)?)),
             },
+            Break => return Err(RuntimeError::Break),
+            Continue => return Err(RuntimeError::Continue),
             Print(e1) => match *e1 {
                 Val(v) => {
                     println!("{}", v);
@@ -131,90 +606,487 @@ impl Interpreter {
                 },
                 (e1, e2, e3) => Ternary(Box::new(self.step(e1)?), e2, e3),
             },
-            FnCall(e1, args) => {
-                match (*e1, args) {
-                    (Val(Func(ref name, ref e1, ref xs)), ref es)
-                        if es
-                            .iter()
-                            .all(|v| if let Val(_) = *v { true } else { false }) =>
-                    {
-                        self.state.begin_scope();
-
-                        // alloc the params
-                        for (xn, en) in xs.iter().zip(es.iter()) {
-                            if let &Var(ref x) = xn {
-                                self.state.alloc(x.to_string(), en.clone())?;
-                            } else {
-                                return Err(RuntimeError::InvalidTypeConversion(
-                                    "var".to_string(),
-                                    xn.clone(),
-                                ));
-                            }
-                        }
+            Lambda(name, e1, xs) => Val(Func(name, Rc::new(*e1), xs, self.state.capture())),
+            FnCall(e1, args) => self.step_call(*e1, args)?,
+            Expr::List(es) => match all_values(&es) {
+                true => Val(Val::List(into_values(es))),
+                false => Expr::List(self.step_first(es)?),
+            },
+            Expr::Record(fields) => self.step_record(fields)?,
+            Field(e1, name) => match *e1 {
+                Val(v) => Val(builtins::field(v, &name)?),
+                e1 => Field(Box::new(self.step(e1)?), name),
+            },
+            Index(e1, e2) => match (*e1, *e2) {
+                (Val(v1), Val(v2)) => Val(builtins::index(v1, v2)?),
+                (v1 @ Val(_), e2) => Index(Box::new(v1), Box::new(self.step(e2)?)),
+                (e1, e2) => Index(Box::new(self.step(e1)?), Box::new(e2)),
+            },
+            Scope(e1) => self.step_scope(*e1)?,
+        };
+
+        debug!("returning with mem {:?}", self.state.mem);
+        debug!("returning with e {:?}", e1);
+        Ok(e1)
+    }
+
+    // errors keep the span of the innermost expression that raised them
+    fn step_located(&mut self, span: Span, e1: Expr) -> Result<Expr> {
+        match e1 {
+            v @ Val(_) => Ok(v),
+            e1 => {
+                self.site = Some(span);
+
+                match self.step(e1) {
+                    Ok(e1) => Ok(Located(span, Box::new(e1))),
+                    Err(err) => Err(err.at(span)),
+                }
+            }
+        }
+    }
+
+    fn step_decl(&mut self, dt: Dec, addr: Expr, e1: Expr, e2: Expr) -> Result<Expr> {
+        match (dt, addr, e1, e2) {
+            (DConst, Var(x), v1 @ Val(_), e1) => {
+                self.state.alloc_const(x, v1)?;
+                Ok(e1)
+            }
+            (DVar, Var(x), v1 @ Val(_), e1) => {
+                debug!("allocing {:?}", v1);
+                self.state.alloc(x, v1)?;
+                Ok(e1)
+            }
+            (dt, addr, e1, e2) => Ok(Decl(dt, Box::new(addr), Box::new(self.step(e1)?), Box::new(e2))),
+        }
+    }
+
+    // a call with all of its arguments evaluated enters the function's body
+    // in a new Scope
+    fn step_call(&mut self, e1: Expr, args: Vec<Expr>) -> Result<Expr> {
+        let e = match (e1, args) {
+            (Val(Func(ref name, ref e1, ref xs, ref env)), ref es) if all_values(es) => {
+                self.limits.check_depth(self.calls.len())?;
+                self.state.begin_scope(env);
 
-                        // alloc the fn body for named functions
-                        if let Some(n) = name {
-                            if let Var(ref s) = **n {
-                                self.state.alloc(
-                                    s.clone(),
-                                    Val(Func(name.clone(), e1.clone(), xs.clone())),
-                                )?;
-                            }
-                        };
-
-                        Scope(e1.clone())
+                // alloc the params
+                for (xn, en) in xs.iter().zip(es.iter()) {
+                    if let Var(x) = xn {
+                        self.state.alloc(x.to_string(), en.clone())?;
+                    } else {
+                        return Err(RuntimeError::InvalidTypeConversion(
+                            "var".to_string(),
+                            xn.clone(),
+                        ));
                     }
-                    (f @ Val(Func(_, _, _)), mut args) => {
-                        // find the first nonvalue arg and call step() on it
-                        if let Some(index) = args.iter().position(|e| match e {
-                            &Val(_) => false,
-                            _ => true,
-                        }) {
-                            // temporary placeholder so we can safely move the value
-                            // this ensures good vec state in between when data is read and rewritten
-                            let expr = mem::replace(&mut args[index], Val(Undefined));
-                            args[index] = self.step(expr)?;
-                        }
+                }
+
+                // alloc the fn body for named functions
+                if let Some(n) = name {
+                    if let Var(ref s) = **n {
+                        self.state.alloc(
+                            s.clone(),
+                            Val(Func(name.clone(), e1.clone(), xs.clone(), env.clone())),
+                        )?;
+                    }
+                };
+
+                self.calls.push(StackFrame {
+                    function: function_name(name),
+                    call: self.site,
+                    tail_calls: 0,
+                });
+
+                Scope(Box::new((**e1).clone()))
+            }
+            (Val(Val::Builtin(b)), es) if all_values(&es) => Val(b.call(into_values(es))?),
+            (f @ Val(Func(..)), args) | (f @ Val(Val::Builtin(_)), args) => {
+                FnCall(Box::new(f), self.step_first(args)?)
+            }
+            (e1, args) => FnCall(Box::new(self.step(e1)?), args),
+        };
+
+        Ok(e)
+    }
+
+    fn step_record(&mut self, fields: Vec<(String, Expr)>) -> Result<Expr> {
+        let e = match fields.iter().position(|(_, e)| !matches!(*e, Val(_))) {
+            Some(index) => {
+                let mut fields = fields;
+                let expr = mem::replace(&mut fields[index].1, Val(Undefined));
+                fields[index].1 = self.step(expr)?;
+                Expr::Record(fields)
+            }
+            None => Val(Val::Record(
+                fields
+                    .into_iter()
+                    .filter_map(|(k, e)| match e {
+                        Val(v) => Some((k, v)),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+        };
+
+        Ok(e)
+    }
+
+    // the body of a function call in progress
+    fn step_scope(&mut self, e1: Expr) -> Result<Expr> {
+        let e = match e1 {
+            v @ Val(_) => {
+                self.end_call();
+                v
+            }
+            // the call starts a new Scope in place of this one, so that
+            // recursive loops run in constant space
+            e1 if is_tail_call(&e1) => {
+                let tail_calls = self.calls.last().map_or(0, |frame| frame.tail_calls + 1);
+                self.end_call();
 
-                        FnCall(Box::new(f), args)
+                let e1 = match tail_call(e1) {
+                    (Some(span), call) => {
+                        self.site = Some(span);
+                        self.step(call).map_err(|err| err.at(span))?
                     }
-                    (e1, args) => FnCall(Box::new(self.step(e1)?), args),
+                    (None, call) => self.step(call)?,
+                };
+
+                // the new call's frame counts the one it replaced
+                if let Some(frame) = self.calls.last_mut() {
+                    frame.tail_calls = tail_calls;
+                }
+
+                e1
+            }
+            e1 => match self.step(e1) {
+                Ok(e1) => Scope(Box::new(e1)),
+                Err(RuntimeError::Return(v)) => {
+                    self.end_call();
+                    Val(v)
                 }
+                // errors leave the function too, so that a `catch` further
+                // up runs in the frame of its `try`
+                Err(err) => {
+                    let err = self.trace(err);
+                    self.end_call();
+                    return Err(err);
+                }
+            },
+        };
+
+        Ok(e)
+    }
+
+    fn end_call(&mut self) {
+        self.state.end_scope();
+        self.calls.pop();
+    }
+
+    // attaches the calls in progress to an error leaving them, unless it
+    // already left one of them
+    fn trace(&self, err: RuntimeError) -> RuntimeError {
+        match err.backtrace().is_empty() {
+            true => err.with_backtrace(self.calls.iter().rev().cloned().collect()),
+            false => err,
+        }
+    }
+
+    // binary operators are stepped separately to keep the frame of the
+    // recursive step function small
+    fn step_bop(&mut self, op: BinOp, e1: Expr, e2: Expr) -> Result<Expr> {
+        let e = match (op, e1, e2) {
+            // && and || step their left side first, and only go on to the
+            // right side when the left side doesn't already decide the result
+            (And, Val(Bool(false)), _) => Val(Bool(false)),
+            (Or, Val(Bool(true)), _) => Val(Bool(true)),
+            (And, Val(v1), _) | (Or, Val(v1), _) if !matches!(v1, Bool(_)) => {
+                return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Val(v1)))
+            }
+            (Seq, Val(_), e1) => e1,
+            (Assign, Var(x), v @ Val(_)) => {
+                self.state.assign(x, v.clone())?;
+                debug!("done assigning {:?}", self.state.mem);
+                v
             }
-            Scope(e1) => match *e1 {
-                v @ Val(_) => {
+            (Assign, ref place @ Index(..), Val(ref v))
+            | (Assign, ref place @ Field(..), Val(ref v))
+                if place_is_value(place) =>
+            {
+                assign_to(&self.state.mem, place, v.clone())?;
+                Val(v.clone())
+            }
+            (op, Val(v1), Val(v2)) => Val(apply_bop(op, v1, v2)?),
+            // Search Cases
+            (op, v1 @ Val(_), e2) => Bop(op, Box::new(v1), Box::new(self.step(e2)?)),
+            (Assign, v1 @ Var(_), e2) => Bop(Assign, Box::new(v1), Box::new(self.step(e2)?)),
+            // evaluate the indexes of an assignment target before the value
+            (Assign, place @ Index(..), e2) | (Assign, place @ Field(..), e2) => match place_is_value(&place) {
+                true => Bop(Assign, Box::new(place), Box::new(self.step(e2)?)),
+                false => Bop(Assign, Box::new(self.step_place(place)?), Box::new(e2)),
+            },
+            (op, e1, e2) => Bop(op, Box::new(self.step(e1)?), Box::new(e2)),
+        };
+
+        Ok(e)
+    }
+
+    // loops are stepped separately to keep the frame of the recursive step
+    // function small
+    fn step_loop(&mut self, e: Expr) -> Result<Expr> {
+        let e1 = match e {
+            // The condition and body being stepped, then the originals to
+            // restart them from on the next iteration, then the rest of the
+            // program. The condition is checked before each iteration.
+            While(e1, e1o, e2, e2o, e3) => match (*e1, e1o, *e2, e2o, e3) {
+                (Val(Bool(false)), _, _, _, e3) => *e3,
+                (Val(Bool(true)), e1o, Val(_), e2o, e3) => {
+                    While(e1o.clone(), e1o, e2o.clone(), e2o, e3)
+                }
+                (c @ Val(Bool(true)), e1o, e2, e2o, e3) => match self.step(e2) {
+                    Ok(e2) => While(Box::new(c), e1o, Box::new(e2), e2o, e3),
+                    Err(RuntimeError::Break) => *e3,
+                    Err(RuntimeError::Continue) => While(e1o.clone(), e1o, e2o.clone(), e2o, e3),
+                    Err(err) => return Err(err),
+                },
+                (Val(v), _, _, _, _) => {
+                    return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Val(v)))
+                }
+                (e1, e1o, e2, e2o, e3) => {
+                    While(Box::new(self.step(e1)?), e1o, Box::new(e2), e2o, e3)
+                }
+            },
+            ForIn(x, e1, body, rest) => match *e1 {
+                // the first item is taken in the same step
+                Val(v) => self.step_loop(Items(x, Box::new(v), 0, body, rest))?,
+                e1 => ForIn(x, Box::new(self.step(e1)?), body, rest),
+            },
+            Items(x, v, at, body, rest) => match builtins::item_at(&v, at)? {
+                None => *rest,
+                Some((item, next)) => {
+                    // each iteration binds the loop variable in a fresh frame,
+                    // so closures created in the body see their own value
+                    self.state.begin_block();
+
+                    match *x {
+                        Var(ref name) => self.state.alloc(name.clone(), Val(item))?,
+                        ref e => {
+                            return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone()))
+                        }
+                    }
+
+                    let next = Items(x, v, next, body.clone(), rest);
+                    Iteration(body, Box::new(Val(Undefined)), Box::new(next))
+                }
+            },
+            For(e1, e1o, update, body, rest) => match *e1 {
+                Val(Bool(false)) => *rest,
+                Val(Bool(true)) => {
+                    self.state.begin_block();
+
+                    let next = For(e1o.clone(), e1o, update.clone(), body.clone(), rest);
+                    Iteration(body, update, Box::new(next))
+                }
+                Val(v) => return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Val(v))),
+                e1 => For(Box::new(self.step(e1)?), e1o, update, body, rest),
+            },
+            // the iteration's frame is left however the body finishes
+            Iteration(body, update, next) => match *body {
+                Val(_) => {
                     self.state.end_scope();
-                    v
+                    Bop(Seq, update, next)
                 }
-                e1 => Scope(Box::new(self.step(e1)?)),
+                body => match self.step(body) {
+                    Ok(body) => Iteration(Box::new(body), update, next),
+                    Err(RuntimeError::Continue) => {
+                        self.state.end_scope();
+                        Bop(Seq, update, next)
+                    }
+                    Err(RuntimeError::Break) => {
+                        self.state.end_scope();
+                        after_loop(*next)
+                    }
+                    Err(err) => {
+                        self.state.end_scope();
+                        return Err(err);
+                    }
+                },
             },
+            e => return Err(RuntimeError::UnexpectedExpr("loop".to_string(), e)),
         };
 
-        debug!("returning with mem {:?}", self.state.mem);
-        debug!("returning with e {:?}", e1);
         Ok(e1)
     }
 
+    // step the first expression that is not yet a value, leaving the rest alone
+    fn step_first(&mut self, mut es: Vec<Expr>) -> Result<Vec<Expr>> {
+        if let Some(index) = es.iter().position(|e| !matches!(*e, Val(_))) {
+            // temporary placeholder so we can safely move the value
+            // this ensures good vec state in between when data is read and rewritten
+            let expr = mem::replace(&mut es[index], Val(Undefined));
+            es[index] = self.step(expr)?;
+        }
+
+        Ok(es)
+    }
+
+    fn step_match(&mut self, e: Expr) -> Result<Expr> {
+        let e1 = match e {
+            Match(e1, mut arms) => match *e1 {
+                Val(v) => {
+                    let mut bindings = Vec::new();
+
+                    let i = match arms.iter().position(|arm| {
+                        bindings.clear();
+                        match_pattern(&arm.0, &v, &mut bindings)
+                    }) {
+                        Some(i) => i,
+                        None => return Err(RuntimeError::NoMatchingArm(Val(v))),
+                    };
+
+                    let remaining = arms.split_off(i + 1);
+                    let (_, guard, body) = arms.pop().unwrap();
+
+                    // like a function call, the bindings live in their own frame
+                    self.state.begin_block();
+
+                    for (x, v) in bindings {
+                        self.state.alloc(x, Val(v))?;
+                    }
+
+                    let (guard, fallback) = match guard {
+                        Some(guard) => (guard, Match(Box::new(Val(v)), remaining)),
+                        None => (Val(Bool(true)), Val(Undefined)),
+                    };
+
+                    MatchArm(Box::new(guard), Box::new(body), Box::new(fallback))
+                }
+                e1 => Match(Box::new(self.step(e1)?), arms),
+            },
+            // the arm's frame is left however it finishes
+            MatchArm(guard, body, fallback) => match (*guard, *body) {
+                (Val(Bool(true)), body) => Block(Box::new(body)),
+                (Val(Bool(false)), _) => {
+                    self.state.end_scope();
+                    *fallback
+                }
+                (Val(v), _) if !matches!(v, Bool(_)) => {
+                    self.state.end_scope();
+                    return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Val(v)));
+                }
+                (guard, body) => match self.step(guard) {
+                    Ok(guard) => MatchArm(Box::new(guard), Box::new(body), fallback),
+                    Err(err) => {
+                        self.state.end_scope();
+                        return Err(err);
+                    }
+                },
+            },
+            e => return Err(RuntimeError::UnexpectedExpr("match".to_string(), e)),
+        };
+
+        Ok(e1)
+    }
+
+    fn step_try(&mut self, e: Expr) -> Result<Expr> {
+        let e1 = match e {
+            Throw(e1) => match *e1 {
+                Val(v) => return Err(RuntimeError::Thrown(v)),
+                e1 => Throw(Box::new(self.step(e1)?)),
+            },
+            Try(body, x, handler) => match *body {
+                Val(v) => Val(v),
+                body => match self.step(body) {
+                    Ok(body) => Try(Box::new(body), x, handler),
+                    // any frames entered inside the body have been left by now
+                    Err(err) => match (err.to_val(), *x) {
+                        (Some(v), Var(x)) => {
+                            self.state.begin_block();
+                            self.state.alloc(x, Val(v))?;
+                            Block(handler)
+                        }
+                        _ => return Err(err),
+                    },
+                },
+            },
+            Block(e1) => match *e1 {
+                Val(v) => {
+                    self.state.end_scope();
+                    Val(v)
+                }
+                e1 => match self.step(e1) {
+                    Ok(e1) => Block(Box::new(e1)),
+                    Err(err) => {
+                        self.state.end_scope();
+                        return Err(err);
+                    }
+                },
+            },
+            e => return Err(RuntimeError::UnexpectedExpr("try".to_string(), e)),
+        };
+
+        Ok(e1)
+    }
+
+    // step the leftmost index of an assignment target like `xs[i][j]`
+    fn step_place(&mut self, place: Expr) -> Result<Expr> {
+        match place {
+            Index(p, i) => match place_is_value(&p) {
+                true => Ok(Index(p, Box::new(self.step(*i)?))),
+                false => Ok(Index(Box::new(self.step_place(*p)?), i)),
+            },
+            Field(p, name) => Ok(Field(Box::new(self.step_place(*p)?), name)),
+            e => Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), e)),
+        }
+    }
+
+    // Evaluates a program. If it fails, the state goes back to the depth it
+    // was at before, so a later eval doesn't run inside the scopes of a call
+    // that never finished. Bindings the program made at that depth, like a
+    // top level `var`, are kept.
     pub fn eval(&mut self, input: &str) -> Result<Expr> {
-        let mut e = parse(input)?;
+        let e = parse(input)?;
+        let depth = self.state.depth();
+        let mut meter = Meter::new(self.limits.clone());
 
-        let mut num_iterations = 0;
+        let result = match self.engine {
+            Engine::SmallStep => self.eval_expr(e, &mut meter),
+            Engine::Bytecode => self.vm.eval(&e, &mut self.state, &mut meter).map(Val),
+        };
 
-        loop {
-            if num_iterations > 1000000000 {
-                return Err(RuntimeError::TooManyIterations(num_iterations));
-            }
+        self.fuel_used = meter.used;
+
+        // errors that didn't leave the calls they were raised in, like running
+        // out of fuel, are traced here
+        let result = result.map_err(|err| self.trace(err));
+        self.calls.clear();
+
+        if result.is_err() {
+            self.state.unwind_to(depth);
+        }
+
+        result
+    }
 
+    fn eval_expr(&mut self, mut e: Expr, meter: &mut Meter) -> Result<Expr> {
+        loop {
             debug!("-----------------");
             debug!("--- iterating on e {:?} ", e);
             debug!("--- iterating on m {:?} ", self.state.mem);
-            num_iterations += 1;
+
             if let Val(_) = e {
-                debug!("--- iterations: {}", num_iterations);
+                debug!("--- iterations: {}", meter.used);
                 return Ok(e);
-            } else {
-                e = self.step(e)?;
             }
+
+            // a value still wrapped in its span, like a literal, needs no step
+            if let Located(_, ref e1) = e {
+                if let Val(ref v) = **e1 {
+                    return Ok(Val(v.clone()));
+                }
+            }
+
+            meter.tick()?;
+            e = self.step(e)?;
         }
     }
 }
@@ -1,8 +1,13 @@
 #[macro_use]
 extern crate log;
 
+pub mod builtins;
+pub mod evaluator;
 pub mod expr;
 pub mod interpreter;
 pub mod parser;
 pub mod runtime_error;
+pub mod span;
 pub mod state;
+pub mod unparser;
+pub mod vm;
@@ -1,28 +1,146 @@
 extern crate boxx;
 
-use boxx::interpreter::Interpreter;
+use boxx::expr::{Expr, Val};
+use boxx::interpreter::{Engine, Interpreter};
+use boxx::runtime_error::RuntimeError;
 
-use std::io::{Write, stdout, stdin};
+use std::env;
+use std::fs;
+use std::io::{Read, Write, stdout, stdin};
+use std::process;
+use std::thread;
+
+// exit codes, following the BSD sysexits conventions for usage and input errors
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_PARSER_ERROR: i32 = 2;
+const EXIT_USAGE: i32 = 64;
+const EXIT_NO_INPUT: i32 = 66;
+
+// The small step interpreter recurses on the native stack for each function
+// call, so programs run on a thread with room for as many as it allows.
+const STACK_SIZE: usize = 1 << 30;
+
+const USAGE: &str = "usage: boxx [--bytecode] [script.bx | -e expr | -] [args...]
+
+  boxx              start the REPL
+  boxx script.bx    run a file
+  boxx -e expr      evaluate expr and print the result
+  boxx -            run a program read from stdin
+
+  --bytecode        compile to bytecode and run it on a stack machine,
+                    instead of the small step interpreter
+
+Remaining arguments are bound to the `args` list as strings.";
+
+enum Source {
+  Repl,
+  File(String),
+  Inline(String),
+  Stdin,
+}
 
 fn main() {
-  let mut interpreter = Interpreter::new();
+  let runner = match thread::Builder::new().stack_size(STACK_SIZE).spawn(run) {
+    Ok(runner) => runner,
+    Err(e) => {
+      eprintln!("boxx: cannot start the interpreter: {}", e);
+      process::exit(EXIT_RUNTIME_ERROR);
+    },
+  };
+
+  // a panic has already been reported by the thread
+  if runner.join().is_err() {
+    process::exit(EXIT_RUNTIME_ERROR);
+  }
+}
+
+fn run() {
+  let mut argv = env::args().skip(1).peekable();
+
+  let engine = match argv.peek() {
+    Some(a) if a == "--bytecode" => {
+      argv.next();
+      Engine::Bytecode
+    },
+    _ => Engine::SmallStep,
+  };
+
+  let source = match argv.next() {
+    None => Source::Repl,
+    Some(ref a) if a == "-h" || a == "--help" => {
+      println!("{}", USAGE);
+      return;
+    },
+    Some(ref a) if a == "-e" => match argv.next() {
+      Some(expr) => Source::Inline(expr),
+      None => usage_error("-e requires an expression"),
+    },
+    Some(ref a) if a == "-" => Source::Stdin,
+    Some(ref a) if a.starts_with('-') => usage_error(&format!("unknown option {}", a)),
+    Some(path) => Source::File(path),
+  };
+
+  let args: Vec<Val> = argv.map(Val::Str).collect();
+
+  let mut interpreter = Interpreter::builder().engine(engine).build();
 
+  if let Err(err) = interpreter.state.alloc_const("args".to_string(), Expr::Val(Val::List(args))) {
+    exit_with(&err, "boxx", "");
+  }
+
+  // only an inline expression prints its result, scripts use print
+  let print_result = matches!(source, Source::Inline(_));
+
+  let (name, program) = match source {
+    Source::Repl => return repl(&mut interpreter),
+    Source::File(path) => match fs::read_to_string(&path) {
+      Ok(text) => (path, text),
+      Err(e) => {
+        eprintln!("boxx: cannot read {}: {}", path, e);
+        process::exit(EXIT_NO_INPUT);
+      },
+    },
+    Source::Inline(expr) => ("<expr>".to_string(), expr),
+    Source::Stdin => {
+      let mut text = String::new();
+      if let Err(e) = stdin().read_to_string(&mut text) {
+        eprintln!("boxx: cannot read stdin: {}", e);
+        process::exit(EXIT_NO_INPUT);
+      }
+      ("<stdin>".to_string(), text)
+    },
+  };
+
+  match interpreter.eval(&program) {
+    Ok(Expr::Val(Val::Undefined)) => {},
+    Ok(Expr::Val(v)) if print_result => println!("{}", v),
+    Ok(_) => {},
+    Err(err) => exit_with(&err, &name, &program),
+  }
+}
+
+fn repl(interpreter: &mut Interpreter) {
   loop {
     print!("boxx> ");
     let _ = stdout().flush();
 
     let mut input = String::new();
     match stdin().read_line(&mut input) {
+      // end of input
+      Ok(0) => {
+        println!();
+        break;
+      },
       Ok(_) => {
-        if input == "exit\n".to_string() { 
+        if input == "exit\n" {
           break;
         }
 
         let expr_result = interpreter.eval(&input);
-        
+
         match expr_result {
-          Ok(exp) => println!("{:?}", exp), 
-          Err(err) => println!("Error: {}", err),
+          Ok(exp) => println!("{}", exp),
+          Err(err) => println!("{}", err.render("<repl>", &input)),
         }
       },
       Err(e) => print!("error: {}", e)
@@ -30,3 +148,18 @@ fn main() {
     let _ = stdout().flush();
   }
 }
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("boxx: {}\n\n{}", message, USAGE);
+  process::exit(EXIT_USAGE);
+}
+
+// prints the error as `file:line:col: message` with the source underlined
+fn exit_with(err: &RuntimeError, name: &str, source: &str) -> ! {
+  eprintln!("{}", err.render(name, source));
+
+  match *err.kind() {
+    RuntimeError::ParserError(_) => process::exit(EXIT_PARSER_ERROR),
+    _ => process::exit(EXIT_RUNTIME_ERROR),
+  }
+}
@@ -1,36 +1,153 @@
 use parser::lexer_error::LexerError;
-use parser::token::Token;
+use parser::token::{Lexeme, Token};
+use span::Span;
 
 pub struct Lexer {
   text: String,
+  // byte offset of the next unread character, and its line and column
+  pos: usize,
+  line: usize,
+  col: usize,
 }
 
 impl Lexer {
   pub fn new(text: String) -> Lexer {
     Lexer {
-      text: text,
+      text,
+      pos: 0,
+      line: 1,
+      col: 1,
     }
   }
 
+  // the text that has not been lexed yet
+  fn rest(&self) -> &str {
+    &self.text[self.pos..]
+  }
+
   fn advance(&mut self, n: usize) {
-    self.text = self.text.split_at(n).1.to_string()
+    let (line, col) = position_after(self.line, self.col, &self.rest()[..n]);
 
+    self.pos += n;
+    self.line = line;
+    self.col = col;
   }
 
-  fn lex_integer(&mut self) -> Result<Token, LexerError> {
-    let int_str: String = self.text
-      .chars()
-      .take_while(|c| c.is_digit(10))
-      .collect();
+  // the span of `rest()[from..to]`, without consuming anything
+  fn span_of(&self, from: usize, to: usize) -> Span {
+    let (line, col) = position_after(self.line, self.col, &self.rest()[..from]);
+    Span::new(self.pos + from, self.pos + to, line, col)
+  }
+
+  // integers like `42`, and floats like `1.5`, `2e10` or `6.02e-23`
+  fn lex_number(&mut self) -> Result<Token, LexerError> {
+    let bytes = self.rest().as_bytes();
+    let digits_from = |i: usize| match bytes.get(i..) {
+      Some(rest) => rest.iter().take_while(|b| b.is_ascii_digit()).count(),
+      None => 0,
+    };
+
+    let mut len = digits_from(0);
+    let mut is_float = false;
+
+    // a fractional part needs a digit after the '.'
+    if bytes.get(len) == Some(&b'.') && digits_from(len + 1) > 0 {
+      len += 1 + digits_from(len + 1);
+      is_float = true;
+    }
+
+    // as does an exponent after the 'e' and its optional sign
+    if let Some(&b'e') | Some(&b'E') = bytes.get(len) {
+      let sign = match bytes.get(len + 1) {
+        Some(&b'+') | Some(&b'-') => 1,
+        _ => 0,
+      };
+
+      let exp_digits = digits_from(len + 1 + sign);
+
+      if exp_digits > 0 {
+        len += 1 + sign + exp_digits;
+        is_float = true;
+      }
+    }
+
+    let num_str = self.rest()[..len].to_string();
+    let span = self.span_of(0, len);
+
+    let token = if is_float {
+      Token::Float(num_str.parse::<f64>().map_err(|e| LexerError::ParseFloat(e, span))?)
+    } else {
+      Token::Int(num_str.parse::<isize>().map_err(|e| LexerError::ParseInt(e, span))?)
+    };
+
+    self.advance(len);
+    Ok(token)
+  }
+
+  // a double quoted literal, supporting \n \t \r \" \\ and \u{...} escapes
+  fn lex_string(&mut self) -> Result<Token, LexerError> {
+    let mut s = String::new();
+    let mut len = None;
+
+    {
+      // skip the opening quote
+      let mut chars = self.rest().char_indices().skip(1);
 
-    let n = int_str.parse::<isize>()?;
+      while let Some((i, c)) = chars.next() {
+        match c {
+          '"' => {
+            len = Some(i + 1);
+            break;
+          },
+          '\\' => match chars.next() {
+            Some((_, 'n')) => s.push('\n'),
+            Some((_, 't')) => s.push('\t'),
+            Some((_, 'r')) => s.push('\r'),
+            Some((_, '"')) => s.push('"'),
+            Some((_, '\\')) => s.push('\\'),
+            Some((_, 'u')) => {
+              let hex: String = chars.by_ref()
+                .map(|(_, c)| c)
+                .take_while(|c| *c != '}')
+                .collect();
 
-    self.advance(int_str.len());
-    Ok(Token::Int(n))
+              let code = hex.strip_prefix('{')
+                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                .and_then(std::char::from_u32);
+
+              match code {
+                Some(c) => s.push(c),
+                None => {
+                  let span = self.span_of(i, i + 3 + hex.len());
+                  return Err(LexerError::InvalidEscape(format!("invalid unicode escape \\u{}}}", hex), span));
+                },
+              }
+            },
+            Some((_, c)) => {
+              let span = self.span_of(i, i + 1 + c.len_utf8());
+              return Err(LexerError::InvalidEscape(format!("invalid escape \\{}", c), span));
+            },
+            None => break,
+          },
+          c => s.push(c),
+        }
+      }
+    }
+
+    match len {
+      Some(n) => {
+        self.advance(n);
+        Ok(Token::Str(s))
+      },
+      None => {
+        let span = self.span_of(0, self.rest().len());
+        Err(LexerError::UnterminatedString(format!("unterminated string {:?}", s), span))
+      },
+    }
   }
 
   fn lex_keyword(&mut self) -> Result<Token, LexerError> {
-    let keyword: String = self.text
+    let keyword: String = self.rest()
       .chars()
       .take_while(|c| c.is_alphabetic() || *c == '_')
       .collect();
@@ -40,22 +157,32 @@ impl Lexer {
     let token = match keyword.as_ref() {
       "true" => Token::Bool(true),
       "false" => Token::Bool(false),
+      "undefined" => Token::Undefined,
       "fn" => Token::FnDecl,
       "let" => Token::Let,
       "var" => Token::VarDecl,
       "if" => Token::If,
       "else" => Token::Else,
       "while" => Token::While,
+      "for" => Token::For,
+      "in" => Token::In,
       "print" => Token::Print,
-      s if s.len() > 0 => Token::Var(s.to_string()),
-      s => return Err(LexerError::InvalidKeyword(format!("invalid keyword {:?}", s)))
+      "return" => Token::Return,
+      "break" => Token::Break,
+      "continue" => Token::Continue,
+      "match" => Token::Match,
+      "throw" => Token::Throw,
+      "try" => Token::Try,
+      "catch" => Token::Catch,
+      s if !s.is_empty() => Token::Var(s.to_string()),
+      s => return Err(LexerError::InvalidKeyword(format!("invalid keyword {:?}", s), self.span_of(0, 0)))
     };
 
     Ok(token)
   }
 
   fn skip_whitespace(&mut self) {
-    let spaces_str: String = self.text
+    let spaces_str: String = self.rest()
       .chars()
       .take_while(|c| c.is_whitespace())
       .collect();
@@ -63,54 +190,132 @@ impl Lexer {
     self.advance(spaces_str.len());
   }
 
-  fn skip_inline_comment(&mut self) {
-    let comment_str: String = self.text
+  // skips to the end of the line, returning the comment text
+  fn skip_inline_comment(&mut self) -> String {
+    let comment_str: String = self.rest()
       .chars()
-      .take_while(|c| !(c == &'\n'))
+      .take_while(|c| c != &'\n')
       .collect();
 
     self.advance(comment_str.len());
+    comment_str
   }
 
-  fn skip_block_comment(&mut self) {
-    // TODO: remove everything through the next '*/'
+  // skips a `/* ... */` comment, including any block comments nested in it
+  fn skip_block_comment(&mut self) -> Result<(), LexerError> {
+    let end = {
+      let bytes = self.rest().as_bytes();
+      let mut depth = 0;
+      let mut i = 0;
+      let mut end = None;
+
+      while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+          depth += 1;
+          i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+          depth -= 1;
+          i += 2;
+
+          if depth == 0 {
+            end = Some(i);
+            break;
+          }
+        } else {
+          i += 1;
+        }
+      }
+
+      end
+    };
+
+    match end {
+      Some(n) => {
+        self.advance(n);
+        Ok(())
+      },
+      None => {
+        let span = self.span_of(0, 2);
+        Err(LexerError::UnterminatedComment(String::from("unterminated block comment"), span))
+      },
+    }
   }
 
   fn peek_next(&mut self) -> Option<char> {
-    self.text.chars().next()
+    self.rest().chars().next()
   }
 
-  pub fn get_next_token(&mut self) -> Result<Token, LexerError> {
-    while self.peek_next() != None {
-      debug!("get_next_token: {}", self.text);
+  // the next token along with the span of source it was read from, and any
+  // `///` doc comment lines directly before it
+  pub fn get_next_token(&mut self) -> Result<Lexeme, LexerError> {
+    let mut doc: Vec<String> = Vec::new();
+
+    while self.peek_next().is_some() {
+      debug!("get_next_token: {}", self.rest());
+
+      let start = self.span_of(0, 0);
 
       let token = match self.peek_next() {
-        Some('/') if self.text.starts_with("//") => {
+        // `///` starts a doc comment, but `////` is just a comment
+        Some('/') if self.rest().starts_with("///") && !self.rest().starts_with("////") => {
+          self.advance(3);
+          let line = self.skip_inline_comment();
+          doc.push(line.strip_prefix(' ').unwrap_or(&line).to_string());
+          continue;
+        },
+        Some('/') if self.rest().starts_with("//") => {
           self.advance(2);
           self.skip_inline_comment();
           continue;
         },
-        Some('/') if self.text.starts_with("/*") => {
-          self.advance(2);
-          self.skip_block_comment();
+        Some('/') if self.rest().starts_with("/*") => {
+          self.skip_block_comment()?;
           continue;
         },
+        Some('+') if self.rest().starts_with("+=") => {
+          self.advance(2);
+          Token::PlusAssign
+        },
         Some('+') => {
           self.advance(1);
           Token::Plus
         },
+        Some('-') if self.rest().starts_with("-=") => {
+          self.advance(2);
+          Token::MinusAssign
+        },
         Some('-') => {
           self.advance(1);
           Token::Minus
         },
+        Some('*') if self.rest().starts_with("**=") => {
+          self.advance(3);
+          Token::PowAssign
+        },
+        Some('*') if self.rest().starts_with("*=") => {
+          self.advance(2);
+          Token::TimesAssign
+        },
+        Some('*') if self.rest().starts_with("**") => {
+          self.advance(2);
+          Token::Pow
+        },
         Some('*') => {
           self.advance(1);
           Token::Times
         },
+        Some('/') if self.rest().starts_with("/=") => {
+          self.advance(2);
+          Token::DivAssign
+        },
         Some('/') => {
           self.advance(1);
           Token::Div
         },
+        Some('%') if self.rest().starts_with("%=") => {
+          self.advance(2);
+          Token::ModAssign
+        },
         Some('%') => {
           self.advance(1);
           Token::Mod
@@ -123,23 +328,55 @@ impl Lexer {
           self.advance(1);
           Token::RParen
         },
-        Some('&') if self.text.starts_with("&&") => {
+        Some('&') if self.rest().starts_with("&&") => {
           self.advance(2);
           Token::And
         },
-        Some('|') if self.text.starts_with("||") => {
+        Some('&') if self.rest().starts_with("&=") => {
+          self.advance(2);
+          Token::BitAndAssign
+        },
+        Some('&') => {
+          self.advance(1);
+          Token::BitAnd
+        },
+        Some('|') if self.rest().starts_with("||") => {
           self.advance(2);
           Token::Or
         },
-        Some('=') if self.text.starts_with("==") => {
+        Some('|') if self.rest().starts_with("|=") => {
+          self.advance(2);
+          Token::BitOrAssign
+        },
+        Some('|') => {
+          self.advance(1);
+          Token::BitOr
+        },
+        Some('^') if self.rest().starts_with("^=") => {
+          self.advance(2);
+          Token::BitXorAssign
+        },
+        Some('^') => {
+          self.advance(1);
+          Token::BitXor
+        },
+        Some('~') => {
+          self.advance(1);
+          Token::BitNot
+        },
+        Some('=') if self.rest().starts_with("==") => {
           self.advance(2);
           Token::Eq
         },
+        Some('=') if self.rest().starts_with("=>") => {
+          self.advance(2);
+          Token::FatArrow
+        },
         Some('=') => {
           self.advance(1);
           Token::Assign
         },
-        Some('!') if self.text.starts_with("!=") => {
+        Some('!') if self.rest().starts_with("!=") => {
           self.advance(2);
           Token::Ne
         },
@@ -147,7 +384,15 @@ impl Lexer {
           self.advance(1);
           Token::Not
         },
-        Some('>') if self.text.starts_with(">=") => {
+        Some('>') if self.rest().starts_with(">>=") => {
+          self.advance(3);
+          Token::ShrAssign
+        },
+        Some('>') if self.rest().starts_with(">>") => {
+          self.advance(2);
+          Token::Shr
+        },
+        Some('>') if self.rest().starts_with(">=") => {
           self.advance(2);
           Token::Geq
         },
@@ -155,7 +400,15 @@ impl Lexer {
           self.advance(1);
           Token::Gt
         },
-        Some('<') if self.text.starts_with("<=") => {
+        Some('<') if self.rest().starts_with("<<=") => {
+          self.advance(3);
+          Token::ShlAssign
+        },
+        Some('<') if self.rest().starts_with("<<") => {
+          self.advance(2);
+          Token::Shl
+        },
+        Some('<') if self.rest().starts_with("<=") => {
           self.advance(2);
           Token::Leq
         },
@@ -173,7 +426,19 @@ impl Lexer {
         },
         Some(':') => {
           self.advance(1);
-          Token::Else
+          Token::Colon
+        },
+        Some('.') if self.rest().starts_with("..=") => {
+          self.advance(3);
+          Token::DotDotEq
+        },
+        Some('.') if self.rest().starts_with("..") => {
+          self.advance(2);
+          Token::DotDot
+        },
+        Some('.') => {
+          self.advance(1);
+          Token::Dot
         },
         Some('{') => {
           self.advance(1);
@@ -183,23 +448,58 @@ impl Lexer {
           self.advance(1);
           Token::RBracket
         },
+        Some('[') => {
+          self.advance(1);
+          Token::LSquare
+        },
+        Some(']') => {
+          self.advance(1);
+          Token::RSquare
+        },
+        Some('"') => self.lex_string()?,
         Some(',') => {
           self.advance(1);
           Token::Comma
         },
-        Some(c) if c.is_alphabetic() => return self.lex_keyword(),
-        Some(c) if c.is_digit(10) => return self.lex_integer(),
+        Some(c) if c.is_alphabetic() || c == '_' => self.lex_keyword()?,
+        Some(c) if c.is_ascii_digit() => self.lex_number()?,
         Some(c) if c.is_whitespace() => {
           self.skip_whitespace();
           continue;
         },
-        Some(c) => return Err(LexerError::UnexpectedCharacter(format!("unexpected char {:?}", c))),
+        Some(c) => {
+          let span = self.span_of(0, c.len_utf8());
+          return Err(LexerError::UnexpectedCharacter(format!("unexpected char {:?}", c), span));
+        },
         None => Token::EOF
       };
 
-      return Ok(token)
+      return Ok(Lexeme { token, span: Span { end: self.pos, ..start }, doc: join_doc(doc) })
     }
 
-    Ok(Token::EOF)
+    Ok(Lexeme { token: Token::EOF, span: self.span_of(0, 0), doc: join_doc(doc) })
+  }
+}
+
+// whether `s` is read as a single name, and not a keyword or anything else
+pub fn is_name(s: &str) -> bool {
+  match Lexer::new(s.to_string()).get_next_token() {
+    Ok(Lexeme { token: Token::Var(ref x), .. }) => x == s,
+    _ => false,
   }
 }
+
+fn join_doc(lines: Vec<String>) -> Option<String> {
+  match lines.is_empty() {
+    true => None,
+    false => Some(lines.join("\n")),
+  }
+}
+
+// the line and column reached by reading `text` from `line`:`col`
+fn position_after(line: usize, col: usize, text: &str) -> (usize, usize) {
+  text.chars().fold((line, col), |(line, col), c| match c {
+    '\n' => (line + 1, 1),
+    _ => (line, col + 1),
+  })
+}
@@ -1,20 +1,44 @@
+use span::Span;
 use std::error;
 use std::fmt;
 use std::num;
 
 #[derive(Debug, PartialEq)]
 pub enum LexerError {
-    UnexpectedCharacter(String),
-    InvalidKeyword(String),
-    ParseInt(num::ParseIntError),
+    UnexpectedCharacter(String, Span),
+    InvalidKeyword(String, Span),
+    UnterminatedString(String, Span),
+    UnterminatedComment(String, Span),
+    InvalidEscape(String, Span),
+    ParseInt(num::ParseIntError, Span),
+    ParseFloat(num::ParseFloatError, Span),
+}
+
+impl LexerError {
+    // where in the source the error was found
+    pub fn span(&self) -> Span {
+        match *self {
+            LexerError::UnexpectedCharacter(_, span) => span,
+            LexerError::InvalidKeyword(_, span) => span,
+            LexerError::UnterminatedString(_, span) => span,
+            LexerError::UnterminatedComment(_, span) => span,
+            LexerError::InvalidEscape(_, span) => span,
+            LexerError::ParseInt(_, span) => span,
+            LexerError::ParseFloat(_, span) => span,
+        }
+    }
 }
 
 impl fmt::Display for LexerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            LexerError::UnexpectedCharacter(ref s) => write!(f, "{}", s),
-            LexerError::InvalidKeyword(ref s) => write!(f, "{}", s),
-            LexerError::ParseInt(ref err) => write!(f, "Parse error: {}", err),
+            LexerError::UnexpectedCharacter(ref s, _) => write!(f, "{}", s),
+            LexerError::InvalidKeyword(ref s, _) => write!(f, "{}", s),
+            LexerError::UnterminatedString(ref s, _) => write!(f, "{}", s),
+            LexerError::UnterminatedComment(ref s, _) => write!(f, "{}", s),
+            LexerError::InvalidEscape(ref s, _) => write!(f, "{}", s),
+            LexerError::ParseInt(ref err, _) => write!(f, "Parse error: {}", err),
+            LexerError::ParseFloat(ref err, _) => write!(f, "Parse error: {}", err),
         }
     }
 }
@@ -22,15 +46,13 @@ impl fmt::Display for LexerError {
 impl error::Error for LexerError {
     fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
-            LexerError::UnexpectedCharacter(_) => None,
-            LexerError::InvalidKeyword(_) => None,
-            LexerError::ParseInt(ref err) => Some(err),
+            LexerError::UnexpectedCharacter(_, _) => None,
+            LexerError::InvalidKeyword(_, _) => None,
+            LexerError::UnterminatedString(_, _) => None,
+            LexerError::UnterminatedComment(_, _) => None,
+            LexerError::InvalidEscape(_, _) => None,
+            LexerError::ParseInt(ref err, _) => Some(err),
+            LexerError::ParseFloat(ref err, _) => Some(err),
         }
     }
 }
-
-impl From<num::ParseIntError> for LexerError {
-    fn from(err: num::ParseIntError) -> LexerError {
-        LexerError::ParseInt(err)
-    }
-}
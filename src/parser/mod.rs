@@ -1,5 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod parser;
 pub mod parser_error;
-mod token;
-mod lexer;
-mod lexer_error;
+pub mod token;
+pub mod lexer;
+pub mod lexer_error;
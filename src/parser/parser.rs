@@ -1,7 +1,10 @@
-use expr::{Val, Expr, BinOp, UnOp, Dec};
+use expr::{Val, Expr, BinOp, UnOp, Dec, Pattern};
 use parser::lexer::Lexer;
-use parser::token::Token;
+use parser::token::{Lexeme, Token};
 use parser::parser_error::ParserError;
+use span::Span;
+use std::collections::VecDeque;
+use std::mem;
 use std::result;
 
 pub type Result<T> = result::Result<T, ParserError>;
@@ -9,13 +12,27 @@ pub type Result<T> = result::Result<T, ParserError>;
 struct Parser {
   lexer: Lexer,
   current_token: Token,
+  current_span: Span,
+  // the tokens after the current one that have been peeked at but not eaten
+  lookahead: VecDeque<Lexeme>,
+  // the span of the last token eaten, where the node being parsed ends
+  prev_span: Span,
+  // how many loops and functions enclose the current token, for checking
+  // where `break`, `continue` and `return` may appear
+  loop_depth: usize,
+  fn_depth: usize,
 }
 
 impl Parser {
-  pub fn new(lexer: Lexer, token: Token) -> Parser {
+  pub fn new(lexer: Lexer, lexeme: Lexeme) -> Parser {
     Parser {
-      lexer: lexer,
-      current_token: token,
+      lexer,
+      current_token: lexeme.token,
+      current_span: lexeme.span,
+      lookahead: VecDeque::new(),
+      prev_span: lexeme.span,
+      loop_depth: 0,
+      fn_depth: 0,
     }
   }
 
@@ -23,35 +40,70 @@ impl Parser {
     self.current_token.clone()
   }
 
+  // the nth token after the current one, without consuming anything
+  fn peek_token(&mut self, n: usize) -> Result<Token> {
+    if n == 0 {
+      return Ok(self.current_token());
+    }
+
+    while self.lookahead.len() < n {
+      let next = self.lexer.get_next_token()?;
+      self.lookahead.push_back(next);
+    }
+
+    Ok(self.lookahead[n - 1].token.clone())
+  }
+
   fn eat(&mut self, expected: Token) -> Result<()> {
     let actual = self.current_token();
 
     if expected != actual {
-      return Err(ParserError::UnexpectedToken(expected, actual))
+      return Err(ParserError::UnexpectedToken(expected, actual, self.current_span))
     }
 
-    self.current_token = self.lexer.get_next_token()?;
+    let next = match self.lookahead.pop_front() {
+      Some(next) => next,
+      None => self.lexer.get_next_token()?,
+    };
+
+    self.prev_span = self.current_span;
+    self.current_token = next.token;
+    self.current_span = next.span;
     debug!("new current token: {:?}", self.current_token);
     Ok(())
   }
 
-  fn ternary(&mut self, e1: Expr, e2: Expr, e3: Expr) -> Expr {
-    Expr::Ternary(Box::new(e1), Box::new(e2), Box::new(e3))
+  fn invalid_token(&self, t: Token, context: &str) -> ParserError {
+    ParserError::InvalidToken(t, String::from(context), self.current_span)
   }
 
-  fn binop(&mut self, bop: BinOp, e1: Expr, e2: Expr) -> Expr {
-    Expr::Bop(bop, Box::new(e1), Box::new(e2))
+  // wraps a node with the span running from `start` through the last token eaten
+  fn located(&self, start: Span, e: Expr) -> Expr {
+    Expr::Located(start.to(&self.prev_span), Box::new(e))
+  }
+
+  fn ternary(&mut self, start: Span, e1: Expr, e2: Expr, e3: Expr) -> Expr {
+    self.located(start, Expr::Ternary(Box::new(e1), Box::new(e2), Box::new(e3)))
+  }
+
+  fn binop(&mut self, start: Span, bop: BinOp, e1: Expr, e2: Expr) -> Expr {
+    self.located(start, Expr::Bop(bop, Box::new(e1), Box::new(e2)))
   }
 
   fn parse_fn_params(&mut self) -> Result<Vec<Expr>> {
-    let mut params = Vec::new();
+    self.parse_items(Token::RParen)
+  }
+
+  // comma separated expressions up to (but not including) the closing token
+  fn parse_items(&mut self, end: Token) -> Result<Vec<Expr>> {
+    let mut items = Vec::new();
     let mut token = self.current_token();
 
-    while token != Token::RParen {
-      debug!("getting fn params");
+    while token != end && token != Token::EOF {
+      debug!("getting items until {:?}", end);
       let term = self.binop_expr()?;
 
-      params.push(term);
+      items.push(term);
 
       if self.current_token == Token::Comma {
         self.eat(Token::Comma)?;
@@ -60,7 +112,44 @@ impl Parser {
       token = self.current_token();
     }
 
-    Ok(params)
+    Ok(items)
+  }
+
+  // fn calls, indexing and field access, repeated so that e.g. returned functions can be
+  // called directly
+  fn parse_postfix(&mut self, start: Span, e: Expr) -> Result<Expr> {
+    let mut node = e;
+
+    loop {
+      node = match self.current_token() {
+        Token::LParen => {
+          self.eat(Token::LParen)?;
+          let params = self.parse_fn_params()?;
+          self.eat(Token::RParen)?;
+
+          self.located(start, Expr::FnCall(Box::new(node), params))
+        },
+        Token::LSquare => {
+          self.eat(Token::LSquare)?;
+          let index = self.statement()?;
+          self.eat(Token::RSquare)?;
+
+          self.located(start, Expr::Index(Box::new(node), Box::new(index)))
+        },
+        Token::Dot => {
+          self.eat(Token::Dot)?;
+
+          match self.current_token() {
+            Token::Var(s) => {
+              self.eat(Token::Var(s.clone()))?;
+              self.located(start, Expr::Field(Box::new(node), s))
+            },
+            t => return Err(self.invalid_token(t, "parsing field access")),
+          }
+        },
+        _ => return Ok(node),
+      };
+    }
   }
 
   fn parse_fn_decl_params(&mut self) -> Result<Vec<Expr>> {
@@ -75,7 +164,7 @@ impl Parser {
           params.push(Expr::Var(s));
         },
         Token::Comma => self.eat(Token::Comma)?,
-        _ => return Err(ParserError::InvalidToken(token, String::from("parsing fn decl params")))
+        _ => return Err(self.invalid_token(token, "parsing fn decl params"))
       }
 
       token = self.current_token();
@@ -86,13 +175,15 @@ impl Parser {
 
   fn parse_print(&mut self) -> Result<Expr> {
     debug!("parsing print...");
+    let start = self.current_span;
     self.eat(Token::Print)?;
     let term = self.binop_expr()?;
-    Ok(Expr::Print(Box::new(term)))
+    Ok(self.located(start, Expr::Print(Box::new(term))))
   }
 
   fn parse_fn(&mut self) -> Result<Expr> {
     debug!("parsing named fn...");
+    let start = self.current_span;
     self.eat(Token::FnDecl)?;
 
     let var = match self.current_token() {
@@ -107,209 +198,577 @@ impl Parser {
     let params = self.parse_fn_decl_params()?;
     self.eat(Token::RParen)?;
 
+    // a loop around the function doesn't extend into its body
+    let loop_depth = mem::replace(&mut self.loop_depth, 0);
+    self.fn_depth += 1;
+
     self.eat(Token::LBracket)?;
     let body = self.block()?;
     self.eat(Token::RBracket)?;
 
+    self.fn_depth -= 1;
+    self.loop_depth = loop_depth;
+
     match var {
       Some(v) => {
+        let func = self.located(start, Expr::Lambda(Some(Box::new(v.clone())), Box::new(body), params));
+
         self.eat(Token::Seq)?;
         let e3 = self.block()?;
 
-        let func = Expr::Val(Val::Func(Some(Box::new(v.clone())), Box::new(body), params));
-
-        Ok(Expr::Decl(Dec::DConst, Box::new(v), Box::new(func), Box::new(e3)))
+        Ok(self.located(start, Expr::Decl(Dec::DConst, Box::new(v), Box::new(func), Box::new(e3))))
       },
       None => {
-        let func = Expr::Val(Val::Func(None, Box::new(body), params));
+        let func = self.located(start, Expr::Lambda(None, Box::new(body), params));
 
-        // fn call rule
-        if self.current_token == Token::LParen {
-          self.eat(Token::LParen)?;
-          let params = self.parse_fn_params()?;
-          self.eat(Token::RParen)?;
+        self.parse_postfix(start, func)
+      }
+    }
+  }
 
-          Ok(Expr::FnCall(Box::new(func), params))
-        } else {
-          Ok(func)
-        }
+  // `{` starts a record rather than a block when it is immediately closed
+  // (`{}`), or when it is followed by a key and a colon (`{ name: ...`)
+  fn is_record_start(&mut self) -> Result<bool> {
+    Ok(matches!(
+      (self.peek_token(1)?, self.peek_token(2)?),
+      (Token::RBracket, _) | (Token::Var(_), Token::Colon) | (Token::Str(_), Token::Colon)
+    ))
+  }
+
+  fn parse_record(&mut self) -> Result<Expr> {
+    debug!("parsing record...");
+    let start = self.current_span;
+    self.eat(Token::LBracket)?;
+
+    let mut fields = Vec::new();
+    let mut token = self.current_token();
+
+    while token != Token::RBracket && token != Token::EOF {
+      let key = match token {
+        Token::Var(s) | Token::Str(s) => s,
+        _ => return Err(self.invalid_token(token, "parsing record key")),
+      };
+
+      self.eat(self.current_token())?;
+      self.eat(Token::Colon)?;
+      fields.push((key, self.binop_expr()?));
+
+      if self.current_token == Token::Comma {
+        self.eat(Token::Comma)?;
       }
+
+      token = self.current_token();
     }
+
+    self.eat(Token::RBracket)?;
+
+    let record = self.located(start, Expr::Record(fields));
+    self.parse_postfix(start, record)
   }
 
   fn parse_while(&mut self) -> Result<Expr> {
+    let start = self.current_span;
     self.eat(Token::While)?;
     self.eat(Token::LParen)?;
     let e1 = self.statement()?;
     self.eat(Token::RParen)?;
-    let e2 = self.factor()?;
-    self.eat(Token::Seq)?;
-    let e3 = self.block()?;
+    let (e2, e3) = self.parse_loop_body()?;
 
-    return Ok(Expr::While(
+    Ok(self.located(start, Expr::While(
       Box::new(e1.clone()),
       Box::new(e1),
       Box::new(e2.clone()),
       Box::new(e2),
       Box::new(e3)
+    )))
+  }
+
+  // `for (x in e) body; rest` or `for (init; cond; update) body; rest`
+  fn parse_for(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+    self.eat(Token::For)?;
+    self.eat(Token::LParen)?;
+
+    if let (Token::Var(x), Token::In) = (self.current_token(), self.peek_token(1)?) {
+      self.eat(Token::Var(x.clone()))?;
+      self.eat(Token::In)?;
+      let e1 = self.statement()?;
+      self.eat(Token::RParen)?;
+
+      let (body, rest) = self.parse_loop_body()?;
+
+      return Ok(self.located(start, Expr::ForIn(Box::new(Expr::Var(x)), Box::new(e1), Box::new(body), Box::new(rest))));
+    }
+
+    // a declaration in the initializer runs before the loop, and like any
+    // other declaration its binding stays in scope after it
+    let init = match self.current_token() {
+      Token::Seq => None,
+      Token::VarDecl | Token::Let => {
+        let dt = match self.current_token() {
+          Token::Let => Dec::DConst,
+          _ => Dec::DVar,
+        };
+
+        self.eat(self.current_token())?;
+        let var = self.factor()?;
+        self.eat(Token::Assign)?;
+        Some((Some((dt, var.unlocated())), self.statement()?))
+      },
+      _ => Some((None, self.statement()?)),
+    };
+    self.eat(Token::Seq)?;
+
+    let cond = match self.current_token() {
+      Token::Seq => Expr::Val(Val::Bool(true)),
+      _ => self.statement()?,
+    };
+    self.eat(Token::Seq)?;
+
+    let update = match self.current_token() {
+      Token::RParen => Expr::Val(Val::Undefined),
+      _ => self.statement()?,
+    };
+    self.eat(Token::RParen)?;
+
+    let (body, rest) = self.parse_loop_body()?;
+
+    let for_loop = self.located(start, Expr::For(
+      Box::new(cond.clone()),
+      Box::new(cond),
+      Box::new(update),
+      Box::new(body),
+      Box::new(rest),
     ));
+
+    Ok(match init {
+      None => for_loop,
+      Some((Some((dt, var)), e1)) => {
+        self.located(start, Expr::Decl(dt, Box::new(var), Box::new(e1), Box::new(for_loop)))
+      },
+      Some((None, e1)) => self.binop(start, BinOp::Seq, e1, for_loop),
+    })
+  }
+
+  // the body of a loop or a branch of an if, where `{}` is an empty block
+  // that evaluates to undefined rather than an empty record
+  fn parse_body(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+
+    if let (Token::LBracket, Token::RBracket) = (self.current_token(), self.peek_token(1)?) {
+      self.eat(Token::LBracket)?;
+      self.eat(Token::RBracket)?;
+      return Ok(self.located(start, Expr::Val(Val::Undefined)));
+    }
+
+    self.factor()
+  }
+
+  // a loop body, and the rest of the block after the `;` that ends the loop,
+  // which can be left out when the loop is last in its block
+  fn parse_loop_body(&mut self) -> Result<(Expr, Expr)> {
+    self.loop_depth += 1;
+    let body = self.parse_body()?;
+    self.loop_depth -= 1;
+
+    if let Token::RBracket | Token::EOF = self.current_token() {
+      return Ok((body, Expr::Val(Val::Undefined)));
+    }
+
+    self.eat(Token::Seq)?;
+
+    let rest = match self.current_token() {
+      Token::RBracket => Expr::Val(Val::Undefined),
+      _ => self.block()?,
+    };
+
+    Ok((body, rest))
+  }
+
+  fn parse_return(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+
+    if self.fn_depth == 0 {
+      return Err(ParserError::MisplacedToken(Token::Return, String::from("inside a function"), start));
+    }
+
+    self.eat(Token::Return)?;
+
+    // `return` on its own returns undefined
+    let e1 = match self.current_token() {
+      Token::Seq | Token::RBracket | Token::EOF => Expr::Val(Val::Undefined),
+      _ => self.statement()?,
+    };
+
+    Ok(self.located(start, Expr::Return(Box::new(e1))))
+  }
+
+  // `break` or `continue`, which must be inside a loop
+  fn parse_loop_jump(&mut self, token: Token, e: Expr) -> Result<Expr> {
+    let start = self.current_span;
+
+    if self.loop_depth == 0 {
+      return Err(ParserError::MisplacedToken(token, String::from("inside a loop"), start));
+    }
+
+    self.eat(token)?;
+    Ok(self.located(start, e))
+  }
+
+  fn parse_throw(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+    self.eat(Token::Throw)?;
+    let e1 = self.statement()?;
+
+    Ok(self.located(start, Expr::Throw(Box::new(e1))))
+  }
+
+  // `try { body } catch (x) { handler }`
+  fn parse_try(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+    self.eat(Token::Try)?;
+
+    self.eat(Token::LBracket)?;
+    let body = self.block()?;
+    self.eat(Token::RBracket)?;
+
+    self.eat(Token::Catch)?;
+    self.eat(Token::LParen)?;
+    let var = match self.current_token() {
+      Token::Var(x) => {
+        self.eat(Token::Var(x.clone()))?;
+        Expr::Var(x)
+      },
+      t => return Err(self.invalid_token(t, "parsing catch binding")),
+    };
+    self.eat(Token::RParen)?;
+
+    self.eat(Token::LBracket)?;
+    let handler = self.block()?;
+    self.eat(Token::RBracket)?;
+
+    Ok(self.located(start, Expr::Try(Box::new(body), Box::new(var), Box::new(handler))))
+  }
+
+  // `match e { pattern if guard => body, ... }`, where the guard is optional
+  // and a trailing comma is allowed
+  fn parse_match(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+    self.eat(Token::Match)?;
+    let e1 = self.binop_expr()?;
+    self.eat(Token::LBracket)?;
+
+    let mut arms = Vec::new();
+
+    while self.current_token() != Token::RBracket && self.current_token() != Token::EOF {
+      let pattern = self.parse_pattern(&mut Vec::new())?;
+
+      let guard = match self.current_token() {
+        Token::If => {
+          self.eat(Token::If)?;
+          Some(self.statement()?)
+        },
+        _ => None,
+      };
+
+      self.eat(Token::FatArrow)?;
+      arms.push((pattern, guard, self.statement()?));
+
+      if self.current_token() != Token::Comma {
+        break;
+      }
+
+      self.eat(Token::Comma)?;
+    }
+
+    self.eat(Token::RBracket)?;
+
+    Ok(self.located(start, Expr::Match(Box::new(e1), arms)))
+  }
+
+  // `bound` collects the names bound so far, since each may only be bound once
+  fn parse_pattern(&mut self, bound: &mut Vec<String>) -> Result<Pattern> {
+    let pattern = match self.current_token() {
+      Token::Var(ref x) if x == "_" => Pattern::Wildcard,
+      Token::Var(x) => {
+        if bound.contains(&x) {
+          return Err(self.invalid_token(Token::Var(x), "binding a name already bound in this pattern"));
+        }
+
+        bound.push(x.clone());
+        Pattern::Bind(x)
+      },
+      Token::Int(n) => Pattern::Lit(Val::Int(n)),
+      Token::Float(n) => Pattern::Lit(Val::Float(n)),
+      Token::Bool(b) => Pattern::Lit(Val::Bool(b)),
+      Token::Str(s) => Pattern::Lit(Val::Str(s)),
+      Token::Minus => {
+        self.eat(Token::Minus)?;
+
+        return match self.current_token() {
+          Token::Int(n) => {
+            self.eat(Token::Int(n))?;
+            Ok(Pattern::Lit(Val::Int(-n)))
+          },
+          Token::Float(n) => {
+            self.eat(Token::Float(n))?;
+            Ok(Pattern::Lit(Val::Float(-n)))
+          },
+          t => Err(self.invalid_token(t, "parsing negative number pattern")),
+        };
+      },
+      Token::LSquare => {
+        self.eat(Token::LSquare)?;
+
+        let mut items = Vec::new();
+        let mut rest = None;
+
+        while self.current_token() != Token::RSquare && self.current_token() != Token::EOF {
+          // `..rest` or a bare `..` ends the list
+          if self.current_token() == Token::DotDot {
+            self.eat(Token::DotDot)?;
+
+            rest = match self.current_token() {
+              Token::RSquare | Token::Comma => Some(Box::new(Pattern::Wildcard)),
+              _ => Some(Box::new(self.parse_pattern(bound)?)),
+            };
+
+            if self.current_token() == Token::Comma {
+              self.eat(Token::Comma)?;
+            }
+
+            break;
+          }
+
+          items.push(self.parse_pattern(bound)?);
+
+          if self.current_token() != Token::Comma {
+            break;
+          }
+
+          self.eat(Token::Comma)?;
+        }
+
+        self.eat(Token::RSquare)?;
+        return Ok(Pattern::List(items, rest));
+      },
+      Token::LBracket => {
+        self.eat(Token::LBracket)?;
+
+        let mut fields = Vec::new();
+
+        while self.current_token() != Token::RBracket && self.current_token() != Token::EOF {
+          let key = match self.current_token() {
+            Token::Var(s) | Token::Str(s) => s,
+            t => return Err(self.invalid_token(t, "parsing record pattern key")),
+          };
+
+          // `{ x }` is short for `{ x: x }`
+          let field = match self.peek_token(1)? {
+            Token::Colon => {
+              self.eat(self.current_token())?;
+              self.eat(Token::Colon)?;
+              self.parse_pattern(bound)?
+            },
+            _ => self.parse_pattern(bound)?,
+          };
+
+          fields.push((key, field));
+
+          if self.current_token() != Token::Comma {
+            break;
+          }
+
+          self.eat(Token::Comma)?;
+        }
+
+        self.eat(Token::RBracket)?;
+        return Ok(Pattern::Record(fields));
+      },
+      t => return Err(self.invalid_token(t, "parsing pattern")),
+    };
+
+    self.eat(self.current_token())?;
+    Ok(pattern)
   }
 
   fn parse_if(&mut self) -> Result<Expr> {
+    let start = self.current_span;
     self.eat(Token::If)?;
     let e1 = self.binop_expr()?;
-    let e2 = self.block()?;
-    let e3 = self.statement()?;
+    let e2 = self.parse_body()?;
 
-    return Ok(self.ternary(e1, e2, e3));
+    // without an else the statement is done, and evaluates to undefined
+    let e3 = match self.current_token() {
+      Token::Else => {
+        self.eat(Token::Else)?;
+        self.parse_body()?
+      },
+      _ => Expr::Val(Val::Undefined),
+    };
+
+    Ok(self.ternary(start, e1, e2, e3))
   }
 
   fn factor(&mut self) -> Result<Expr> {
+    let start = self.current_span;
+
     let e = match self.current_token() {
       Token::Int(n) => {
-        self.eat(Token::Int(n.clone()))?;
-        Expr::Val(Val::Int(n))
+        self.eat(Token::Int(n))?;
+        self.located(start, Expr::Val(Val::Int(n)))
+      },
+      Token::Float(n) => {
+        self.eat(Token::Float(n))?;
+        self.located(start, Expr::Val(Val::Float(n)))
+      },
+      Token::Str(s) => {
+        self.eat(Token::Str(s.clone()))?;
+        let string = self.located(start, Expr::Val(Val::Str(s)));
+        self.parse_postfix(start, string)?
+      },
+      Token::LSquare => {
+        self.eat(Token::LSquare)?;
+        let items = self.parse_items(Token::RSquare)?;
+        self.eat(Token::RSquare)?;
+        let list = self.located(start, Expr::List(items));
+        self.parse_postfix(start, list)?
       },
       Token::Bool(b) => {
-        self.eat(Token::Bool(b.clone()))?;
-        Expr::Val(Val::Bool(b))
+        self.eat(Token::Bool(b))?;
+        self.located(start, Expr::Val(Val::Bool(b)))
+      },
+      Token::Undefined => {
+        self.eat(Token::Undefined)?;
+        self.located(start, Expr::Val(Val::Undefined))
       },
       Token::Var(s) => {
         self.eat(Token::Var(s.clone()))?;
-
-        // fn call rule
-        if self.current_token == Token::LParen {
-          self.eat(Token::LParen)?;
-          let params = self.parse_fn_params()?;
-          self.eat(Token::RParen)?;
-
-          Expr::FnCall(Box::new(Expr::Var(s)), params)
-        } else {
-          Expr::Var(s)
-        }
+        let var = self.located(start, Expr::Var(s));
+        self.parse_postfix(start, var)?
       },
       Token::Print => {
         self.parse_print()?
       },
+      Token::Return => {
+        self.parse_return()?
+      },
+      Token::Break => {
+        self.parse_loop_jump(Token::Break, Expr::Break)?
+      },
+      Token::Continue => {
+        self.parse_loop_jump(Token::Continue, Expr::Continue)?
+      },
       Token::FnDecl => {
         self.parse_fn()?
       },
       Token::VarDecl => {
         self.eat(Token::VarDecl)?;
-        let var = self.term()?;
+        let var = self.factor()?;
         self.eat(Token::Assign)?;
         let e2 = self.statement()?;
         self.eat(Token::Seq)?;
         let e3 = self.block()?;
 
-        Expr::Decl(Dec::DVar, Box::new(var), Box::new(e2), Box::new(e3))
+        self.located(start, Expr::Decl(Dec::DVar, Box::new(var.unlocated()), Box::new(e2), Box::new(e3)))
       },
       Token::Let => {
         self.eat(Token::Let)?;
-        let var = self.term()?;
+        let var = self.factor()?;
         self.eat(Token::Assign)?;
         let e2 = self.statement()?;
         self.eat(Token::Seq)?;
         let e3 = self.block()?;
 
-        Expr::Decl(Dec::DConst, Box::new(var), Box::new(e2), Box::new(e3))
+        self.located(start, Expr::Decl(Dec::DConst, Box::new(var.unlocated()), Box::new(e2), Box::new(e3)))
       },
       Token::If => {
         self.parse_if()?
       },
-      Token::Else => {
-        self.eat(Token::Else)?;
-        self.statement()?
-      },
       Token::While => {
         self.parse_while()?
       },
+      Token::For => {
+        self.parse_for()?
+      },
+      Token::Match => {
+        self.parse_match()?
+      },
+      Token::Throw => {
+        self.parse_throw()?
+      },
+      Token::Try => {
+        self.parse_try()?
+      },
       Token::LParen => {
         self.eat(Token::LParen)?;
         let node = self.statement()?;
         self.eat(Token::RParen)?;
-        node
+        self.parse_postfix(start, node)?
+      },
+      Token::LBracket if self.is_record_start()? => {
+        self.parse_record()?
       },
       Token::LBracket => {
         self.eat(Token::LBracket)?;
         let node = self.block()?;
         self.eat(Token::RBracket)?;
-        node
+        self.located(start, node)
       },
       Token::Not => {
         self.eat(Token::Not)?;
-        Expr::Uop(UnOp::Not, Box::new(self.factor()?))
+        let e = self.binop_expr_from(Token::PREFIX_POWER)?;
+        self.located(start, Expr::Uop(UnOp::Not, Box::new(e)))
       },
       Token::Minus => {
         self.eat(Token::Minus)?;
-        Expr::Uop(UnOp::Neg, Box::new(self.factor()?))
+        let e = self.binop_expr_from(Token::PREFIX_POWER)?;
+        self.located(start, Expr::Uop(UnOp::Neg, Box::new(e)))
+      },
+      Token::BitNot => {
+        self.eat(Token::BitNot)?;
+        let e = self.binop_expr_from(Token::PREFIX_POWER)?;
+        self.located(start, Expr::Uop(UnOp::BitNot, Box::new(e)))
       },
       Token::EOF => {
         self.eat(Token::EOF)?;
         Expr::Val(Val::Undefined)
       },
       _ => {
-        return Err(ParserError::InvalidToken(self.current_token(), String::from("parsing factor")))
+        return Err(self.invalid_token(self.current_token(), "parsing factor"))
       }
     };
 
     Ok(e)
   }
 
-  pub fn term(&mut self) -> Result<Expr> {
+  // Precedence climbing over the operator table in Token::infix_op: parse
+  // operands while the next operator binds at least as tightly as `min_power`.
+  fn binop_expr_from(&mut self, min_power: u8) -> Result<Expr> {
+    let start = self.current_span;
     let mut node = self.factor()?;
 
-    let mut op = self.current_token();
-
-    while op.is_term_op() {
-      self.eat(op.clone())?;
-      let right_node = self.term()?;
+    while let Some((op, left_power, right_power)) = self.current_token().infix_op() {
+      if left_power < min_power {
+        break;
+      }
 
-      node = match op {
-        Token::Times => self.binop(BinOp::Times, node, right_node),
-        Token::Div => self.binop(BinOp::Div, node, right_node),
-        _ => return Err(ParserError::InvalidToken(op, String::from("parsing term")))
-      };
+      debug!("expr looping on op {:?}", op);
+      self.eat(self.current_token())?;
 
-      op = self.current_token();
+      let right_node = self.binop_expr_from(right_power)?;
+      node = self.binop(start, op, node, right_node);
     }
 
     Ok(node)
   }
 
   pub fn binop_expr(&mut self) -> Result<Expr> {
-    let mut node = self.term()?;
-
-    let mut op = self.current_token();
-
-    while op.is_expr_op() {
-      debug!("expr looping on op {:?}", op);
-      self.eat(op.clone())?;
-
-      let right_node = self.term()?;
-
-      node = match op {
-        Token::Plus => self.binop(BinOp::Plus, node, right_node),
-        Token::Minus => self.binop(BinOp::Minus, node, right_node),
-        Token::Eq => self.binop(BinOp::Eq, node, right_node),
-        Token::Ne => self.binop(BinOp::Ne, node, right_node),
-        Token::Leq => self.binop(BinOp::Leq, node, right_node),
-        Token::Geq => self.binop(BinOp::Geq, node, right_node),
-        Token::Lt => self.binop(BinOp::Lt, node, right_node),
-        Token::Gt => self.binop(BinOp::Gt, node, right_node),
-        Token::And => self.binop(BinOp::And, node, right_node),
-        Token::Or => self.binop(BinOp::Or, node, right_node),
-        Token::Mod => self.binop(BinOp::Mod, node, right_node),
-        _ => return Err(ParserError::InvalidToken(op, String::from("parsing binop expression")))
-      };
-
-      op = self.current_token();
-    }
-    
-    Ok(node)
+    self.binop_expr_from(0)
   }
 
   pub fn statement(&mut self) -> Result<Expr> {
+    let start = self.current_span;
     let mut node = self.binop_expr()?;
     let mut op = self.current_token();
 
@@ -319,15 +778,26 @@ impl Parser {
       node = match op {
         Token::Ternary => {
           let e2 = self.block()?;
-          self.eat(Token::Else)?;
+
+          // `a ? b else c` is the older spelling of `a ? b : c`
+          match self.current_token() {
+            Token::Else => self.eat(Token::Else)?,
+            _ => self.eat(Token::Colon)?,
+          }
+
           let e3 = self.statement()?;
-          self.ternary(node, e2, e3)
+          self.ternary(start, node, e2, e3)
         },
         Token::Assign => {
           let e2 = self.statement()?;
-          self.binop(BinOp::Assign, node, e2)
+          self.binop(start, BinOp::Assign, node.unlocated_place(), e2)
+        },
+        ref op if op.assign_op().is_some() => {
+          let e2 = self.statement()?;
+          let assign = Expr::AssignOp(op.assign_op().unwrap(), Box::new(node.unlocated_place()), Box::new(e2));
+          self.located(start, assign)
         },
-        _ => return Err(ParserError::InvalidToken(op, String::from("parsing statement")))
+        _ => return Err(self.invalid_token(op, "parsing statement"))
       };
 
       op = self.current_token();
@@ -337,6 +807,7 @@ impl Parser {
   }
 
   pub fn block(&mut self) -> Result<Expr> {
+    let start = self.current_span;
     let mut node = self.statement()?;
     let mut op = self.current_token();
 
@@ -350,9 +821,9 @@ impl Parser {
             _ => self.statement()?,
           };
 
-          self.binop(BinOp::Seq, node, e2)
+          self.binop(start, BinOp::Seq, node, e2)
         },
-        _ => return Err(ParserError::InvalidToken(op, String::from("parsing block")))
+        _ => return Err(self.invalid_token(op, "parsing block"))
       };
 
       op = self.current_token();
@@ -369,9 +840,9 @@ impl Parser {
 pub fn parse(input: &str) -> Result<Expr> {
   let mut lexer = Lexer::new(input.to_string());
 
-  let token = lexer.get_next_token()?;
+  let lexeme = lexer.get_next_token()?;
   
-  let mut parser = Parser::new(lexer, token);
+  let mut parser = Parser::new(lexer, lexeme);
   let expr = parser.program();
 
   debug!("parsed expr: {:#?}", expr);
@@ -1,25 +1,43 @@
 use parser::lexer_error::LexerError;
 use parser::token::Token;
+use span::Span;
 use std::error;
 use std::fmt;
 
 #[derive(Debug, PartialEq)]
 pub enum ParserError {
-    UnexpectedToken(Token, Token), // expected, actual
-    InvalidToken(Token, String),
+    UnexpectedToken(Token, Token, Span), // expected, actual
+    InvalidToken(Token, String, Span),
+    // a token that is only allowed in some context, like `break` in a loop
+    MisplacedToken(Token, String, Span),
     LexerError(LexerError),
 }
 
+impl ParserError {
+    // where in the source the error was found
+    pub fn span(&self) -> Span {
+        match *self {
+            ParserError::UnexpectedToken(_, _, span) => span,
+            ParserError::InvalidToken(_, _, span) => span,
+            ParserError::MisplacedToken(_, _, span) => span,
+            ParserError::LexerError(ref err) => err.span(),
+        }
+    }
+}
+
 impl fmt::Display for ParserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            ParserError::UnexpectedToken(ref t1, ref t2) => {
-                write!(f, "expected token: {:?} actual: {:?}", t1, t2)
+            ParserError::UnexpectedToken(ref t1, ref t2, _) => {
+                write!(f, "expected {} found {}", t1, t2)
+            }
+            ParserError::InvalidToken(ref t, ref s, _) => {
+                write!(f, "unexpected {} while {}", t, s)
             }
-            ParserError::InvalidToken(ref t, ref s) => {
-                write!(f, "invalid token {:?} while {}", t, s)
+            ParserError::MisplacedToken(ref t, ref s, _) => {
+                write!(f, "{} is only allowed {}", t, s)
             }
-            ParserError::LexerError(ref err) => write!(f, "Lexer error: {}", err),
+            ParserError::LexerError(ref err) => write!(f, "{}", err),
         }
     }
 }
@@ -27,8 +45,9 @@ impl fmt::Display for ParserError {
 impl error::Error for ParserError {
     fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
-            ParserError::UnexpectedToken(_, _) => None,
-            ParserError::InvalidToken(_, _) => None,
+            ParserError::UnexpectedToken(_, _, _) => None,
+            ParserError::InvalidToken(_, _, _) => None,
+            ParserError::MisplacedToken(_, _, _) => None,
             ParserError::LexerError(ref err) => Some(err),
         }
     }
@@ -1,3 +1,8 @@
+use expr::BinOp;
+use span::Span;
+use std::fmt;
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Plus,
@@ -16,63 +21,234 @@ pub enum Token {
     And,
     Or,
     Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
     Seq,
     Ternary,
+    Colon,
     Else,
     Var(String),
     Int(isize),
+    Float(f64),
+    Str(String),
     Bool(bool),
+    Undefined,
     Let,
     VarDecl,
     Assign,
+    PlusAssign,
+    MinusAssign,
+    TimesAssign,
+    DivAssign,
+    ModAssign,
+    PowAssign,
+    BitAndAssign,
+    BitOrAssign,
+    BitXorAssign,
+    ShlAssign,
+    ShrAssign,
     FnDecl,
     LBracket,
     RBracket,
+    LSquare,
+    RSquare,
     Comma,
+    Dot,
+    DotDot,
+    DotDotEq,
     If,
     While,
+    For,
+    In,
     EOF,
     Print,
+    Return,
+    Break,
+    Continue,
+    Match,
+    FatArrow,
+    Throw,
+    Try,
+    Catch,
 }
 
 impl Token {
-    pub fn is_term_op(&self) -> bool {
-        match *self {
-            Token::Times => true,
-            Token::Div => true,
-            _ => false,
-        }
+    // The binary operator a token stands for, with its binding powers on the
+    // left and right. Higher powers bind tighter, and an operator is left
+    // associative when its right power is the higher of the two, so that
+    // `8 / 4 / 2` groups as `(8 / 4) / 2`.
+    //
+    //   operators        precedence    associativity
+    //   **               13 (tightest) right
+    //   prefix - ! ~     12
+    //   * / %            11            left
+    //   + -              10            left
+    //   << >>            9             left
+    //   .. ..=           8             left
+    //   < > <= >=        7             left
+    //   == !=            6             left
+    //   &                5             left
+    //   ^                4             left
+    //   |                3             left
+    //   &&               2             left
+    //   ||               1 (loosest)   left
+    //
+    // This follows C, including its placement of the bitwise operators below
+    // comparison, so flags need parentheses: `(flags & MASK) == 0`. Ranges
+    // bind just looser than arithmetic and shifts, so `0..n - 1` is
+    // `0..(n - 1)` and `0..1 << n` is `0..(1 << n)`. As in maths,
+    // `**` binds tighter than a prefix operator on its left, so `-2 ** 2` is
+    // `-(2 ** 2)`, while `2 ** -1` still parses. `?:` and `=` bind looser
+    // than everything here.
+    pub fn infix_op(&self) -> Option<(BinOp, u8, u8)> {
+        let (op, precedence) = match *self {
+            Token::Pow => return Some((BinOp::Pow, 27, 26)),
+            Token::Times => (BinOp::Times, 11),
+            Token::Div => (BinOp::Div, 11),
+            Token::Mod => (BinOp::Mod, 11),
+            Token::Plus => (BinOp::Plus, 10),
+            Token::Minus => (BinOp::Minus, 10),
+            Token::Shl => (BinOp::Shl, 9),
+            Token::Shr => (BinOp::Shr, 9),
+            Token::DotDot => (BinOp::Range, 8),
+            Token::DotDotEq => (BinOp::RangeInclusive, 8),
+            Token::Lt => (BinOp::Lt, 7),
+            Token::Gt => (BinOp::Gt, 7),
+            Token::Leq => (BinOp::Leq, 7),
+            Token::Geq => (BinOp::Geq, 7),
+            Token::Eq => (BinOp::Eq, 6),
+            Token::Ne => (BinOp::Ne, 6),
+            Token::BitAnd => (BinOp::BitAnd, 5),
+            Token::BitXor => (BinOp::BitXor, 4),
+            Token::BitOr => (BinOp::BitOr, 3),
+            Token::And => (BinOp::And, 2),
+            Token::Or => (BinOp::Or, 1),
+            _ => return None,
+        };
+
+        Some((op, precedence * 2, precedence * 2 + 1))
     }
 
-    pub fn is_expr_op(&self) -> bool {
+    // the binding power of the operand of prefix `-`, `!` and `~`
+    pub const PREFIX_POWER: u8 = 24;
+
+    // the operator of a compound assignment like `+=`
+    pub fn assign_op(&self) -> Option<BinOp> {
         match *self {
-            Token::Plus => true,
-            Token::Minus => true,
-            Token::Eq => true,
-            Token::Ne => true,
-            Token::Leq => true,
-            Token::Geq => true,
-            Token::Lt => true,
-            Token::Gt => true,
-            Token::And => true,
-            Token::Or => true,
-            Token::Mod => true,
-            _ => false,
+            Token::PlusAssign => Some(BinOp::Plus),
+            Token::MinusAssign => Some(BinOp::Minus),
+            Token::TimesAssign => Some(BinOp::Times),
+            Token::DivAssign => Some(BinOp::Div),
+            Token::ModAssign => Some(BinOp::Mod),
+            Token::PowAssign => Some(BinOp::Pow),
+            Token::BitAndAssign => Some(BinOp::BitAnd),
+            Token::BitOrAssign => Some(BinOp::BitOr),
+            Token::BitXorAssign => Some(BinOp::BitXor),
+            Token::ShlAssign => Some(BinOp::Shl),
+            Token::ShrAssign => Some(BinOp::Shr),
+            _ => None,
         }
     }
 
     pub fn is_statement_op(&self) -> bool {
-        match *self {
-            Token::Ternary => true,
-            Token::Assign => true,
-            _ => false,
-        }
+        matches!(*self, Token::Ternary | Token::Assign) || self.assign_op().is_some()
     }
 
     pub fn is_block_op(&self) -> bool {
-        match *self {
-            Token::Seq => true,
-            _ => false,
-        }
+        matches!(*self, Token::Seq)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match *self {
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Times => "*",
+            Token::Div => "/",
+            Token::RParen => ")",
+            Token::LParen => "(",
+            Token::Eq => "==",
+            Token::Ne => "!=",
+            Token::Leq => "<=",
+            Token::Geq => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Not => "!",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Mod => "%",
+            Token::Pow => "**",
+            Token::BitAnd => "&",
+            Token::BitOr => "|",
+            Token::BitXor => "^",
+            Token::BitNot => "~",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::Seq => ";",
+            Token::Ternary => "?",
+            Token::Colon => ":",
+            Token::Else => "else",
+            Token::Var(ref s) => return write!(f, "'{}'", s),
+            Token::Int(n) => return write!(f, "'{}'", n),
+            Token::Float(n) => return write!(f, "'{:?}'", n),
+            Token::Str(ref s) => return write!(f, "{:?}", s),
+            Token::Bool(b) => return write!(f, "'{}'", b),
+            Token::Undefined => "undefined",
+            Token::Let => "let",
+            Token::VarDecl => "var",
+            Token::Assign => "=",
+            Token::PlusAssign => "+=",
+            Token::MinusAssign => "-=",
+            Token::TimesAssign => "*=",
+            Token::DivAssign => "/=",
+            Token::ModAssign => "%=",
+            Token::PowAssign => "**=",
+            Token::BitAndAssign => "&=",
+            Token::BitOrAssign => "|=",
+            Token::BitXorAssign => "^=",
+            Token::ShlAssign => "<<=",
+            Token::ShrAssign => ">>=",
+            Token::FnDecl => "fn",
+            Token::LBracket => "{",
+            Token::RBracket => "}",
+            Token::LSquare => "[",
+            Token::RSquare => "]",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::DotDot => "..",
+            Token::DotDotEq => "..=",
+            Token::If => "if",
+            Token::While => "while",
+            Token::For => "for",
+            Token::In => "in",
+            Token::EOF => return write!(f, "end of input"),
+            Token::Print => "print",
+            Token::Return => "return",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Match => "match",
+            Token::FatArrow => "=>",
+            Token::Throw => "throw",
+            Token::Try => "try",
+            Token::Catch => "catch",
+        };
+
+        write!(f, "'{}'", text)
     }
 }
+
+// a token and the span of source it was read from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+    // the text of any `///` lines just before the token, without the slashes,
+    // so that documentation can be attached to the declaration that follows
+    pub doc: Option<String>,
+}
@@ -1,9 +1,95 @@
-use expr::Expr;
+use expr::{Expr, Val};
 use parser::parser_error::ParserError;
+use span::Span;
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+// A function call an error escaped from: the name of the function, if it
+// has one, the span of the call, and how many tail calls led up to it. Each
+// tail call replaces the frame of the call it was made from, so those frames
+// are only counted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    pub function: Option<String>,
+    pub call: Option<Span>,
+    pub tail_calls: usize,
+}
+
+impl StackFrame {
+    fn function(&self) -> &str {
+        match self.function {
+            Some(ref name) => name,
+            None => "an anonymous function",
+        }
+    }
+
+    // the line standing in for the frames its tail calls replaced
+    fn elided(&self) -> Option<String> {
+        match self.tail_calls {
+            0 => None,
+            1 => Some("... 1 tail call".to_string()),
+            n => Some(format!("... {} tail calls", n)),
+        }
+    }
+}
+
+// the most frames a backtrace shows, after collapsing repeats
+const MAX_FRAMES: usize = 20;
+
+// The lines of a backtrace, with each frame shown by `show`. A run of
+// identical frames, like the calls of a recursive function, shows the first
+// and counts the rest, and frames past MAX_FRAMES are only counted.
+fn backtrace_lines<F: Fn(&StackFrame) -> String>(frames: &[StackFrame], show: F) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rest = frames;
+
+    for shown in 0.. {
+        let frame = match rest.first() {
+            Some(frame) => frame,
+            None => break,
+        };
+
+        if shown == MAX_FRAMES {
+            lines.push(match rest.len() {
+                1 => "... 1 more frame".to_string(),
+                n => format!("... {} more frames", n),
+            });
+            break;
+        }
+
+        lines.push(show(frame));
+
+        if let Some(elided) = frame.elided() {
+            lines.push(elided);
+        }
+
+        let run = rest.iter().take_while(|other| *other == frame).count();
+        match run {
+            1 => {}
+            2 => lines.push("... repeated 1 more time".to_string()),
+            n => lines.push(format!("... repeated {} more times", n - 1)),
+        }
+
+        rest = &rest[run..];
+    }
+
+    lines
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.call {
+            Some(span) => write!(f, "in {}, called at {}:{}", self.function(), span.line, span.col),
+            None => write!(f, "in {}", self.function()),
+        }
+    }
+}
+
+// PartialEq is implemented by hand below so that locations and backtraces
+// are ignored
+#[derive(Debug)]
 pub enum RuntimeError {
     SteppingOnValue(Expr),
     UnexpectedExpr(String, Expr),
@@ -12,27 +98,251 @@ pub enum RuntimeError {
     InvalidTypeConversion(String, Expr),
     InvalidMemoryState(String),
     TooManyIterations(usize),
+    // the evaluation ran for longer than this timeout
+    Timeout(Duration),
+    // the evaluation's cancel flag was set
+    Cancelled,
+    // more function calls were in progress at once than this limit
+    StackOverflow(usize),
     ParserError(ParserError),
     IntegerOverflow,
     IntegerUnderflow,
+    // the division or modulo expression, with its operands evaluated
+    DivisionByZero(Expr),
+    // an integer raised to a negative power, with its operands evaluated
+    NegativeExponent(Expr),
+    // a shift by a negative amount, or by at least the width of an int
+    InvalidShift(Expr),
+    // a left shift that moves set bits (or the sign) out of an int
+    ShiftOverflow(Expr),
+    IndexOutOfBounds(isize, usize),
+    FieldNotFound(String),
+    ArityMismatch(String, usize, usize),
+    // the value no arm of a match matched
+    NoMatchingArm(Expr),
+    // a value raised by `throw`
+    Thrown(Val),
+    // Control flow signals. These unwind through Interpreter::step to the
+    // enclosing function call or loop, which the parser guarantees exists.
+    Return(Val),
+    Break,
+    Continue,
+    // an error raised while stepping the expression parsed from this span
+    Located(Span, Box<RuntimeError>),
+    // an error that escaped from these function calls, innermost first
+    Traced(Vec<StackFrame>, Box<RuntimeError>),
+}
+
+impl RuntimeError {
+    // attaches a location, unless a more precise one is already attached
+    pub fn at(self, span: Span) -> RuntimeError {
+        match self {
+            e @ RuntimeError::Located(_, _) => e,
+            RuntimeError::Traced(frames, e) => RuntimeError::Traced(frames, Box::new(e.at(span))),
+            e @ RuntimeError::ParserError(_) => e,
+            e @ RuntimeError::Return(_) => e,
+            e @ RuntimeError::Break => e,
+            e @ RuntimeError::Continue => e,
+            e => RuntimeError::Located(span, Box::new(e)),
+        }
+    }
+
+    // attaches the calls the error escaped from, unless it already has them
+    pub fn with_backtrace(self, frames: Vec<StackFrame>) -> RuntimeError {
+        match self {
+            e @ RuntimeError::Traced(_, _) => e,
+            e @ RuntimeError::Return(_) => e,
+            e @ RuntimeError::Break => e,
+            e @ RuntimeError::Continue => e,
+            e if frames.is_empty() => e,
+            e => RuntimeError::Traced(frames, Box::new(e)),
+        }
+    }
+
+    // the calls the error escaped from, innermost first
+    pub fn backtrace(&self) -> &[StackFrame] {
+        match *self {
+            RuntimeError::Traced(ref frames, _) => frames,
+            _ => &[],
+        }
+    }
+
+    // the error with its location, but without its backtrace
+    pub fn without_backtrace(&self) -> &RuntimeError {
+        match *self {
+            RuntimeError::Traced(_, ref e) => e,
+            ref e => e,
+        }
+    }
+
+    // the name of the variant, which `catch` exposes as the error's kind
+    pub fn name(&self) -> &'static str {
+        match *self.kind() {
+            RuntimeError::SteppingOnValue(_) => "SteppingOnValue",
+            RuntimeError::UnexpectedExpr(_, _) => "UnexpectedExpr",
+            RuntimeError::VariableNotFound(_) => "VariableNotFound",
+            RuntimeError::InvalidConstAssignment(_, _) => "InvalidConstAssignment",
+            RuntimeError::InvalidTypeConversion(_, _) => "InvalidTypeConversion",
+            RuntimeError::InvalidMemoryState(_) => "InvalidMemoryState",
+            RuntimeError::TooManyIterations(_) => "TooManyIterations",
+            RuntimeError::Timeout(_) => "Timeout",
+            RuntimeError::Cancelled => "Cancelled",
+            RuntimeError::StackOverflow(_) => "StackOverflow",
+            RuntimeError::ParserError(_) => "ParserError",
+            RuntimeError::IntegerOverflow => "IntegerOverflow",
+            RuntimeError::IntegerUnderflow => "IntegerUnderflow",
+            RuntimeError::DivisionByZero(_) => "DivisionByZero",
+            RuntimeError::NegativeExponent(_) => "NegativeExponent",
+            RuntimeError::InvalidShift(_) => "InvalidShift",
+            RuntimeError::ShiftOverflow(_) => "ShiftOverflow",
+            RuntimeError::IndexOutOfBounds(_, _) => "IndexOutOfBounds",
+            RuntimeError::FieldNotFound(_) => "FieldNotFound",
+            RuntimeError::ArityMismatch(_, _, _) => "ArityMismatch",
+            RuntimeError::NoMatchingArm(_) => "NoMatchingArm",
+            RuntimeError::Thrown(_) => "Thrown",
+            RuntimeError::Return(_) => "Return",
+            RuntimeError::Break => "Break",
+            RuntimeError::Continue => "Continue",
+            RuntimeError::Located(_, _) => "Located",
+            RuntimeError::Traced(_, _) => "Traced",
+        }
+    }
+
+    // The value `catch` binds for this error. A thrown value is caught as is,
+    // and any other error as a record with its kind and message, and its
+    // line and col when it has a location. Control flow signals can't be
+    // caught, and neither can running out of calls, like the other limits.
+    pub fn to_val(&self) -> Option<Val> {
+        match *self.kind() {
+            RuntimeError::Return(_) | RuntimeError::Break | RuntimeError::Continue => return None,
+            RuntimeError::StackOverflow(_) => return None,
+            RuntimeError::Thrown(ref v) => return Some(v.clone()),
+            _ => {}
+        }
+
+        let mut fields = BTreeMap::new();
+        fields.insert("kind".to_string(), Val::Str(self.name().to_string()));
+        fields.insert("message".to_string(), Val::Str(self.kind().to_string()));
+
+        if let Some(span) = self.span() {
+            fields.insert("line".to_string(), Val::Int(span.line as isize));
+            fields.insert("col".to_string(), Val::Int(span.col as isize));
+        }
+
+        Some(Val::Record(fields))
+    }
+
+    // the error without its location and backtrace
+    pub fn kind(&self) -> &RuntimeError {
+        match *self {
+            RuntimeError::Located(_, ref e) => e.kind(),
+            RuntimeError::Traced(_, ref e) => e.kind(),
+            ref e => e,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            RuntimeError::Located(span, _) => Some(span),
+            RuntimeError::Traced(_, ref e) => e.span(),
+            RuntimeError::ParserError(ref err) => Some(err.span()),
+            _ => None,
+        }
+    }
+
+    // A `file:line:col: message` diagnostic followed by the offending source
+    // underlined, or just `file: message` when the error has no location.
+    // Then a line for each call the error escaped from:
+    //
+    //   script.bx:2:3: Variable "y" does not exist in memory
+    //     |
+    //   2 |   y
+    //     |   ^
+    //     in f, called at script.bx:4:1
+    //
+    // with `... N tail calls` after a call that N tail calls led up to, and
+    // `... repeated N more times` after a call that recursion repeated.
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let message = match *self.kind() {
+            RuntimeError::ParserError(ref err) => err.to_string(),
+            ref e => e.to_string(),
+        };
+
+        let mut out = match self.span() {
+            Some(span) => span.render(filename, source, &message),
+            None => format!("{}: {}", filename, message),
+        };
+
+        let lines = backtrace_lines(self.backtrace(), |frame| match frame.call {
+            Some(span) => format!("in {}, called at {}:{}:{}", frame.function(), filename, span.line, span.col),
+            None => frame.to_string(),
+        });
+
+        for line in lines {
+            out += &format!("\n  {}", line);
+        }
+
+        out
+    }
+}
+
+impl PartialEq for RuntimeError {
+    fn eq(&self, other: &RuntimeError) -> bool {
+        use self::RuntimeError::*;
+
+        match (self.kind(), other.kind()) {
+            (SteppingOnValue(e1), SteppingOnValue(e2)) => e1 == e2,
+            (UnexpectedExpr(s1, e1), UnexpectedExpr(s2, e2)) => s1 == s2 && e1 == e2,
+            (VariableNotFound(s1), VariableNotFound(s2)) => s1 == s2,
+            (InvalidConstAssignment(e1, s1), InvalidConstAssignment(e2, s2)) => {
+                e1 == e2 && s1 == s2
+            }
+            (InvalidTypeConversion(s1, e1), InvalidTypeConversion(s2, e2)) => {
+                s1 == s2 && e1 == e2
+            }
+            (InvalidMemoryState(s1), InvalidMemoryState(s2)) => s1 == s2,
+            (TooManyIterations(n1), TooManyIterations(n2)) => n1 == n2,
+            (Timeout(d1), Timeout(d2)) => d1 == d2,
+            (Cancelled, Cancelled) => true,
+            (StackOverflow(n1), StackOverflow(n2)) => n1 == n2,
+            (ParserError(e1), ParserError(e2)) => e1 == e2,
+            (IntegerOverflow, IntegerOverflow) => true,
+            (IntegerUnderflow, IntegerUnderflow) => true,
+            (DivisionByZero(e1), DivisionByZero(e2)) => e1 == e2,
+            (NegativeExponent(e1), NegativeExponent(e2)) => e1 == e2,
+            (InvalidShift(e1), InvalidShift(e2)) => e1 == e2,
+            (ShiftOverflow(e1), ShiftOverflow(e2)) => e1 == e2,
+            (IndexOutOfBounds(i1, l1), IndexOutOfBounds(i2, l2)) => i1 == i2 && l1 == l2,
+            (FieldNotFound(s1), FieldNotFound(s2)) => s1 == s2,
+            (ArityMismatch(s1, e1, a1), ArityMismatch(s2, e2, a2)) => {
+                s1 == s2 && e1 == e2 && a1 == a2
+            }
+            (NoMatchingArm(e1), NoMatchingArm(e2)) => e1 == e2,
+            (Thrown(v1), Thrown(v2)) => v1 == v2,
+            (Return(v1), Return(v2)) => v1 == v2,
+            (Break, Break) => true,
+            (Continue, Continue) => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RuntimeError::SteppingOnValue(ref e) => write!(f, "Stepping on a value {:?}", e),
+            RuntimeError::SteppingOnValue(ref e) => write!(f, "Stepping on a value {}", e),
             RuntimeError::UnexpectedExpr(ref s, ref e) => {
-                write!(f, "Unexpected expression. Expected {} and found {:?}", s, e)
+                write!(f, "Unexpected expression. Expected {} and found {}", s, e)
             }
             RuntimeError::VariableNotFound(ref e) => {
                 write!(f, "Variable {:?} does not exist in memory", e)
             }
             RuntimeError::InvalidConstAssignment(ref e, ref s) => {
-                write!(f, "Cannot assign {:?} to const {}", e, s)
+                write!(f, "Cannot assign {} to const {}", e, s)
             }
             RuntimeError::InvalidTypeConversion(ref s, ref e) => write!(
                 f,
-                "Invalid type conversion. Expected {} and found {:?}",
+                "Invalid type conversion. Expected {} and found {}",
                 s, e
             ),
             RuntimeError::InvalidMemoryState(ref s) => {
@@ -41,12 +351,47 @@ impl fmt::Display for RuntimeError {
             RuntimeError::TooManyIterations(ref n) => {
                 write!(f, "Too many iterations while evaluating expression: {}", n)
             }
+            RuntimeError::Timeout(ref d) => write!(f, "Evaluation timed out after {:?}", d),
+            RuntimeError::Cancelled => write!(f, "Evaluation was cancelled"),
+            RuntimeError::StackOverflow(ref n) => write!(f, "Stack overflow: more than {} nested calls", n),
             RuntimeError::ParserError(ref err) => write!(f, "Parser error: {}", err),
             RuntimeError::IntegerOverflow => write!(
                 f,
                 "Integer overflow: Integer underflow: Value grew too large"
             ),
             RuntimeError::IntegerUnderflow => write!(f, "Integer underflow: Value grew too small"),
+            RuntimeError::DivisionByZero(ref e) => write!(f, "Division by zero: {}", e),
+            RuntimeError::NegativeExponent(ref e) => {
+                write!(f, "Negative exponent for an integer: {}", e)
+            }
+            RuntimeError::InvalidShift(ref e) => {
+                write!(f, "Shift amount must be between 0 and {}: {}", isize::BITS - 1, e)
+            }
+            RuntimeError::ShiftOverflow(ref e) => write!(f, "Shift overflow: {}", e),
+            RuntimeError::IndexOutOfBounds(ref i, ref len) => {
+                write!(f, "Index {} is out of bounds for length {}", i, len)
+            }
+            RuntimeError::FieldNotFound(ref s) => write!(f, "Field {:?} does not exist", s),
+            RuntimeError::ArityMismatch(ref s, ref expected, ref actual) => write!(
+                f,
+                "Function {} expects {} argument(s) but was given {}",
+                s, expected, actual
+            ),
+            RuntimeError::NoMatchingArm(ref e) => write!(f, "No match arm matches {}", e),
+            RuntimeError::Thrown(ref v) => write!(f, "Uncaught exception: {}", v),
+            RuntimeError::Return(_) => write!(f, "return outside of a function"),
+            RuntimeError::Break => write!(f, "break outside of a loop"),
+            RuntimeError::Continue => write!(f, "continue outside of a loop"),
+            RuntimeError::Located(_, ref err) => write!(f, "{}", err),
+            RuntimeError::Traced(ref frames, ref err) => {
+                write!(f, "{}", err)?;
+
+                for line in backtrace_lines(frames, StackFrame::to_string) {
+                    write!(f, "\n  {}", line)?;
+                }
+
+                Ok(())
+            }
         }
     }
 }
@@ -61,9 +406,26 @@ impl error::Error for RuntimeError {
             RuntimeError::InvalidTypeConversion(_, _) => None,
             RuntimeError::InvalidMemoryState(_) => None,
             RuntimeError::TooManyIterations(_) => None,
+            RuntimeError::Timeout(_) => None,
+            RuntimeError::Cancelled => None,
+            RuntimeError::StackOverflow(_) => None,
             RuntimeError::ParserError(ref err) => Some(err),
             RuntimeError::IntegerOverflow => None,
             RuntimeError::IntegerUnderflow => None,
+            RuntimeError::DivisionByZero(_) => None,
+            RuntimeError::NegativeExponent(_) => None,
+            RuntimeError::InvalidShift(_) => None,
+            RuntimeError::ShiftOverflow(_) => None,
+            RuntimeError::IndexOutOfBounds(_, _) => None,
+            RuntimeError::FieldNotFound(_) => None,
+            RuntimeError::ArityMismatch(_, _, _) => None,
+            RuntimeError::NoMatchingArm(_) => None,
+            RuntimeError::Thrown(_) => None,
+            RuntimeError::Return(_) => None,
+            RuntimeError::Break => None,
+            RuntimeError::Continue => None,
+            RuntimeError::Located(_, ref err) => Some(err),
+            RuntimeError::Traced(_, ref err) => Some(err),
         }
     }
 }
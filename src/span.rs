@@ -0,0 +1,64 @@
+use std::fmt;
+
+// A region of source text: a byte range, plus the 1-based line and column
+// (counted in characters) where it starts.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize,
+  pub line: usize,
+  pub col: usize,
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize, line: usize, col: usize) -> Span {
+    Span { start, end, line, col }
+  }
+
+  // a span from the start of this one through the end of another
+  pub fn to(&self, other: &Span) -> Span {
+    Span {
+      end: other.end.max(self.start),
+      ..*self
+    }
+  }
+
+  // Formats a message as `file:line:col: message`, followed by the source
+  // line the span starts on with the span underlined:
+  //
+  //   script.bx:1:14: expected ')' found ';'
+  //     |
+  //   1 | let x = (1 + 2;
+  //     |              ^
+  pub fn render(&self, filename: &str, source: &str, message: &str) -> String {
+    let line_start = source[..self.start.min(source.len())]
+      .rfind('\n')
+      .map_or(0, |i| i + 1);
+
+    let line_text = source[line_start..].lines().next().unwrap_or("");
+    let line_end = line_start + line_text.len();
+
+    let underline_end = self.end.clamp(self.start, line_end);
+    let width = source
+      .get(self.start.min(line_end)..underline_end)
+      .map_or(0, |s| s.chars().count())
+      .max(1);
+
+    let gutter = " ".repeat(self.line.to_string().len());
+
+    format!(
+      "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+      filename, self.line, self.col, message,
+      gutter,
+      self.line, line_text,
+      gutter, " ".repeat(self.col.saturating_sub(1)), "^".repeat(width)
+    )
+  }
+}
+
+// compact, since spans end up in the Debug output of every parsed expression
+impl fmt::Debug for Span {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.col)
+  }
+}
@@ -1,89 +1,234 @@
-use expr::Expr;
-use std::collections::HashMap;
+use expr::{Expr, Val};
 use runtime_error::RuntimeError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
-#[derive(Clone, Debug)] 
-pub struct State {
-  pub mem: Vec<HashMap<String, Binding>>,
-}
+pub type Frame = Rc<RefCell<HashMap<String, Binding>>>;
 
-#[derive(Clone, Debug)] 
+#[derive(Clone, Debug)]
 pub enum Binding {
   Var(Box<Expr>),
   Const(Box<Expr>),
 }
 
-impl State {
-  pub fn new() -> State {
-    return State {
-      mem: vec!(HashMap::new()),
+// A chain of memory frames, innermost last. Frames are shared, so a function
+// value that captures an Env keeps seeing (and updating) the bindings of the
+// scope it was defined in, even after that scope has been left.
+//
+// Frames are reference counted and nothing collects cycles, so a function
+// bound in the frame it captured, like every `fn f() { .. }` declaration, is
+// stored without its Env and given the chain up to that frame back when it
+// is read. A function that captured a frame and is only reachable from it
+// some other way, like inside a list, still keeps it alive.
+#[derive(Clone)]
+pub struct Env {
+  frames: Vec<Frame>,
+}
+
+impl Env {
+  pub fn new() -> Env {
+    Env {
+      frames: vec!(Rc::new(RefCell::new(HashMap::new()))),
     }
   }
 
-  fn first_map_for(&mut self, x: String) -> Option<&mut HashMap<String, Binding>> {
-    self.mem.iter_mut().rev().find(|map| map.contains_key(&x))
+  // a copy of this chain with a fresh innermost frame
+  pub fn extend(&self) -> Env {
+    let mut frames = self.frames.clone();
+    frames.push(Rc::new(RefCell::new(HashMap::new())));
+    Env { frames }
   }
 
-  pub fn alloc(&mut self, x: String, v1: Expr) -> Result<(), RuntimeError> {
-    let binding = Binding::Var(Box::new(v1));
+  // the index of the innermost frame binding x
+  fn first_frame_for(&self, x: &str) -> Option<usize> {
+    self.frames.iter().rposition(|frame| frame.borrow().contains_key(x))
+  }
 
-    match self.mem.last_mut() {
-      Some(map) => {
-        map.insert(x, binding);
-        Ok(())
+  // the chain up to and including the frame at this index
+  fn chain_to(&self, index: usize) -> Env {
+    Env { frames: self.frames[..=index].to_vec() }
+  }
+
+  // whether this is the chain up to and including other's frame at index
+  fn is_chain_to(&self, other: &Env, index: usize) -> bool {
+    self.frames.len() == index + 1 &&
+      self.frames.iter().zip(other.frames.iter()).all(|(a, b)| Rc::ptr_eq(a, b))
+  }
+
+  // a value to store in the frame at this index, without the Env of a
+  // function that captured exactly the chain up to it
+  fn detach(&self, index: usize, v1: Expr) -> Expr {
+    match v1 {
+      Expr::Val(Val::Func(name, body, params, ref env)) if env.is_chain_to(self, index) => {
+        Expr::Val(Val::Func(name, body, params, Env { frames: Vec::new() }))
       },
-      None => Err(RuntimeError::InvalidMemoryState("no memory frame for var allocation".to_string())),
+      v1 => v1,
     }
   }
 
-  pub fn alloc_const(&mut self, x: String, v1: Expr) ->Result<(), RuntimeError> {
-    let binding = Binding::Const(Box::new(v1));
+  // a value read from the frame at this index, with a detached function's
+  // Env given back
+  fn attach(&self, index: usize, v1: Expr) -> Expr {
+    match v1 {
+      Expr::Val(Val::Func(name, body, params, ref env)) if env.frames.is_empty() => {
+        Expr::Val(Val::Func(name, body, params, self.chain_to(index)))
+      },
+      v1 => v1,
+    }
+  }
 
-    match self.mem.last_mut() {
-      Some(map) => {
-        map.insert(x, binding);
+  fn insert(&self, x: String, binding: fn(Box<Expr>) -> Binding, v1: Expr) -> Result<(), RuntimeError> {
+    match self.frames.last() {
+      Some(frame) => {
+        let v1 = self.detach(self.frames.len() - 1, v1);
+        frame.borrow_mut().insert(x, binding(Box::new(v1)));
         Ok(())
       },
-      None => return Err(RuntimeError::InvalidMemoryState("no memory frame for const allocation".to_string())),
+      None => Err(RuntimeError::InvalidMemoryState("no memory frame for allocation".to_string())),
     }
   }
 
-  pub fn assign(&mut self, x: String, v1: Expr) -> Result<(), RuntimeError> {
-    let mut map_option = self.first_map_for(x.clone());
+  pub fn alloc(&self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    self.insert(x, Binding::Var, v1)
+  }
 
-    let map = match map_option.as_mut() {
-      Some(m) => m,
-      None => return Err(RuntimeError::InvalidConstAssignment(v1, x)),
-    };
+  pub fn alloc_const(&self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    self.insert(x, Binding::Const, v1)
+  }
 
-    let binding = match map.get_mut(&x) {
-      Some(b) => b.clone(),
+  pub fn assign(&self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    let index = match self.first_frame_for(&x) {
+      Some(index) => index,
       None => return Err(RuntimeError::InvalidConstAssignment(v1, x)),
     };
 
-    match binding {
-      Binding::Var(_) => map.insert(x, Binding::Var(Box::new(v1))),
-      Binding::Const(_) => return Err(RuntimeError::InvalidConstAssignment(v1, x)),
+    let mut map = self.frames[index].borrow_mut();
+
+    match map.get(&x) {
+      Some(&Binding::Var(_)) => {},
+      _ => return Err(RuntimeError::InvalidConstAssignment(v1, x)),
     };
+
+    map.insert(x, Binding::Var(Box::new(self.detach(index, v1))));
     Ok(())
   }
 
-  pub fn get(&mut self, x: String) -> Option<Expr> {
-    match self.first_map_for(x.clone()) {
-      Some(map) => match map.get(&x).clone() {
-        Some(&Binding::Var(ref e)) => Some(*e.clone()),
-        Some(&Binding::Const(ref e)) => Some(*e.clone()),
-        _ => None,
-      },
-      None => None,
+  pub fn get(&self, x: &str) -> Option<Expr> {
+    self.first_frame_for(x).and_then(|index| {
+      let v1 = match self.frames[index].borrow().get(x) {
+        Some(Binding::Var(e)) => *e.clone(),
+        Some(Binding::Const(e)) => *e.clone(),
+        None => return None,
+      };
+
+      Some(self.attach(index, v1))
+    })
+  }
+}
+
+impl Default for Env {
+  fn default() -> Env {
+    Env::new()
+  }
+}
+
+// Two environments are the same if they share the same frames. Comparing the
+// contents instead would recurse forever through closures that capture the
+// frame they are bound in.
+impl PartialEq for Env {
+  fn eq(&self, other: &Env) -> bool {
+    self.frames.len() == other.frames.len() &&
+      self.frames.iter().zip(other.frames.iter()).all(|(a, b)| Rc::ptr_eq(a, b))
+  }
+}
+
+// Only the bound names are printed, for the same reason as above.
+impl fmt::Debug for Env {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let names: Vec<Vec<String>> = self.frames.iter().map(|frame| {
+      let mut keys: Vec<String> = frame.borrow().keys().cloned().collect();
+      keys.sort();
+      keys
+    }).collect();
+
+    write!(f, "Env{:?}", names)
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct State {
+  pub mem: Env,
+  callers: Vec<Env>,
+}
+
+impl Default for State {
+  fn default() -> State {
+    State::new()
+  }
+}
+
+impl State {
+  pub fn new() -> State {
+    State {
+      mem: Env::new(),
+      callers: Vec::new(),
     }
   }
 
-  pub fn begin_scope(&mut self) {
-    self.mem.push(HashMap::new());
+  pub fn alloc(&mut self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    self.mem.alloc(x, v1)
+  }
+
+  pub fn alloc_const(&mut self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    self.mem.alloc_const(x, v1)
   }
 
+  pub fn assign(&mut self, x: String, v1: Expr) -> Result<(), RuntimeError> {
+    self.mem.assign(x, v1)
+  }
+
+  pub fn get(&mut self, x: String) -> Option<Expr> {
+    self.mem.get(&x)
+  }
+
+  // the environment a function literal closes over
+  pub fn capture(&self) -> Env {
+    self.mem.clone()
+  }
+
+  // enter a function body: run in a new frame on top of the function's
+  // captured environment, remembering the caller's environment
+  pub fn begin_scope(&mut self, env: &Env) {
+    let caller = mem::replace(&mut self.mem, env.extend());
+    self.callers.push(caller);
+  }
+
+  // enter a block, like a loop iteration, whose bindings live in a new frame
+  // on top of the current environment
+  pub fn begin_block(&mut self) {
+    let env = self.mem.clone();
+    self.begin_scope(&env);
+  }
+
+  // leave a function body or a block
   pub fn end_scope(&mut self) {
-    self.mem.pop();
+    if let Some(caller) = self.callers.pop() {
+      self.mem = caller;
+    }
+  }
+
+  // how many function bodies and blocks are currently entered
+  pub fn depth(&self) -> usize {
+    self.callers.len()
+  }
+
+  // leave scopes until back at an earlier depth
+  pub fn unwind_to(&mut self, depth: usize) {
+    while self.callers.len() > depth {
+      self.end_scope();
+    }
   }
 }
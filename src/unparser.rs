@@ -0,0 +1,551 @@
+// Prints expressions back as boxx source that parses to the same expression,
+// with only the parentheses and braces the parser needs to read it that way.
+//
+// Each expression is printed in a context: the loosest level the parser
+// reads there, and the level of whatever comes after it. An expression is
+// printed bare when it is at least as tight as the context, and when what
+// comes after wouldn't be swallowed by its right hand side. `a - b` is bare
+// on the left of `- c`, but not on the right of `a -`, nor on the left of
+// `** c`. Anything else is wrapped in parentheses, or in braces when the
+// parser only reads it as a block.
+
+use builtins;
+use expr::{BinOp, Dec, Expr, Pattern, UnOp, Val};
+use parser::lexer;
+use parser::token::Token;
+use std::fmt;
+
+// What can come after an expression, from what nothing swallows to what
+// most things do. Binary operators are 10 above their binding power.
+const END: u8 = 0; // `}` or the end of the input
+const CLOSE: u8 = 1; // `)`, `]`, `,`, `:`, `=>`, or the `{` after a condition
+const SEQ: u8 = 2; // `;`
+const STATEMENT: u8 = 3; // `?`, `=` and `op=`
+const BINOP: u8 = 10;
+const PREFIX: u8 = BINOP + Token::PREFIX_POWER;
+// a factor that calls, indexes and field accesses can't follow, and one they can
+const FACTOR: u8 = 90;
+const POSTFIX: u8 = 100;
+// reaching past anything, so that nothing after it is swallowed
+const ALL: u8 = u8::MAX;
+
+// The loosest level the parser reads at this point, like a block or a
+// statement, and the level of what comes after.
+#[derive(Clone, Copy)]
+struct Context {
+  min: u8,
+  follow: u8,
+}
+
+const BLOCK: Context = Context { min: 0, follow: END };
+const PARENS: Context = Context { min: STATEMENT, follow: CLOSE };
+
+fn at(min: u8, follow: u8) -> Context {
+  Context { min, follow }
+}
+
+// an expression to print in a context
+struct In<'a>(&'a Expr, Context);
+
+impl fmt::Display for Expr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", In(self, BLOCK))
+  }
+}
+
+impl<'a> fmt::Display for In<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let In(e, cx) = *self;
+    let e = unlocated(e);
+    let (level, reach, needs_end) = shape(e);
+
+    if level >= cx.min && cx.follow < reach && (!needs_end || cx.follow == END) {
+      write_bare(f, e, cx)
+    } else if level >= STATEMENT && !needs_end {
+      write!(f, "({})", In(e, PARENS))
+    } else if cx.min > FACTOR {
+      write!(f, "({{ {} }})", In(e, BLOCK))
+    } else {
+      write!(f, "{{ {} }}", In(e, BLOCK))
+    }
+  }
+}
+
+fn unlocated(e: &Expr) -> &Expr {
+  match *e {
+    Expr::Located(_, ref e) => unlocated(e),
+    ref e => e,
+  }
+}
+
+fn is_undefined(e: &Expr) -> bool {
+  matches!(*unlocated(e), Expr::Val(Val::Undefined))
+}
+
+// A declaration followed directly by a C-style for loop is printed as the
+// loop's initializer.
+fn for_init(e: &Expr) -> Option<&Expr> {
+  match *e {
+    Expr::Decl(_, _, _, ref rest) => match *unlocated(rest) {
+      ref l @ Expr::For(..) => Some(l),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+// `fn f(..) { .. }; rest` is a const declaration of a lambda with its own name
+fn fn_decl(e: &Expr) -> Option<(&str, &Expr, &[Expr])> {
+  match *e {
+    Expr::Decl(Dec::DConst, ref x, ref e1, _) => match (unlocated(x), unlocated(e1)) {
+      (Expr::Var(x), Expr::Lambda(Some(name), body, params)) => match **name {
+        Expr::Var(ref name) if name == x => Some((x, body, params)),
+        _ => None,
+      },
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+// The level an expression is at, the level of what comes after it that its
+// right hand side would swallow, and whether it can only come last in a
+// block, like a loop without anything after it.
+fn shape(e: &Expr) -> (u8, u8, bool) {
+  match *e {
+    Expr::Val(ref v) => match *v {
+      Val::Int(n) if n < 0 => (FACTOR, PREFIX, false),
+      Val::Float(n) if n.is_sign_negative() && !n.is_nan() => (FACTOR, PREFIX, false),
+      Val::Int(_) | Val::Float(_) | Val::Bool(_) | Val::Undefined => (FACTOR, ALL, false),
+      Val::Range(_, _) => {
+        let (_, left, right) = Token::DotDot.infix_op().unwrap();
+        (BINOP + left, BINOP + right, false)
+      },
+      _ => (POSTFIX, ALL, false),
+    },
+    Expr::Var(_) => (POSTFIX, ALL, false),
+    Expr::Bop(BinOp::Seq, _, _) => (0, STATEMENT, false),
+    Expr::Bop(BinOp::Assign, _, _) | Expr::AssignOp(_, _, _) => (STATEMENT, STATEMENT, false),
+    Expr::Bop(ref op, _, _) => {
+      let (_, left, right) = infix(op).1.infix_op().unwrap();
+      (BINOP + left, BINOP + right, false)
+    },
+    Expr::Uop(_, _) => (FACTOR, PREFIX, false),
+    Expr::Lambda(_, _, _) => (POSTFIX, ALL, false),
+    Expr::Ternary(_, _, ref e3) if is_undefined(e3) => (FACTOR, ALL, false),
+    Expr::Ternary(_, _, _) => (STATEMENT, STATEMENT, false),
+    Expr::While(_, _, _, _, ref rest)
+    | Expr::ForIn(_, _, _, ref rest)
+    | Expr::Items(_, _, _, _, ref rest)
+    | Expr::For(_, _, _, _, ref rest) => {
+      match is_undefined(rest) {
+        true => (FACTOR, ALL, true),
+        false => (FACTOR, SEQ, false),
+      }
+    },
+    Expr::Decl(_, _, _, _) => match for_init(e) {
+      Some(l) => shape(l),
+      None => (FACTOR, SEQ, false),
+    },
+    Expr::FnCall(_, _) | Expr::Index(_, _) | Expr::Field(_, _) => (POSTFIX, ALL, false),
+    Expr::List(_) | Expr::Record(_) => (POSTFIX, ALL, false),
+    Expr::Print(_) => (FACTOR, BINOP, false),
+    Expr::Return(_) | Expr::Throw(_) => (FACTOR, STATEMENT, false),
+    Expr::Break | Expr::Continue => (FACTOR, ALL, false),
+    Expr::Try(_, _, _) | Expr::Match(_, _) => (FACTOR, ALL, false),
+    Expr::Scope(_) | Expr::Block(_) => (FACTOR, ALL, false),
+    Expr::Iteration(_, _, _) => (0, STATEMENT, false),
+    Expr::MatchArm(_, _, _) => (STATEMENT, STATEMENT, false),
+    Expr::Located(_, ref e) => shape(e),
+  }
+}
+
+// the source of a binary operator, and the token the parser reads it from
+fn infix(op: &BinOp) -> (&'static str, Token) {
+  match *op {
+    BinOp::Plus => ("+", Token::Plus),
+    BinOp::Minus => ("-", Token::Minus),
+    BinOp::Times => ("*", Token::Times),
+    BinOp::Div => ("/", Token::Div),
+    BinOp::Mod => ("%", Token::Mod),
+    BinOp::Pow => ("**", Token::Pow),
+    BinOp::Eq => ("==", Token::Eq),
+    BinOp::Ne => ("!=", Token::Ne),
+    BinOp::Leq => ("<=", Token::Leq),
+    BinOp::Geq => (">=", Token::Geq),
+    BinOp::Lt => ("<", Token::Lt),
+    BinOp::Gt => (">", Token::Gt),
+    BinOp::And => ("&&", Token::And),
+    BinOp::Or => ("||", Token::Or),
+    BinOp::BitAnd => ("&", Token::BitAnd),
+    BinOp::BitOr => ("|", Token::BitOr),
+    BinOp::BitXor => ("^", Token::BitXor),
+    BinOp::Shl => ("<<", Token::Shl),
+    BinOp::Shr => (">>", Token::Shr),
+    BinOp::Range => ("..", Token::DotDot),
+    BinOp::RangeInclusive => ("..=", Token::DotDotEq),
+    BinOp::Seq => (";", Token::Seq),
+    BinOp::Assign => ("=", Token::Assign),
+  }
+}
+
+fn write_bare(f: &mut fmt::Formatter, e: &Expr, cx: Context) -> fmt::Result {
+  match *e {
+    Expr::Val(ref v) => write_val(f, v),
+    Expr::Var(ref x) => write!(f, "{}", x),
+    Expr::Bop(BinOp::Seq, ref e1, ref e2) => {
+      write!(f, "{};", In(e1, at(0, SEQ)))?;
+
+      // a block can end in `;` when nothing but its closing brace follows
+      match is_undefined(e2) && cx.follow == END {
+        true => Ok(()),
+        false => write!(f, " {}", In(e2, at(STATEMENT, cx.follow))),
+      }
+    },
+    Expr::Bop(BinOp::Assign, ref e1, ref e2) => {
+      write!(f, "{} = {}", In(e1, at(BINOP, STATEMENT)), In(e2, at(STATEMENT, cx.follow)))
+    },
+    Expr::AssignOp(ref op, ref e1, ref e2) => write!(
+      f, "{} {}= {}",
+      In(e1, at(BINOP, STATEMENT)), infix(op).0, In(e2, at(STATEMENT, cx.follow))
+    ),
+    Expr::Bop(ref op, ref e1, ref e2) => {
+      let (symbol, token) = infix(op);
+      let (_, left, right) = token.infix_op().unwrap();
+      let (e1, e2) = (In(e1, at(cx.min, BINOP + left)), In(e2, at(BINOP + right, cx.follow)));
+
+      match *op {
+        BinOp::Range | BinOp::RangeInclusive => write!(f, "{}{}{}", e1, symbol, e2),
+        _ => write!(f, "{} {} {}", e1, symbol, e2),
+      }
+    },
+    Expr::Uop(ref op, ref e1) => {
+      let operand = In(e1, at(PREFIX, cx.follow)).to_string();
+
+      match *op {
+        // `- -x` rather than `--x`
+        UnOp::Neg if operand.starts_with('-') => write!(f, "- {}", operand),
+        UnOp::Neg => write!(f, "-{}", operand),
+        UnOp::Not => write!(f, "!{}", operand),
+        UnOp::BitNot => write!(f, "~{}", operand),
+      }
+    },
+    Expr::Lambda(ref name, ref body, ref params) => write_fn(f, name, body, params),
+    Expr::Ternary(ref e1, ref e2, ref e3) if is_undefined(e3) => {
+      write!(f, "if ({}) {{ {} }}", In(e1, PARENS), In(e2, BLOCK))
+    },
+    Expr::Ternary(ref e1, ref e2, ref e3) => write!(
+      f, "{} ? {} : {}",
+      In(e1, at(BINOP, STATEMENT)), In(e2, at(0, CLOSE)), In(e3, at(STATEMENT, cx.follow))
+    ),
+    // the original condition and body, rather than the ones being stepped
+    Expr::While(_, ref e1, _, ref body, ref rest) => {
+      write!(f, "while ({}) {{ {} }}", In(e1, PARENS), In(body, BLOCK))?;
+      write_rest(f, rest, cx)
+    },
+    Expr::ForIn(ref x, ref e1, ref body, ref rest) => {
+      write!(f, "for ({} in {}) {{ {} }}", In(x, BLOCK), In(e1, PARENS), In(body, BLOCK))?;
+      write_rest(f, rest, cx)
+    },
+    Expr::For(..) => write_for(f, None, e, cx),
+    Expr::Decl(ref dt, ref x, ref e1, ref rest) => {
+      if let Some(l) = for_init(e) {
+        return write_for(f, Some((dt, x, e1)), l, cx);
+      }
+
+      match fn_decl(e) {
+        Some((name, body, params)) => write!(f, "fn {}({}) {{ {} }}", name, Params(params), In(body, BLOCK))?,
+        None => write!(f, "{} {} = {}", decl(dt), In(x, BLOCK), In(e1, at(STATEMENT, SEQ)))?,
+      }
+
+      write!(f, "; {}", In(rest, at(0, cx.follow)))
+    },
+    Expr::FnCall(ref e1, ref args) => {
+      write!(f, "{}(", In(e1, at(POSTFIX, POSTFIX)))?;
+      write_items(f, args)?;
+      write!(f, ")")
+    },
+    Expr::Index(ref e1, ref e2) => write!(f, "{}[{}]", In(e1, at(POSTFIX, POSTFIX)), In(e2, PARENS)),
+    Expr::Field(ref e1, ref name) => write!(f, "{}.{}", In(e1, at(POSTFIX, POSTFIX)), name),
+    Expr::List(ref items) => {
+      write!(f, "[")?;
+      write_items(f, items)?;
+      write!(f, "]")
+    },
+    Expr::Record(ref fields) => {
+      let fields: Vec<(&str, &Expr)> = fields.iter().map(|(k, v)| (k.as_str(), v)).collect();
+      write_record(f, &fields, |f, _, e| write!(f, ": {}", In(e, at(BINOP, CLOSE))))
+    },
+    Expr::Print(ref e1) => write!(f, "print {}", In(e1, at(BINOP, cx.follow))),
+    // a bare `return` has to be followed by the end of its statement
+    Expr::Return(ref e1) if is_undefined(e1) && (cx.follow == END || cx.follow == SEQ) => write!(f, "return"),
+    Expr::Return(ref e1) => write!(f, "return {}", In(e1, at(STATEMENT, cx.follow))),
+    Expr::Break => write!(f, "break"),
+    Expr::Continue => write!(f, "continue"),
+    Expr::Throw(ref e1) => write!(f, "throw {}", In(e1, at(STATEMENT, cx.follow))),
+    Expr::Try(ref body, ref x, ref handler) => write!(
+      f, "try {{ {} }} catch ({}) {{ {} }}",
+      In(body, BLOCK), In(x, BLOCK), In(handler, BLOCK)
+    ),
+    Expr::Match(ref e1, ref arms) => {
+      write!(f, "match {} {{", In(e1, at(BINOP, CLOSE)))?;
+
+      for (i, (pattern, guard, body)) in arms.iter().enumerate() {
+        write!(f, "{} {}", if i > 0 { "," } else { "" }, pattern)?;
+
+        if let Some(ref guard) = *guard {
+          write!(f, " if {}", In(guard, PARENS))?;
+        }
+
+        write!(f, " => {}", In(body, PARENS))?;
+      }
+
+      match arms.is_empty() {
+        true => write!(f, "}}"),
+        false => write!(f, " }}"),
+      }
+    },
+    // the nodes only made while running are printed as what they behave like
+    Expr::Scope(ref e1) | Expr::Block(ref e1) => write!(f, "{{ {} }}", In(e1, BLOCK)),
+    Expr::Items(ref x, ref v, at, ref body, ref rest) => {
+      let items = Expr::Val(builtins::items_from(v, at));
+      write_bare(f, &Expr::ForIn(x.clone(), Box::new(items), body.clone(), rest.clone()), cx)
+    },
+    Expr::Iteration(ref body, ref update, ref l) => {
+      let body = Expr::Bop(BinOp::Seq, body.clone(), update.clone());
+      write_bare(f, &Expr::Bop(BinOp::Seq, Box::new(body), l.clone()), cx)
+    },
+    Expr::MatchArm(ref guard, ref body, ref fallback) => {
+      write_bare(f, &Expr::Ternary(guard.clone(), body.clone(), fallback.clone()), cx)
+    },
+    Expr::Located(_, ref e1) => write_bare(f, e1, cx),
+  }
+}
+
+fn decl(dt: &Dec) -> &'static str {
+  match *dt {
+    Dec::DVar => "var",
+    Dec::DConst => "let",
+  }
+}
+
+// what follows a loop, which is left out when nothing does
+fn write_rest(f: &mut fmt::Formatter, rest: &Expr, cx: Context) -> fmt::Result {
+  match is_undefined(rest) {
+    true => Ok(()),
+    false => write!(f, "; {}", In(rest, at(0, cx.follow))),
+  }
+}
+
+// `for (init; cond; update) body`, leaving out the parts the parser fills in
+fn write_for(f: &mut fmt::Formatter, init: Option<(&Dec, &Expr, &Expr)>, l: &Expr, cx: Context) -> fmt::Result {
+  let (cond, update, body, rest) = match *l {
+    Expr::For(_, ref cond, ref update, ref body, ref rest) => (cond, update, body, rest),
+    _ => unreachable!("a for loop"),
+  };
+
+  write!(f, "for (")?;
+
+  if let Some((dt, x, e1)) = init {
+    write!(f, "{} {} = {}", decl(dt), In(x, BLOCK), In(e1, at(STATEMENT, SEQ)))?;
+  }
+
+  write!(f, ";")?;
+
+  if !matches!(*unlocated(cond), Expr::Val(Val::Bool(true))) {
+    write!(f, " {}", In(cond, at(STATEMENT, SEQ)))?;
+  }
+
+  write!(f, ";")?;
+
+  if !is_undefined(update) {
+    write!(f, " {}", In(update, PARENS))?;
+  }
+
+  write!(f, ") {{ {} }}", In(body, BLOCK))?;
+  write_rest(f, rest, cx)
+}
+
+// comma separated, as call arguments and list items
+fn write_items(f: &mut fmt::Formatter, items: &[Expr]) -> fmt::Result {
+  for (i, e) in items.iter().enumerate() {
+    if i > 0 {
+      write!(f, ", ")?;
+    }
+    write!(f, "{}", In(e, at(BINOP, CLOSE)))?;
+  }
+
+  Ok(())
+}
+
+struct Params<'a>(&'a [Expr]);
+
+impl<'a> fmt::Display for Params<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, e) in self.0.iter().enumerate() {
+      if i > 0 {
+        write!(f, ", ")?;
+      }
+      write!(f, "{}", In(e, BLOCK))?;
+    }
+
+    Ok(())
+  }
+}
+
+// `fn name(params) { body }`, which is how functions are printed too
+pub fn write_fn(f: &mut fmt::Formatter, name: &Option<Box<Expr>>, body: &Expr, params: &[Expr]) -> fmt::Result {
+  write!(f, "fn")?;
+
+  if let Some(ref name) = *name {
+    write!(f, " {}", In(name, BLOCK))?;
+  }
+
+  write!(f, "({}) {{ {} }}", Params(params), In(body, BLOCK))
+}
+
+// Values are printed as the literals they could have been parsed from.
+// Functions can't always be, since a named one is a declaration, and they
+// don't show what they close over.
+fn write_val(f: &mut fmt::Formatter, v: &Val) -> fmt::Result {
+  match *v {
+    Val::Int(n) => write_int(f, n),
+    Val::Float(n) => write_float(f, n),
+    Val::Bool(b) => write!(f, "{}", b),
+    Val::Str(ref s) => write_str(f, s),
+    Val::List(ref xs) => {
+      write!(f, "[")?;
+      for (i, x) in xs.iter().enumerate() {
+        if i > 0 {
+          write!(f, ", ")?;
+        }
+        write_val(f, x)?;
+      }
+      write!(f, "]")
+    },
+    Val::Record(ref fields) => {
+      let fields: Vec<(&str, &Val)> = fields.iter().map(|(k, v)| (k.as_str(), v)).collect();
+      write_record(f, &fields, |f, _, v| {
+        write!(f, ": ")?;
+        write_val(f, v)
+      })
+    },
+    Val::Range(start, end) => {
+      write_int(f, start)?;
+      write!(f, "..")?;
+      write_int(f, end)
+    },
+    Val::Undefined => write!(f, "undefined"),
+    Val::Func(ref name, ref body, ref params, _) => write_fn(f, name, body, params),
+    Val::Builtin(ref b) => write!(f, "{}", b.name()),
+  }
+}
+
+// A literal is lexed without its sign, so the smallest int is written as
+// the arithmetic that makes it.
+fn write_int(f: &mut fmt::Formatter, n: isize) -> fmt::Result {
+  match n {
+    isize::MIN => write!(f, "({} - 1)", isize::MIN + 1),
+    n => write!(f, "{}", n),
+  }
+}
+
+// Debug keeps the fractional part (`1.0`). Infinities are written as
+// literals too big for a float, which read as infinity, and NaN as the
+// division that makes it.
+fn write_float(f: &mut fmt::Formatter, n: f64) -> fmt::Result {
+  match n {
+    n if n.is_nan() => write!(f, "(0.0 / 0.0)"),
+    n if n.is_infinite() && n > 0.0 => write!(f, "1e999"),
+    n if n.is_infinite() => write!(f, "-1e999"),
+    n => write!(f, "{:?}", n),
+  }
+}
+
+// `{ key: value }`, quoting the keys that aren't names. The value is written
+// with its colon, so that patterns can leave both out.
+fn write_record<T, W>(f: &mut fmt::Formatter, fields: &[(&str, T)], write_value: W) -> fmt::Result
+  where W: Fn(&mut fmt::Formatter, &str, &T) -> fmt::Result {
+  if fields.is_empty() {
+    return write!(f, "{{}}");
+  }
+
+  write!(f, "{{")?;
+  for (i, &(k, ref v)) in fields.iter().enumerate() {
+    if i > 0 {
+      write!(f, ",")?;
+    }
+    write!(f, " ")?;
+    write_key(f, k)?;
+    write_value(f, k, v)?;
+  }
+  write!(f, " }}")
+}
+
+fn write_key(f: &mut fmt::Formatter, k: &str) -> fmt::Result {
+  match lexer::is_name(k) {
+    true => write!(f, "{}", k),
+    false => write_str(f, k),
+  }
+}
+
+// a string literal, with the escapes the lexer reads
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+  write!(f, "\"")?;
+
+  for c in s.chars() {
+    match c {
+      '\n' => write!(f, "\\n")?,
+      '\t' => write!(f, "\\t")?,
+      '\r' => write!(f, "\\r")?,
+      '"' => write!(f, "\\\"")?,
+      '\\' => write!(f, "\\\\")?,
+      c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+      c => write!(f, "{}", c)?,
+    }
+  }
+
+  write!(f, "\"")
+}
+
+impl fmt::Display for Pattern {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Pattern::Wildcard => write!(f, "_"),
+      Pattern::Bind(ref x) => write!(f, "{}", x),
+      Pattern::Lit(ref v) => write_val(f, v),
+      Pattern::List(ref items, ref rest) => {
+        write!(f, "[")?;
+        for (i, p) in items.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{}", p)?;
+        }
+
+        match *rest {
+          Some(ref rest) => {
+            if !items.is_empty() {
+              write!(f, ", ")?;
+            }
+
+            match **rest {
+              Pattern::Wildcard => write!(f, "..]"),
+              ref rest => write!(f, "..{}]", rest),
+            }
+          },
+          None => write!(f, "]"),
+        }
+      },
+      Pattern::Record(ref fields) => {
+        let fields: Vec<(&str, &Pattern)> = fields.iter().map(|(k, p)| (k.as_str(), p)).collect();
+
+        // `{ x }` is short for `{ x: x }`
+        write_record(f, &fields, |f, k, p| match **p {
+          Pattern::Bind(ref x) if x == k && lexer::is_name(k) => Ok(()),
+          ref p => write!(f, ": {}", p),
+        })
+      },
+    }
+  }
+}
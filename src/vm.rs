@@ -0,0 +1,989 @@
+use builtins;
+use expr::{BinOp, Dec, Expr, Pattern, UnOp, Val};
+use interpreter::{
+    apply_bop, apply_uop, assign_to, function_name, lookup, match_pattern, place_base, read_place, Meter, Result,
+};
+use runtime_error::{RuntimeError, StackFrame};
+use span::Span;
+use state::State;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+// An instruction for the stack machine. Jump targets are indexes into the
+// code of the chunk the instruction is in.
+#[derive(Clone, Debug)]
+pub enum Op {
+    Const(Val),
+    // push a variable, or the builtin of that name
+    Load(String),
+    // pop a value and bind it in the current frame
+    Decl(Dec, String),
+    // assign the value on top of the stack, leaving it there
+    Store(String),
+    // Push the value at an assignment target like `xs[i].name`, whose n
+    // indexes are on the stack. They stay there for the StorePlace to follow.
+    LoadPlace(Expr, usize),
+    // pop a value and the n indexes of an assignment target, assign the
+    // value to the target and push it again
+    StorePlace(Expr, usize),
+    // fail on an assignment target without a variable at its base
+    NotAssignable(Expr),
+    Pop,
+    // drop the value under the top of the stack
+    Nip,
+    Unary(UnOp),
+    Binary(BinOp),
+    // The left side of && or ||. Jump, keeping it, when it decides the
+    // result. Otherwise it is left for Binary to combine with the right side.
+    ShortCircuit(BinOp, usize),
+    Jump(usize),
+    // pop the condition of a ternary, jumping when it is false
+    Branch(usize),
+    // pop the condition of a loop, jumping when it is false
+    LoopTest(usize),
+    // Take the next item of what a for loop iterates over, from the offset
+    // on top of the stack with the collection under it. When there are none
+    // left, pop both and jump. Otherwise replace the offset with the one
+    // after the item, and bind the item in a new frame for the iteration.
+    ForNext(String, usize),
+    BeginBlock,
+    EndScope,
+    // Jump out of a loop body for break or continue, dropping the values,
+    // try handlers and frames it left behind.
+    Unwind(usize, usize, usize),
+    List(usize),
+    Record(Vec<String>),
+    Index,
+    Field(String),
+    Closure(Option<Box<Expr>>, Rc<Expr>, Vec<Expr>),
+    // fail unless the value on top of the stack can be called, before its
+    // arguments are evaluated
+    Callable,
+    Call(usize),
+    // a call in tail position, which replaces the frame of the function
+    // making it
+    TailCall(usize),
+    Return,
+    Print,
+    Throw,
+    // Match the value on top of the stack. If it matches, bind the names in
+    // the pattern in a new frame for the arm, otherwise jump.
+    Match(Pattern, usize),
+    // pop a guard, leaving the arm's frame and jumping when it is false
+    Guard(usize),
+    // pop a value that no arm matched
+    NoMatch,
+    // catch errors with the handler at the target until the matching
+    // EndTry, binding them to the name
+    Try(String, usize),
+    EndTry,
+    // pop a value that was found where an expression was expected
+    SteppingOnValue,
+}
+
+// compiled code, and the span of the innermost located expression each
+// instruction was compiled from, for errors to point at
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Option<Span>>,
+}
+
+// a loop being compiled, for the break and continue statements in its body
+struct Loop {
+    // the stack height to leave the loop at, and to go round again at
+    break_height: usize,
+    continue_height: usize,
+    // the try handlers and frames entered outside the loop
+    handlers: usize,
+    scopes: usize,
+    // jumps to patch once the targets are known
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Compiles one program or function body. It keeps track of the stack height
+// and of the try handlers and frames entered, so that break and continue
+// know what to leave.
+struct Compiler {
+    chunk: Chunk,
+    spans: Vec<Span>,
+    height: usize,
+    handlers: usize,
+    scopes: usize,
+    loops: Vec<Loop>,
+    // Whether the function body returns the value of the expression being
+    // compiled, with nothing left to do. These are the same positions the
+    // small step interpreter makes tail calls from.
+    tail: bool,
+}
+
+pub fn compile(e: &Expr) -> Result<Chunk> {
+    let mut compiler = Compiler::new();
+    compiler.expr(e)?;
+    Ok(compiler.chunk)
+}
+
+// a function body returns the value it finishes with
+fn compile_body(e: &Expr) -> Result<Chunk> {
+    let mut compiler = Compiler::new();
+    compiler.tail = true;
+    compiler.expr(e)?;
+    compiler.emit(Op::Return);
+    Ok(compiler.chunk)
+}
+
+impl Compiler {
+    fn new() -> Compiler {
+        Compiler {
+            chunk: Chunk::default(),
+            spans: Vec::new(),
+            height: 0,
+            handlers: 0,
+            scopes: 0,
+            loops: Vec::new(),
+            tail: false,
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let (pops, pushes) = match op {
+            Op::Const(_) | Op::Load(_) | Op::LoadPlace(..) | Op::Closure(..) => (0, 1),
+            Op::Decl(..) | Op::Pop | Op::Nip | Op::Binary(_) | Op::Branch(_) | Op::LoopTest(_) => (1, 0),
+            Op::Index | Op::Return | Op::Throw | Op::Guard(_) | Op::NoMatch | Op::SteppingOnValue => (1, 0),
+            Op::StorePlace(_, n) => (n, 0),
+            Op::Unwind(n, _, _) => (n, 0),
+            Op::List(n) => (n, 1),
+            Op::Record(ref keys) => (keys.len(), 1),
+            Op::Call(n) | Op::TailCall(n) => (n + 1, 1),
+            _ => (0, 0),
+        };
+
+        self.height = self.height + pushes - pops;
+        self.chunk.code.push(op);
+        self.chunk.spans.push(self.spans.last().cloned());
+        self.chunk.code.len() - 1
+    }
+
+    // point the jump at an instruction to the next one emitted
+    fn patch(&mut self, at: usize) {
+        let here = self.chunk.code.len();
+        self.patch_to(at, here);
+    }
+
+    fn patch_to(&mut self, at: usize, to: usize) {
+        match self.chunk.code[at] {
+            Op::ShortCircuit(_, ref mut target)
+            | Op::Jump(ref mut target)
+            | Op::Branch(ref mut target)
+            | Op::LoopTest(ref mut target)
+            | Op::ForNext(_, ref mut target)
+            | Op::Match(_, ref mut target)
+            | Op::Guard(ref mut target)
+            | Op::Try(_, ref mut target) => *target = to,
+            ref op => panic!("cannot patch {:?}", op),
+        }
+    }
+
+    // Compiles code that leaves the value of the expression on the stack.
+    // Expressions that jump away instead still count as one value, so the
+    // height stays right for the code after them.
+    fn expr(&mut self, e: &Expr) -> Result<()> {
+        let height = self.height;
+        let tail = mem::replace(&mut self.tail, false);
+
+        match *e {
+            Expr::Val(ref v) => {
+                self.emit(Op::Const(v.clone()));
+            }
+            Expr::Var(ref x) => {
+                self.emit(Op::Load(x.clone()));
+            }
+            Expr::Located(span, ref e1) => {
+                self.spans.push(span);
+                self.tail = tail;
+                self.expr(e1)?;
+                self.spans.pop();
+            }
+            Expr::Uop(ref op, ref e1) => {
+                self.expr(e1)?;
+                self.emit(Op::Unary(op.clone()));
+            }
+            Expr::Bop(ref op, ref e1, ref e2) => self.bop(op, e1, e2, tail)?,
+            Expr::AssignOp(ref op, ref place, ref e2) => match place_base(place) {
+                Expr::Var(_) => {
+                    let n = self.indexes(place)?;
+                    self.emit(Op::LoadPlace((**place).clone(), n));
+                    self.expr(e2)?;
+                    self.emit(Op::Binary(op.clone()));
+                    self.emit(Op::StorePlace((**place).clone(), n));
+                }
+                base => {
+                    self.emit(Op::NotAssignable(base.clone()));
+                }
+            },
+            Expr::Decl(ref dt, ref addr, ref e1, ref e2) => {
+                self.expr(e1)?;
+
+                match **addr {
+                    Expr::Var(ref x) => {
+                        self.emit(Op::Decl(dt.clone(), x.clone()));
+                    }
+                    _ => {
+                        self.emit(Op::SteppingOnValue);
+                    }
+                }
+
+                self.tail = tail;
+                self.expr(e2)?;
+            }
+            Expr::Ternary(ref e1, ref e2, ref e3) => {
+                self.expr(e1)?;
+                let branch = self.emit(Op::Branch(0));
+                self.tail = tail;
+                self.expr(e2)?;
+                let jump = self.emit(Op::Jump(0));
+                self.patch(branch);
+                self.height = height;
+                self.tail = tail;
+                self.expr(e3)?;
+                self.patch(jump);
+            }
+            Expr::While(_, ref cond, _, ref body, ref rest) => {
+                let start = self.chunk.code.len();
+                self.expr(cond)?;
+                let test = self.emit(Op::LoopTest(0));
+
+                self.begin_loop(height, height);
+                self.expr(body)?;
+                self.emit(Op::Pop);
+                self.emit(Op::Jump(start));
+                self.end_loop(start);
+
+                self.patch(test);
+                self.tail = tail;
+                self.expr(rest)?;
+            }
+            Expr::ForIn(ref x, ref iter, ref body, ref rest) => {
+                let x = match **x {
+                    Expr::Var(ref x) => x.clone(),
+                    ref e => return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone())),
+                };
+
+                self.expr(iter)?;
+                self.emit(Op::Const(Val::Int(0)));
+                let start = self.chunk.code.len();
+                let next = self.emit(Op::ForNext(x, 0));
+
+                self.begin_loop(height, height + 2);
+                self.scopes += 1;
+                self.expr(body)?;
+                self.emit(Op::Pop);
+                self.emit(Op::EndScope);
+                self.scopes -= 1;
+                self.emit(Op::Jump(start));
+                self.end_loop(start);
+
+                self.patch(next);
+                self.height = height;
+                self.tail = tail;
+                self.expr(rest)?;
+            }
+            Expr::For(_, ref cond, ref update, ref body, ref rest) => {
+                let start = self.chunk.code.len();
+                self.expr(cond)?;
+                let test = self.emit(Op::LoopTest(0));
+
+                self.begin_loop(height, height);
+                self.emit(Op::BeginBlock);
+                self.scopes += 1;
+                self.expr(body)?;
+                self.emit(Op::Pop);
+                self.emit(Op::EndScope);
+                self.scopes -= 1;
+
+                let update_start = self.chunk.code.len();
+                self.expr(update)?;
+                self.emit(Op::Pop);
+                self.emit(Op::Jump(start));
+                self.end_loop(update_start);
+
+                self.patch(test);
+                self.tail = tail;
+                self.expr(rest)?;
+            }
+            Expr::Match(ref e1, ref arms) => {
+                self.expr(e1)?;
+                let mut ends = Vec::new();
+
+                for (pattern, guard, body) in arms {
+                    let test = self.emit(Op::Match(pattern.clone(), 0));
+                    self.scopes += 1;
+
+                    let guard = match *guard {
+                        Some(ref guard) => {
+                            self.expr(guard)?;
+                            Some(self.emit(Op::Guard(0)))
+                        }
+                        None => None,
+                    };
+
+                    self.expr(body)?;
+                    self.emit(Op::EndScope);
+                    self.scopes -= 1;
+                    self.emit(Op::Nip);
+                    ends.push(self.emit(Op::Jump(0)));
+
+                    self.patch(test);
+                    if let Some(guard) = guard {
+                        self.patch(guard);
+                    }
+                    self.height = height + 1;
+                }
+
+                self.emit(Op::NoMatch);
+
+                for end in ends {
+                    self.patch(end);
+                }
+            }
+            Expr::Throw(ref e1) => {
+                self.expr(e1)?;
+                self.emit(Op::Throw);
+            }
+            Expr::Try(ref body, ref x, ref handler) => {
+                let x = match **x {
+                    Expr::Var(ref x) => x.clone(),
+                    ref e => return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone())),
+                };
+
+                let catch = self.emit(Op::Try(x, 0));
+                self.handlers += 1;
+                self.expr(body)?;
+                self.emit(Op::EndTry);
+                self.handlers -= 1;
+                let end = self.emit(Op::Jump(0));
+
+                // the handler runs with the stack as it was at the try, in a
+                // frame holding the error
+                self.patch(catch);
+                self.height = height;
+                self.scopes += 1;
+                self.expr(handler)?;
+                self.emit(Op::EndScope);
+                self.scopes -= 1;
+                self.patch(end);
+            }
+            Expr::Return(ref e1) => {
+                self.tail = tail;
+                self.expr(e1)?;
+                self.emit(Op::Return);
+            }
+            Expr::Break | Expr::Continue => {
+                let (to, n) = match self.loops.last() {
+                    Some(l) => {
+                        let to = match *e {
+                            Expr::Break => l.break_height,
+                            _ => l.continue_height,
+                        };
+                        (to, (self.handlers - l.handlers, self.scopes - l.scopes))
+                    }
+                    None => match *e {
+                        Expr::Break => return Err(RuntimeError::Break),
+                        _ => return Err(RuntimeError::Continue),
+                    },
+                };
+
+                self.emit(Op::Unwind(self.height - to, n.0, n.1));
+                let jump = self.emit(Op::Jump(0));
+
+                if let Some(l) = self.loops.last_mut() {
+                    match *e {
+                        Expr::Break => l.breaks.push(jump),
+                        _ => l.continues.push(jump),
+                    }
+                }
+            }
+            Expr::Print(ref e1) => {
+                self.expr(e1)?;
+                self.emit(Op::Print);
+            }
+            Expr::Lambda(ref name, ref body, ref params) => {
+                self.emit(Op::Closure(name.clone(), Rc::new((**body).clone()), params.clone()));
+            }
+            Expr::FnCall(ref f, ref args) => {
+                self.expr(f)?;
+                self.emit(Op::Callable);
+
+                for arg in args {
+                    self.expr(arg)?;
+                }
+
+                match tail {
+                    true => self.emit(Op::TailCall(args.len())),
+                    false => self.emit(Op::Call(args.len())),
+                };
+            }
+            Expr::List(ref es) => {
+                for e in es {
+                    self.expr(e)?;
+                }
+
+                self.emit(Op::List(es.len()));
+            }
+            Expr::Record(ref fields) => {
+                for (_, e) in fields {
+                    self.expr(e)?;
+                }
+
+                self.emit(Op::Record(fields.iter().map(|f| f.0.clone()).collect()));
+            }
+            Expr::Index(ref e1, ref e2) => {
+                self.expr(e1)?;
+                self.expr(e2)?;
+                self.emit(Op::Index);
+            }
+            Expr::Field(ref e1, ref name) => {
+                self.expr(e1)?;
+                self.emit(Op::Field(name.clone()));
+            }
+            // only made by the small step interpreter while it runs
+            Expr::Items(..) | Expr::Iteration(..) | Expr::Scope(_) | Expr::Block(_) | Expr::MatchArm(..) => {
+                return Err(RuntimeError::UnexpectedExpr("parsed expression".to_string(), e.clone()))
+            }
+        }
+
+        self.height = height + 1;
+        Ok(())
+    }
+
+    fn bop(&mut self, op: &BinOp, e1: &Expr, e2: &Expr, tail: bool) -> Result<()> {
+        match (op, e1) {
+            (&BinOp::Seq, _) => {
+                self.expr(e1)?;
+                self.emit(Op::Pop);
+                self.tail = tail;
+                self.expr(e2)?;
+            }
+            (&BinOp::And, _) | (&BinOp::Or, _) => {
+                self.expr(e1)?;
+                let short = self.emit(Op::ShortCircuit(op.clone(), 0));
+                self.expr(e2)?;
+                self.emit(Op::Binary(op.clone()));
+                self.patch(short);
+            }
+            (&BinOp::Assign, Expr::Var(x)) => {
+                self.expr(e2)?;
+                self.emit(Op::Store(x.clone()));
+            }
+            // the indexes of an assignment target are evaluated before the value
+            (&BinOp::Assign, &Expr::Index(..)) | (&BinOp::Assign, &Expr::Field(..)) => match place_base(e1) {
+                Expr::Var(_) => {
+                    let n = self.indexes(e1)?;
+                    self.expr(e2)?;
+                    self.emit(Op::StorePlace(e1.clone(), n));
+                }
+                base => {
+                    self.emit(Op::NotAssignable(base.clone()));
+                }
+            },
+            _ => {
+                self.expr(e1)?;
+                self.expr(e2)?;
+                self.emit(Op::Binary(op.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // compile the indexes of an assignment target from its base outwards,
+    // returning how many there are
+    fn indexes(&mut self, place: &Expr) -> Result<usize> {
+        match *place {
+            Expr::Index(ref p, ref i) => {
+                let n = self.indexes(p)?;
+                self.expr(i)?;
+                Ok(n + 1)
+            }
+            Expr::Field(ref p, _) => self.indexes(p),
+            _ => Ok(0),
+        }
+    }
+
+    fn begin_loop(&mut self, break_height: usize, continue_height: usize) {
+        self.loops.push(Loop {
+            break_height,
+            continue_height,
+            handlers: self.handlers,
+            scopes: self.scopes,
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+    }
+
+    // breaks go to the next instruction emitted, after the loop
+    fn end_loop(&mut self, continue_to: usize) {
+        if let Some(l) = self.loops.pop() {
+            for at in l.continues {
+                self.patch_to(at, continue_to);
+            }
+
+            let end = self.chunk.code.len();
+            for at in l.breaks {
+                self.patch_to(at, end);
+            }
+        }
+    }
+}
+
+// an assignment target with its indexes replaced by their values
+fn fill_place(place: &Expr, indexes: &mut dyn Iterator<Item = Val>) -> Expr {
+    match *place {
+        Expr::Index(ref p, _) => {
+            let p = fill_place(p, indexes);
+            let i = indexes.next().unwrap_or(Val::Undefined);
+            Expr::Index(Box::new(p), Box::new(Expr::Val(i)))
+        }
+        Expr::Field(ref p, ref name) => Expr::Field(Box::new(fill_place(p, indexes)), name.clone()),
+        ref e => e.clone(),
+    }
+}
+
+// a function call in progress
+struct Frame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    // the function and where it was called, for backtraces
+    call: Option<StackFrame>,
+    // the stack height, try handlers and state depth to return to
+    stack: usize,
+    handlers: usize,
+    depth: usize,
+}
+
+// a try whose body is running
+struct Handler {
+    // the frame the try is in, and where to go on in it on an error
+    frame: usize,
+    pc: usize,
+    stack: usize,
+    depth: usize,
+    name: String,
+}
+
+// The stack machine. Variables live in the same State as for the small step
+// interpreter, so values, closures and errors behave the same in both.
+#[derive(Default)]
+pub struct Vm {
+    // Compiled function bodies, by the address of the body, for the eval in
+    // progress. The body is kept alongside so the address can't be reused.
+    bodies: HashMap<*const Expr, (Rc<Expr>, Rc<Chunk>)>,
+    frames: Vec<Frame>,
+    stack: Vec<Val>,
+    handlers: Vec<Handler>,
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::default()
+    }
+
+    pub fn eval(&mut self, e: &Expr, state: &mut State, meter: &mut Meter) -> Result<Val> {
+        // like the small step interpreter, a value takes no steps, even in
+        // the spans of the literal it was parsed from
+        let mut literal = e;
+
+        while let Expr::Located(_, ref e1) = *literal {
+            literal = e1;
+        }
+
+        if let Expr::Val(ref v) = *literal {
+            return Ok(v.clone());
+        }
+
+        let chunk = Rc::new(compile(e)?);
+
+        self.frames.clear();
+        self.stack.clear();
+        self.handlers.clear();
+        self.frames.push(Frame {
+            chunk,
+            pc: 0,
+            call: None,
+            stack: 0,
+            handlers: 0,
+            depth: state.depth(),
+        });
+
+        // the bodies compiled for this eval go with it, rather than keeping
+        // the functions they belong to alive for as long as the Vm
+        let result = self.run(state, meter);
+        self.bodies.clear();
+        result
+    }
+
+    fn run(&mut self, state: &mut State, meter: &mut Meter) -> Result<Val> {
+        loop {
+            let (chunk, pc) = match self.frames.last_mut() {
+                Some(frame) => {
+                    frame.pc += 1;
+                    (frame.chunk.clone(), frame.pc - 1)
+                }
+                None => return Err(RuntimeError::InvalidMemoryState("no frame to run".to_string())),
+            };
+
+            // only the program itself runs off the end of its code
+            let op = match chunk.code.get(pc) {
+                Some(op) => op,
+                None => return Ok(self.pop()),
+            };
+
+            if let Err(err) = meter.tick() {
+                return Err(self.trace(err));
+            }
+
+            // errors keep the span of the innermost expression that raised
+            // them, then go to the innermost try, if they can be caught
+            if let Err(err) = self.exec(op, state, meter) {
+                let err = match chunk.spans[pc] {
+                    Some(span) => err.at(span),
+                    None => err,
+                };
+
+                match (err.to_val(), self.handlers.pop()) {
+                    (Some(v), Some(handler)) => self.catch(handler, v, state)?,
+                    _ => return Err(self.trace(err)),
+                }
+            }
+        }
+    }
+
+    // attaches the calls in progress to an error leaving them all
+    fn trace(&self, err: RuntimeError) -> RuntimeError {
+        err.with_backtrace(self.frames.iter().rev().filter_map(|frame| frame.call.clone()).collect())
+    }
+
+    fn catch(&mut self, handler: Handler, v: Val, state: &mut State) -> Result<()> {
+        self.frames.truncate(handler.frame);
+        self.stack.truncate(handler.stack);
+        state.unwind_to(handler.depth);
+
+        state.begin_block();
+        state.alloc(handler.name, Expr::Val(v))?;
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = handler.pc;
+        }
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Val {
+        self.stack.pop().unwrap_or(Val::Undefined)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<Val> {
+        let at = self.stack.len() - n;
+        self.stack.split_off(at)
+    }
+
+    fn jump(&mut self, to: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.pc = to;
+        }
+    }
+
+    // the compiled code for a function body, compiling it on the first call
+    fn body(&mut self, body: &Rc<Expr>) -> Result<Rc<Chunk>> {
+        if let Some((_, chunk)) = self.bodies.get(&Rc::as_ptr(body)) {
+            return Ok(chunk.clone());
+        }
+
+        let chunk = Rc::new(compile_body(body)?);
+        self.bodies.insert(Rc::as_ptr(body), (body.clone(), chunk.clone()));
+        Ok(chunk)
+    }
+
+    fn exec(&mut self, op: &Op, state: &mut State, meter: &Meter) -> Result<()> {
+        match *op {
+            Op::Const(ref v) => self.stack.push(v.clone()),
+            Op::Load(ref x) => {
+                let v = lookup(&state.mem, x)?;
+                self.stack.push(v);
+            }
+            Op::Decl(ref dt, ref x) => {
+                let v = Expr::Val(self.pop());
+
+                match *dt {
+                    Dec::DConst => state.alloc_const(x.clone(), v)?,
+                    Dec::DVar => state.alloc(x.clone(), v)?,
+                }
+            }
+            Op::Store(ref x) => {
+                let v = self.stack.last().cloned().unwrap_or(Val::Undefined);
+                state.assign(x.clone(), Expr::Val(v))?;
+            }
+            Op::LoadPlace(ref place, n) => {
+                let at = self.stack.len() - n;
+                let place = fill_place(place, &mut self.stack[at..].iter().cloned());
+                let v = read_place(&state.mem, &place, lookup)?;
+                self.stack.push(v);
+            }
+            Op::StorePlace(ref place, n) => {
+                let v = self.pop();
+                let place = fill_place(place, &mut self.pop_n(n).into_iter());
+                assign_to(&state.mem, &place, v.clone())?;
+                self.stack.push(v);
+            }
+            Op::NotAssignable(ref e) => {
+                return Err(RuntimeError::UnexpectedExpr("assignable expression".to_string(), e.clone()))
+            }
+            Op::Pop => {
+                self.pop();
+            }
+            Op::Nip => {
+                let v = self.pop();
+                self.pop();
+                self.stack.push(v);
+            }
+            Op::Unary(ref op) => {
+                let v = self.pop();
+                self.stack.push(apply_uop(op.clone(), v)?);
+            }
+            Op::Binary(ref op) => {
+                let v2 = self.pop();
+                let v1 = self.pop();
+                self.stack.push(apply_bop(op.clone(), v1, v2)?);
+            }
+            Op::ShortCircuit(ref op, to) => match (op, self.stack.last()) {
+                (&BinOp::And, Some(&Val::Bool(false))) | (&BinOp::Or, Some(&Val::Bool(true))) => self.jump(to),
+                (_, Some(&Val::Bool(_))) => {}
+                (_, v) => {
+                    let v = v.cloned().unwrap_or(Val::Undefined);
+                    return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v)));
+                }
+            },
+            Op::Jump(to) => self.jump(to),
+            // a ternary doesn't step a condition that is some other value
+            Op::Branch(to) => match self.pop() {
+                Val::Bool(true) => {}
+                Val::Bool(false) => self.jump(to),
+                v => return Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+            },
+            Op::LoopTest(to) => match self.pop() {
+                Val::Bool(true) => {}
+                Val::Bool(false) => self.jump(to),
+                v => return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v))),
+            },
+            Op::ForNext(ref x, to) => {
+                let at = match self.pop() {
+                    Val::Int(at) => at as usize,
+                    _ => unreachable!("a for loop offset"),
+                };
+
+                let item = match self.stack.last() {
+                    Some(items) => builtins::item_at(items, at)?,
+                    None => None,
+                };
+
+                match item {
+                    None => {
+                        self.pop();
+                        self.jump(to);
+                    }
+                    Some((item, next)) => {
+                        self.stack.push(Val::Int(next as isize));
+                        state.begin_block();
+                        state.alloc(x.clone(), Expr::Val(item))?;
+                    }
+                }
+            }
+            Op::BeginBlock => state.begin_block(),
+            Op::EndScope => state.end_scope(),
+            Op::Unwind(values, handlers, scopes) => {
+                let at = self.stack.len() - values;
+                self.stack.truncate(at);
+                let at = self.handlers.len() - handlers;
+                self.handlers.truncate(at);
+
+                for _ in 0..scopes {
+                    state.end_scope();
+                }
+            }
+            Op::List(n) => {
+                let xs = self.pop_n(n);
+                self.stack.push(Val::List(xs));
+            }
+            Op::Record(ref keys) => {
+                let vs = self.pop_n(keys.len());
+                self.stack.push(Val::Record(keys.iter().cloned().zip(vs).collect()));
+            }
+            Op::Index => {
+                let i = self.pop();
+                let v = self.pop();
+                self.stack.push(builtins::index(v, i)?);
+            }
+            Op::Field(ref name) => {
+                let v = self.pop();
+                self.stack.push(builtins::field(v, name)?);
+            }
+            Op::Closure(ref name, ref body, ref params) => {
+                self.stack.push(Val::Func(name.clone(), body.clone(), params.clone(), state.capture()));
+            }
+            Op::Callable => match self.stack.last() {
+                Some(&Val::Func(..)) | Some(&Val::Builtin(_)) => {}
+                v => {
+                    let v = v.cloned().unwrap_or(Val::Undefined);
+                    return Err(RuntimeError::SteppingOnValue(Expr::Val(v)));
+                }
+            },
+            Op::Call(n) | Op::TailCall(n) => {
+                let args = self.pop_n(n);
+
+                match self.pop() {
+                    f @ Val::Func(..) => {
+                        // the span of this op in the calling frame
+                        let site = self.frames.last().and_then(|frame| frame.chunk.spans[frame.pc - 1]);
+
+                        // a tail call replaces the caller's frame, so that
+                        // recursive loops run in constant space, and only
+                        // counts it
+                        let tail_calls = match *op {
+                            Op::TailCall(_) => {
+                                let frame = self.frames.last().and_then(|frame| frame.call.as_ref());
+                                let tail_calls = frame.map_or(0, |call| call.tail_calls + 1);
+                                self.leave_frame(state);
+                                tail_calls
+                            }
+                            _ => 0,
+                        };
+
+                        // the program's own frame is not a call
+                        meter.check_depth(self.frames.len() - 1)?;
+                        self.call(f, site, tail_calls, args, state)?
+                    }
+                    Val::Builtin(b) => {
+                        let v = b.call(args)?;
+                        self.stack.push(v);
+                    }
+                    v => return Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+                }
+            }
+            Op::Return => {
+                let v = self.pop();
+
+                // return is only allowed inside a function, so the program's own
+                // frame is never left this way
+                if self.frames.len() == 1 {
+                    return Err(RuntimeError::Return(v));
+                }
+
+                self.leave_frame(state);
+                self.stack.push(v);
+            }
+            Op::Print => {
+                println!("{}", self.pop());
+                self.stack.push(Val::Undefined);
+            }
+            Op::Throw => return Err(RuntimeError::Thrown(self.pop())),
+            Op::Match(ref pattern, to) => {
+                let mut bindings = Vec::new();
+
+                let matched = match self.stack.last() {
+                    Some(v) => match_pattern(pattern, v, &mut bindings),
+                    None => false,
+                };
+
+                match matched {
+                    true => {
+                        state.begin_block();
+
+                        for (x, v) in bindings {
+                            state.alloc(x, Expr::Val(v))?;
+                        }
+                    }
+                    false => self.jump(to),
+                }
+            }
+            Op::Guard(to) => match self.pop() {
+                Val::Bool(true) => {}
+                Val::Bool(false) => {
+                    state.end_scope();
+                    self.jump(to);
+                }
+                v => {
+                    state.end_scope();
+                    return Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(v)));
+                }
+            },
+            Op::NoMatch => return Err(RuntimeError::NoMatchingArm(Expr::Val(self.pop()))),
+            Op::Try(ref name, to) => self.handlers.push(Handler {
+                frame: self.frames.len(),
+                pc: to,
+                stack: self.stack.len(),
+                depth: state.depth(),
+                name: name.clone(),
+            }),
+            Op::EndTry => {
+                self.handlers.pop();
+            }
+            Op::SteppingOnValue => return Err(RuntimeError::SteppingOnValue(Expr::Val(self.pop()))),
+        }
+
+        Ok(())
+    }
+
+    fn leave_frame(&mut self, state: &mut State) {
+        if let Some(frame) = self.frames.pop() {
+            self.stack.truncate(frame.stack);
+            self.handlers.truncate(frame.handlers);
+            state.unwind_to(frame.depth);
+        }
+    }
+
+    // binds the arguments like the small step interpreter does, without
+    // checking their number
+    fn call(&mut self, f: Val, site: Option<Span>, tail_calls: usize, args: Vec<Val>, state: &mut State) -> Result<()> {
+        let (name, body, params, env) = match f {
+            Val::Func(name, body, params, env) => (name, body, params, env),
+            v => return Err(RuntimeError::SteppingOnValue(Expr::Val(v))),
+        };
+
+        let depth = state.depth();
+        state.begin_scope(&env);
+
+        for (x, v) in params.iter().zip(args) {
+            match *x {
+                Expr::Var(ref x) => state.alloc(x.clone(), Expr::Val(v))?,
+                ref e => return Err(RuntimeError::InvalidTypeConversion("var".to_string(), e.clone())),
+            }
+        }
+
+        // a named function can call itself
+        if let Some(ref n) = name {
+            if let Expr::Var(ref s) = **n {
+                let f = Val::Func(name.clone(), body.clone(), params.clone(), env.clone());
+                state.alloc(s.clone(), Expr::Val(f))?;
+            }
+        }
+
+        let chunk = self.body(&body)?;
+
+        self.frames.push(Frame {
+            chunk,
+            pc: 0,
+            call: Some(StackFrame {
+                function: function_name(&name),
+                call: site,
+                tail_calls,
+            }),
+            stack: self.stack.len(),
+            handlers: self.handlers.len(),
+            depth,
+        });
+
+        Ok(())
+    }
+}
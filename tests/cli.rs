@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::io::Write;
+  use std::process::{Command, Output, Stdio};
+
+  fn boxx(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_boxx"))
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .expect("failed to start boxx");
+
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+  }
+
+  fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+  }
+
+  fn script(name: &str, text: &str) -> String {
+    let path = env::temp_dir().join(format!("boxx_cli_{}_{}.bx", name, std::process::id()));
+    fs::write(&path, text).unwrap();
+    path.to_string_lossy().into_owned()
+  }
+
+  #[test]
+  fn test_run_file() {
+    let path = script("run_file", "
+      fn double(x) { x * 2 };
+      print(double(21));
+    ");
+
+    let output = boxx(&[&path], "");
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("42\n", stdout(&output));
+  }
+
+  #[test]
+  fn test_script_args() {
+    let path = script("script_args", "print(len(args)); print(args[0] + args[1])");
+
+    let output = boxx(&[&path, "foo", "bar"], "");
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("2\nfoobar\n", stdout(&output));
+  }
+
+  #[test]
+  fn test_inline_expression() {
+    let output = boxx(&["-e", "1 + 2"], "");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("3\n", stdout(&output));
+
+    let output = boxx(&["-e", "var x = 1;"], "");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("", stdout(&output));
+
+    let output = boxx(&["-e", "args", "a"], "");
+    assert_eq!("[\"a\"]\n", stdout(&output));
+  }
+
+  #[test]
+  fn test_stdin() {
+    let output = boxx(&["-", "x"], "print(\"hi \" + args[0])");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("hi x\n", stdout(&output));
+  }
+
+  #[test]
+  fn test_bytecode_engine() {
+    let output = boxx(&["--bytecode", "-e", "fn fib(n) { n < 2 ? n : fib(n - 1) + fib(n - 2) }; fib(20)"], "");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("6765\n", stdout(&output));
+
+    let output = boxx(&["--bytecode", "-", "x"], "print(args[0]); print(args[1])");
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("x\n", stdout(&output));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("<stdin>:1:23: Index 1 is out of bounds"));
+  }
+
+  #[test]
+  fn test_exit_codes() {
+    assert_eq!(Some(1), boxx(&["-e", "1 + true"], "").status.code());
+    assert_eq!(Some(1), boxx(&["-"], "print(1); undefined_var").status.code());
+    assert_eq!(Some(2), boxx(&["-e", "\"unterminated"], "").status.code());
+    assert_eq!(Some(64), boxx(&["-e"], "").status.code());
+    assert_eq!(Some(64), boxx(&["--nope"], "").status.code());
+    assert_eq!(Some(66), boxx(&["/no/such/file.bx"], "").status.code());
+
+    let output = boxx(&["-e", "1 + true"], "");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("<expr>:1:1: Invalid type conversion"));
+  }
+
+  #[test]
+  fn test_located_errors() {
+    let path = script("located_errors", "let x = 1;\nlet y = (x + 2;\nprint(y)");
+
+    let output = boxx(&[&path], "");
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(Some(2), output.status.code());
+    assert_eq!(
+      format!("{}:2:15: expected ')' found ';'\n  |\n2 | let y = (x + 2;\n  |               ^\n", path),
+      String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = boxx(&["-"], "var xs = [1, 2];\nprint(xs[0]);\nprint(xs[5] + 1)");
+
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(
+      "<stdin>:3:7: Index 5 is out of bounds for length 2\n  |\n3 | print(xs[5] + 1)\n  |       ^^^^^\n",
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+
+  #[test]
+  fn test_repl_stops_at_end_of_input() {
+    let output = boxx(&[], "1 + 1\n");
+    assert_eq!(Some(0), output.status.code());
+    assert_eq!("boxx> 2\nboxx> \n", stdout(&output));
+  }
+}
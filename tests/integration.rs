@@ -3,15 +3,53 @@ extern crate boxx;
 #[cfg(test)]
 mod tests {
   extern crate boxx;
-  use boxx::interpreter::Interpreter;
-  use boxx::expr::{Val, Expr};
+  use boxx::evaluator::{BigStep, Evaluator};
+  use boxx::interpreter::{Engine, Interpreter};
+  use boxx::expr::{Val, Expr, BinOp};
+  use boxx::parser::parser::parse;
   use boxx::runtime_error::RuntimeError;
+  use std::thread;
 
   extern crate env_logger;
 
+  const ENGINES: [Engine; 2] = [Engine::SmallStep, Engine::Bytecode];
+
+  // Runs a program on both engines and the big step evaluator, which must
+  // agree on the result and on where any error came from, and the engines
+  // on its backtrace too. Debug output is compared, since functions only
+  // equal themselves and errors ignore their spans and backtraces. The
+  // program must also print back as source that parses the same.
   fn boxx(input: &str) -> Result<Expr, RuntimeError> {
-    let mut interpreter = Interpreter::new();
-    interpreter.eval(input)
+    if let Ok(e) = parse(input) {
+      assert_eq!(Ok(e.clone()), parse(&e.to_string()), "{} prints as {}", input, e);
+    }
+
+    let expected = Interpreter::new().eval(input);
+    let bytecode = Interpreter::builder().engine(Engine::Bytecode).build().eval(input);
+    let big_step = BigStep::new().eval(input);
+
+    assert_eq!(format!("{:?}", expected), format!("{:?}", bytecode), "the engines disagree on {}", input);
+    assert_eq!(
+      format!("{:?}", expected.as_ref().map_err(|err| err.without_backtrace())),
+      format!("{:?}", big_step),
+      "the big step evaluator disagrees on {}", input
+    );
+    expected
+  }
+
+  // Runs boxx on a thread with a stack as big as the one the CLI runs
+  // programs on, for programs that nest as many calls as the engines allow.
+  // Values can't leave the thread, so the result is given as its debug
+  // output.
+  fn deep_boxx(input: &str) -> String {
+    let input = input.to_string();
+
+    thread::Builder::new()
+      .stack_size(1 << 30)
+      .spawn(move || format!("{:?}", boxx(&input)))
+      .unwrap()
+      .join()
+      .unwrap()
   }
 
   #[test]
@@ -26,41 +64,43 @@ mod tests {
   pub fn test_interpreter() {
     let _ = env_logger::init();
 
-    let mut interpreter = Interpreter::new();
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).build();
 
-    assert_eq!(
-      Expr::Val(Val::Int(2)),
-      interpreter.eval("1 + 1").unwrap()
-    );
+      assert_eq!(
+        Expr::Val(Val::Int(2)),
+        interpreter.eval("1 + 1").unwrap()
+      );
 
-    assert_eq!(
-      Expr::Val(Val::Undefined),
-      interpreter.eval("var x = 3;").unwrap()
-    );
+      assert_eq!(
+        Expr::Val(Val::Undefined),
+        interpreter.eval("var x = 3;").unwrap()
+      );
 
-    assert_eq!(
-      Expr::Val(Val::Int(3)),
-      interpreter.eval("x").unwrap()
-    );
+      assert_eq!(
+        Expr::Val(Val::Int(3)),
+        interpreter.eval("x").unwrap()
+      );
 
 
-    assert_eq!(
-      Expr::Val(Val::Undefined),
-      interpreter.eval("fn double(x) { x + x };").unwrap()
-    );
+      assert_eq!(
+        Expr::Val(Val::Undefined),
+        interpreter.eval("fn double(x) { x + x };").unwrap()
+      );
 
-    assert_eq!(
-      Expr::Val(Val::Int(48)),
-      interpreter.eval("double(24)").unwrap()
-    );
+      assert_eq!(
+        Expr::Val(Val::Int(48)),
+        interpreter.eval("double(24)").unwrap()
+      );
+    }
   }
 
   #[test]
   pub fn test_integer_overflow() {
     let _ = env_logger::init();
 
-    let max_int = isize::max_value();
-    let min_int = isize::min_value();
+    let max_int = isize::MAX;
+    let min_int = isize::MIN;
 
     assert_eq!(
       Ok(Expr::Val(Val::Int(max_int))),
@@ -92,16 +132,118 @@ mod tests {
       ", min_int + 1).as_str())
     );
 
+    // the result would be isize::MAX + 1
     assert_eq!(
-      Err(RuntimeError::IntegerUnderflow),
+      Err(RuntimeError::IntegerOverflow),
       boxx(format!("
         ({} - 1) / -1
       ", min_int + 1).as_str())
     );
 
     assert_eq!(
-      Err(RuntimeError::IntegerUnderflow),
-      boxx("1 % 0")
+      Ok(Expr::Val(Val::Int(0))),
+      boxx(format!("
+        ({} - 1) % -1
+      ", min_int + 1).as_str())
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(max_int - 1))), boxx(&format!("{} % {}", max_int - 1, max_int)));
+
+    // for the same reason, the smallest int is written back as source as
+    // the arithmetic that makes it, while it prints as a value as usual
+    let min = Expr::Val(Val::Int(min_int));
+    assert_eq!(format!("({} - 1)", min_int + 1), min.to_string());
+    assert_eq!(min_int.to_string(), Val::Int(min_int).to_string());
+    assert_eq!(Ok(min), boxx(&Expr::Val(Val::Int(min_int)).to_string()));
+    assert_eq!(Ok(Expr::Val(Val::Range(min_int, 0))), boxx(&Expr::Val(Val::Range(min_int, 0)).to_string()));
+  }
+
+  #[test]
+  pub fn test_division_by_zero() {
+    let _ = env_logger::init();
+
+    let div = |n1, n2| Expr::Bop(BinOp::Div, Box::new(Expr::Val(Val::Int(n1))), Box::new(Expr::Val(Val::Int(n2))));
+    let rem = |n1, n2| Expr::Bop(BinOp::Mod, Box::new(Expr::Val(Val::Int(n1))), Box::new(Expr::Val(Val::Int(n2))));
+
+    assert_eq!(Err(RuntimeError::DivisionByZero(div(1, 0))), boxx("1 / 0"));
+    assert_eq!(Err(RuntimeError::DivisionByZero(rem(1, 0))), boxx("1 % 0"));
+    assert_eq!(Err(RuntimeError::DivisionByZero(div(-7, 0))), boxx("var x = 0; -7 / x"));
+    assert_eq!(Err(RuntimeError::DivisionByZero(rem(0, 0))), boxx("fn f(n) { n % n }; f(0)"));
+
+    // floats follow IEEE 754 instead
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::INFINITY))), boxx("1.0 / 0.0"));
+  }
+
+  #[test]
+  pub fn test_exponents() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(1024))), boxx("2 ** 10"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("7 ** 0"));
+    assert_eq!(Ok(Expr::Val(Val::Int(512))), boxx("2 ** 3 ** 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-4))), boxx("-2 ** 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("(-2) ** 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-8))), boxx("(-2) ** 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(13))), boxx("1 + 3 * 2 ** 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("1 ** 9223372036854775807"));
+    assert_eq!(Ok(Expr::Val(Val::Int(isize::MIN))), boxx("(-2) ** 63"));
+    assert_eq!(Ok(Expr::Val(Val::Float(0.5))), boxx("2.0 ** -1.0"));
+
+    assert_eq!(Err(RuntimeError::IntegerOverflow), boxx("2 ** 63"));
+    assert_eq!(Err(RuntimeError::IntegerOverflow), boxx("3 ** 9223372036854775807"));
+    assert_eq!(Err(RuntimeError::IntegerOverflow), boxx("-((-2) ** 63)"));
+
+    let pow = Expr::Bop(BinOp::Pow, Box::new(Expr::Val(Val::Int(2))), Box::new(Expr::Val(Val::Int(-1))));
+    assert_eq!(Err(RuntimeError::NegativeExponent(pow)), boxx("2 ** -1"));
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Float(2.0)))),
+      boxx("2 ** 2.0")
+    );
+  }
+
+  #[test]
+  pub fn test_bitwise_operators() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("6 & 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("6 | 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("6 ^ 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-6))), boxx("~5"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("(1 | 2 | 4) & ~2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(40))), boxx("5 << 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-4))), boxx("-8 >> 1"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-1))), boxx("-1 >> 63"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1 << 62))), boxx("1 << 62"));
+    assert_eq!(Ok(Expr::Val(Val::Int(isize::MIN))), boxx("-1 << 63"));
+    assert_eq!(Ok(Expr::Val(Val::Int(16))), boxx("var f = 1; f |= 8; f <<= 2; f ^= 1; f >>= 1; f &= 20; f"));
+
+    // like C, bitwise operators bind looser than comparisons
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("(6 & 4) == 4"));
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Bool(true)))),
+      boxx("6 & 4 == 4")
+    );
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Bool(true)))),
+      boxx("~true")
+    );
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Float(1.5)))),
+      boxx("~1.5")
+    );
+
+    let shl = |n1, n2| Expr::Bop(BinOp::Shl, Box::new(Expr::Val(Val::Int(n1))), Box::new(Expr::Val(Val::Int(n2))));
+    let shr = |n1, n2| Expr::Bop(BinOp::Shr, Box::new(Expr::Val(Val::Int(n1))), Box::new(Expr::Val(Val::Int(n2))));
+
+    assert_eq!(Err(RuntimeError::ShiftOverflow(shl(1, 63))), boxx("1 << 63"));
+    assert_eq!(Err(RuntimeError::ShiftOverflow(shl(3, 62))), boxx("3 << 62"));
+    assert_eq!(Err(RuntimeError::ShiftOverflow(shl(-3, 62))), boxx("-3 << 62"));
+    assert_eq!(Err(RuntimeError::InvalidShift(shl(1, 64))), boxx("1 << 64"));
+    assert_eq!(Err(RuntimeError::InvalidShift(shr(1, -1))), boxx("1 >> -1"));
+    assert_eq!(
+      format!("Shift amount must be between 0 and {}: 1 << 64", isize::BITS - 1),
+      boxx("1 << 64").unwrap_err().to_string()
     );
   }
 
@@ -133,8 +275,6 @@ mod tests {
       ")
     );
 
-    /*
-     * TODO: block comments
     assert_eq!(
       Ok(Expr::Val(Val::Int(2))),
       boxx("
@@ -144,7 +284,62 @@ mod tests {
         i
       ")
     );
-    */
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(7))),
+      boxx("
+        /*
+         * block comments nest, so code containing them can be commented out
+         * /* i = i + 1; */
+         */
+        let i = 3 /* inline */ + 4;
+        /// doc comments are comments too
+        //// as is this
+        i
+      ")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(10))), boxx("5 /**/ * 2 // done"));
+
+    match boxx("1 + /* /* */ 2") {
+      Err(ref err) => assert_eq!(Some((1, 5)), err.span().map(|s| (s.line, s.col))),
+      other => panic!("expected an unterminated comment error, found {:?}", other),
+    }
+  }
+
+  #[test]
+  pub fn test_compound_assignment() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(10))), boxx("var i = 0; while (i < 10) { i += 1 }; i"));
+    assert_eq!(Ok(Expr::Val(Val::Int(10))), boxx("var x = 7; x %= 4; x -= 1; x *= 5; x"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var x = 7; x /= 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(64))), boxx("var x = 2; x **= 3 * 2; x"));
+    assert_eq!(Ok(Expr::Val(Val::Str(String::from("ab")))), boxx("var s = \"a\"; s += \"b\"; s"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var x = 1; var y = 1; x += y += 3; x"));
+
+    // the index of an element is only evaluated once
+    assert_eq!(
+      Ok(Expr::Val(Val::List(vec![Val::Int(1), Val::Int(12), Val::Int(3), Val::Int(1)]))),
+      boxx("
+        var xs = [1, 2, 3];
+        var calls = 0;
+        fn next() { calls += 1; calls };
+        xs[next()] += 10;
+        xs + [calls]
+      ")
+    );
+    assert_eq!(Ok(Expr::Val(Val::Int(8))), boxx("var p = { a: { b: 2 } }; p.a.b **= 3; p.a.b"));
+
+    // errors match the long form
+    assert_eq!(boxx("let x = 1; x = x + 1"), boxx("let x = 1; x += 1"));
+    assert_eq!(boxx("let xs = [1]; xs[0] = xs[0] + 1"), boxx("let xs = [1]; xs[0] += 1"));
+    assert_eq!(Err(RuntimeError::IntegerOverflow), boxx("var x = 9223372036854775807; x += 1"));
+    assert_eq!(
+      Err(RuntimeError::DivisionByZero(Expr::Bop(BinOp::Mod, Box::new(Expr::Val(Val::Int(1))), Box::new(Expr::Val(Val::Int(0)))))),
+      boxx("var x = 1; x %= 0")
+    );
+    assert_eq!(Err(RuntimeError::VariableNotFound(String::from("y"))), boxx("y += 1"));
   }
 
   #[test]
@@ -207,224 +402,1158 @@ mod tests {
   }
 
   #[test]
-  pub fn test_undefined() {
+  pub fn test_while_checks_condition_first() {
     let _ = env_logger::init();
 
-    assert_eq!(Ok(Expr::Val(Val::Undefined)), boxx("var x = 2;"));
-
+    assert_eq!(Ok(Expr::Val(Val::Int(10))), boxx("var i = 10; while (i < 5) { i = i + 1 }; i"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("var n = 0; while (false) { n = n + 1 }; n"));
     assert_eq!(
-      Ok(Expr::Val(Val::Int(8))),
-      boxx("
-        var x = 4;
-        var foo = fn(z) {
-          x = z + 2;
-        };
-        foo(x);
-        foo(x);
-        x
-      ")
+      Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("while (1) { 2 }; 3")
     );
   }
 
   #[test]
-  pub fn test_mut_var() {
+  pub fn test_return() {
     let _ = env_logger::init();
 
-    assert_eq!(Ok(Expr::Val(Val::Int(555))), boxx("var x = 55; var y = 500; x + y"));
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(-1))),
+      boxx("
+        fn find(xs, x) {
+          var i = 0;
+          while (i < len(xs)) {
+            if (xs[i] == x) { return i };
+            i = i + 1
+          };
+          -1
+        };
+        find([1, 2, 3], 5)
+      ")
+    );
 
     assert_eq!(
       Ok(Expr::Val(Val::Int(2))),
-      boxx("var x = 1; var y = 2; x = y; y = 3; x")
+      boxx("fn find(xs, x) { var i = 0; while (i < len(xs)) { if (xs[i] == x) { return i }; i = i + 1 }; -1 }; find([4, 5, 6], 6)")
     );
 
-    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("var x = 1; x = 2; x"));
-
+    assert_eq!(Ok(Expr::Val(Val::Undefined)), boxx("fn f() { return; 1 }; f()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("fn f(x) { return x > 0 ? x : -x; 0 }; f(-5)"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("fn f() { fn g() { return 1; 100 }; g() + 2 }; f()"));
     assert_eq!(
-      Ok(Expr::Val(Val::Int(5))),
-      boxx("var x = 3; var y = 2; x = y; y = x; let z = 1; z + x + y")
+      Ok(Expr::Val(Val::Int(55))),
+      boxx("fn fib(n) { if (n < 2) { return n }; fib(n - 1) + fib(n - 2) }; fib(10)")
     );
 
+    // returning from inside nested calls and loops leaves the caller's scope intact
     assert_eq!(
-      Ok(Expr::Val(Val::Int(20))),
+      Ok(Expr::Val(Val::Int(1012))),
       boxx("
-        var x = 4;
-        fn foo(z) {
-          x * z 
+        var x = 1;
+        fn f() {
+          var x = 2;
+          while (true) {
+            while (true) { return x + 10 };
+            0
+          };
+          0
         };
-        foo(x) + x
+        let r = f();
+        x * 1000 + r
       ")
     );
+  }
+
+  #[test]
+  pub fn test_break_continue() {
+    let _ = env_logger::init();
 
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var i = 0; while (true) { if (i == 5) { break }; i = i + 1 }; i"));
     assert_eq!(
-      Ok(Expr::Val(Val::Int(15))),
-      boxx("var x = 4; fn foo(z) { var x = 7; x + z }; foo(x) + x")
+      Ok(Expr::Val(Val::Int(25))),
+      boxx("var i = 0; var sum = 0; while (i < 10) { i = i + 1; if (i % 2 == 0) { continue }; sum = sum + i }; sum")
     );
 
-    /*
-       TODO: allow var bindings so that fn params can be reassigned
+    // break and continue apply to the innermost loop
     assert_eq!(
-      Ok(Expr::Val(Val::Int(23))),
-      boxx("var x = 4; fn foo(z) { var x = 7; z = x; x = 12; x + z }; foo(x) + x")
+      Ok(Expr::Val(Val::Int(30))),
+      boxx("
+        var total = 0;
+        var i = 0;
+        while (i < 3) {
+          i = i + 1;
+          var j = 0;
+          while (true) {
+            j = j + 1;
+            if (j > 4) { break };
+            if (j == 2) { continue };
+            total = total + 1
+          };
+          total = total + 7
+        };
+        total
+      ")
     );
-    */
-
-    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("var i = 1; i = i + 1; i"));
 
+    // a loop inside a called function doesn't end the caller's loop
     assert_eq!(
-      Ok(Expr::Val(Val::Int(13))),
-      boxx("var x = 10; var foo = fn(x) { var foo = fn (y) { var x = 3; y + x }; foo(x) }; foo(x) ")
+      Ok(Expr::Val(Val::Int(3))),
+      boxx("fn f() { while (true) { break }; 1 }; var n = 0; while (n < 3) { n = n + f() }; n")
     );
-
-    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var x = 3; x = fn() { 4 + 1 }; x()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var x = fn() { 4 + 1 }; x = 3; x"));
   }
 
-
   #[test]
-  pub fn test_if_else() {
+  pub fn test_match() {
     let _ = env_logger::init();
 
     assert_eq!(
-      Ok(Expr::Val(Val::Int(999))),
-      boxx("var b = 1; if (true) { b = 999; }; b ")
+      Ok(Expr::Val(Val::Int(21))),
+      boxx("
+        fn fib(n) {
+          match n {
+            0 => 0,
+            1 => 1,
+            n => fib(n - 1) + fib(n - 2),
+          }
+        };
+        fib(8)
+      ")
     );
 
-    assert_eq!(
-      Ok(Expr::Val(Val::Int(34))),
-      boxx("if (true && false) { 32 } else if (!true && true) { 33 } else { 34 }")
-    );
+    let sign = "fn sign(n) { match n { 0 => \"zero\", n if n < 0 => \"negative\", _ => \"positive\" } };";
+    assert_eq!(Ok(Expr::Val(Val::Str(String::from("negative")))), boxx(&format!("{} sign(-4)", sign)));
+    assert_eq!(Ok(Expr::Val(Val::Str(String::from("zero")))), boxx(&format!("{} sign(0)", sign)));
+    assert_eq!(Ok(Expr::Val(Val::Str(String::from("positive")))), boxx(&format!("{} sign(4)", sign)));
 
-    assert_eq!(
-      Ok(Expr::Val(Val::Int(32))),
-      boxx("if (true || false) { 32 } else if (!true && true) { 33 } else { 34 }")
-    );
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("match -1 { 1 => 1, -1 => 2 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("match true { false => 1, true => 2 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("match \"b\" { \"a\" => 1, \"b\" => 2 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("1 + match 2 { x => x * 2 }"));
 
+    // bindings live in their own scope, and shadow outer ones
+    assert_eq!(Ok(Expr::Val(Val::Int(13))), boxx("var x = 3; var y = match 10 { x => x }; x + y"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var x = 3; match 1 { x if x > 5 => x, _ => x }"));
     assert_eq!(
-      Ok(Expr::Val(Val::Int(30))),
-      boxx("if (true && false) { 32 } else { 30 }")
+      Err(RuntimeError::VariableNotFound(String::from("n"))),
+      boxx("match 1 { n => n }; n")
     );
 
+    // a guard can't see bindings from an earlier arm that didn't match
     assert_eq!(
-      Ok(Expr::Val(Val::Int(52))),
-      boxx("if (let x = 4; x > 3) { 52 } else { 30 }")
+      Err(RuntimeError::VariableNotFound(String::from("a"))),
+      boxx("match 1 { a if false => 1, _ if a => 2 }")
     );
 
+    assert_eq!(Err(RuntimeError::NoMatchingArm(Expr::Val(Val::Int(7)))), boxx("match 7 { 1 => 1, 2 => 2 }"));
+    assert_eq!(Err(RuntimeError::NoMatchingArm(Expr::Val(Val::Int(7)))), boxx("match 7 { n if n < 0 => 1 }"));
+    assert_eq!(Err(RuntimeError::NoMatchingArm(Expr::Val(Val::Int(1)))), boxx("match 1 { }"));
     assert_eq!(
-      Ok(Expr::Val(Val::Int(22))),
-      boxx("if (true) { 11 } else { 0 }; 22")
+      Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("match 1 { n if n => 1 }")
     );
   }
 
   #[test]
-  pub fn test_func() {
+  pub fn test_match_destructuring() {
     let _ = env_logger::init();
 
+    let describe = "
+      fn describe(x) {
+        match x {
+          [] => \"empty\",
+          [a] => \"one \" + str(a),
+          [a, b, ..rest] if len(rest) > 1 => \"long \" + str(a + b),
+          [_, ..] => \"short\",
+          { name, age: 0 } => \"newborn \" + name,
+          { name } => name,
+          _ => \"other\",
+        }
+      };
+    ";
+
+    let cases = [
+      ("[]", "empty"),
+      ("[4]", "one 4"),
+      ("[1, 2, 3, 4]", "long 3"),
+      ("[1, 2, 3]", "short"),
+      ("{ name: \"ann\", age: 0 }", "newborn ann"),
+      ("{ name: \"bo\", age: 3 }", "bo"),
+      ("{ age: 3 }", "other"),
+      ("\"[1]\"", "other"),
+    ];
+
+    for &(input, expected) in cases.iter() {
+      assert_eq!(
+        Ok(Expr::Val(Val::Str(String::from(expected)))),
+        boxx(&format!("{} describe({})", describe, input)),
+        "describe({})", input
+      );
+    }
+
     assert_eq!(
-      Ok(Expr::Val(Val::Int(8))),
-      boxx("
-        var x = 4;
-        fn foo(z) {
-          x = z + 2;
-        };
-        foo(x);
-        foo(x);
-        x
-      ")
+      Ok(Expr::Val(Val::List(vec![Val::Int(3), Val::Int(4)]))),
+      boxx("match [1, [2, 3, 4]] { [_, [2, ..rest]] => rest }")
     );
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("match { p: { x: 1, y: 2 } } { { p: { x, y } } => x + y }"));
+  }
 
-    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("let x = 4; fn foo() { let x = 1; x + 1 }; foo()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(6))), boxx("let x = 5; fn foo() { x + 1 }; foo()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(60))), boxx("fn foo() { 5 }; fn bar() { fn foo() { 6 }; foo() * 10 }; bar()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(50))), boxx("fn foo() { 5 }; fn bar() { foo() * 10 }; bar()"));
-
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn sum(a, b) { a + b }; sum(sum(3, 4), 5)"));
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn tx_two(a) { 2 * a }; tx_two(tx_two(3))"));
+  #[test]
+  pub fn test_match_control_flow() {
+    let _ = env_logger::init();
 
     assert_eq!(
-      Ok(Expr::Val(Val::Int(41))),
+      Ok(Expr::Val(Val::Str(String::from("zero after")))),
       boxx("
-        fn foo(a) {
-          a < 40 ? foo(a + 3) : a
-        };
-
-        foo(20)
+        fn early(x) { match x { 0 => return \"zero\", _ => 1 }; \"after\" };
+        early(0) + \" \" + early(1)
       ")
     );
 
     assert_eq!(
-      Ok(Expr::Val(Val::Int(21))),
-      boxx("
-        fn fib(n) {
-          n == 0 ? 0 : (n == 1 ? 1 : fib(n - 1) + fib(n - 2))
-        };
-
-        fib(8)
-      ")
+      Ok(Expr::Val(Val::Int(4))),
+      boxx("var t = 0; for (i in 0..5) { match i { 2 => continue, 4 => break, _ => t += i } }; t")
     );
 
+    // leaving an arm early also leaves its frame
     assert_eq!(
-      Ok(Expr::Val(Val::Int(21))),
+      Ok(Expr::Val(Val::Int(1))),
       boxx("
-        var fib = fn(n) {
-          n == 0 ? 0 : (n == 1 ? 1 : fib(n - 1) + fib(n - 2))
-        };
-
-        fib(8)
+        var x = 1;
+        for (i in 0..3) { match i { x if x == 1 => break, _ => 0 } };
+        x
       ")
     );
+  }
 
-    assert_eq!(
-      Ok(Expr::Val(Val::Int(28))),
-      boxx("
-        fn foo(a) {
-          1 + a
-        };
+  #[test]
+  pub fn test_throw() {
+    let _ = env_logger::init();
 
-        fn bar(b) {
-          5 * b
-        };
-        
-        foo(bar(4)) + 7
-      ")
-    );
+    assert_eq!(Err(RuntimeError::Thrown(Val::Str(String::from("boom")))), boxx("throw \"boom\""));
+    assert_eq!(Err(RuntimeError::Thrown(Val::Int(3))), boxx("fn f(x) { throw x + 1 }; f(2); 7"));
 
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn b() { 5 + 5 }; let a = b; a() + 2"));
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("let b = fn() { 5 + 5 }; let a = b; a() + 2"));
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn foo(a) { 1 + a }; foo(4) + 7"));
-    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("let foo = fn(a) { 1 + a }; foo(4) + 7"));
+    let err = boxx("var x = 1;\nthrow [x]").unwrap_err();
+    assert_eq!("Uncaught exception: [1]", err.to_string());
+    assert_eq!(Some((2, 1)), err.span().map(|span| (span.line, span.col)));
+  }
 
-    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("fn foo() { 1 + 1 }; foo()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("fn foo() { 1 + 3 }; foo() + 3"));
-    assert_eq!(Ok(Expr::Val(Val::Int(9))), boxx("fn foo() { 1 + 3 }; fn bar() { foo() + 1}; 4 + bar()"));
+  #[test]
+  pub fn test_try_catch() {
+    let _ = env_logger::init();
 
-    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("let foo = fn() { 1 + 1 }; foo()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("let foo = fn() { 1 + 3 }; foo() + 3"));
-    assert_eq!(Ok(Expr::Val(Val::Int(9))), boxx("let foo = fn() { 1 + 3 }; let bar = fn() { foo() + 1}; 4 + bar()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("try { 5 } catch (e) { 6 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(20))), boxx("try { throw 2; 3 } catch (e) { e * 10 }"));
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(42))),
+      boxx("try { throw { code: 42 } } catch (e) { match e { { code } => code } }")
+    );
 
-    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("fn() { 1 + 3 }()"));
-    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("let foo = fn() { 1 + 3 }(); foo"));
+    // rethrowing from a handler goes to the next try out
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(20))),
+      boxx("try { try { throw 1 } catch (e) { throw e + 1 } } catch (e) { e * 10 }")
+    );
+
+    // the binding is only visible in the handler
+    assert_eq!(
+      Err(RuntimeError::VariableNotFound(String::from("e"))),
+      boxx("try { throw 1 } catch (e) { e }; e")
+    );
   }
 
   #[test]
-  pub fn test_const_decl() {
+  pub fn test_catch_runtime_errors() {
     let _ = env_logger::init();
-    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let x = 1 + 2; x"));
-    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("let x = 1; x"));
-    assert_eq!(Ok(Expr::Val(Val::Int(8))), boxx("let x = 5; let y = 3; let z = x + y; z"));
 
-    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let x = (1 > 2) ? 0 : 3; x"));
+    let catch = |input: &str, field: &str| boxx(&format!("try {{ {} }} catch (e) {{ e.{} }}", input, field));
+    let string = |s: &str| Ok(Expr::Val(Val::Str(String::from(s))));
 
-    // using let keyword again re-binds value
-    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("let x = 2; let x = 3; x + 2"));
-    assert_eq!(Ok(Expr::Val(Val::Int(52))), boxx("let underscore_name = 51; 1 + underscore_name"));
+    assert_eq!(string("IntegerOverflow"), catch("9223372036854775807 + 1", "kind"));
+    assert_eq!(string("VariableNotFound"), catch("missing", "kind"));
+    assert_eq!(string("DivisionByZero"), catch("1 / 0", "kind"));
+    assert_eq!(string("NoMatchingArm"), catch("match 1 { 2 => 2 }", "kind"));
+    assert_eq!(string("Variable \"missing\" does not exist in memory"), catch("missing", "message"));
+    assert_eq!(string("Index 3 is out of bounds for length 1"), catch("[1][3]", "message"));
+
+    // with the location of the error
+    assert_eq!(
+      Ok(Expr::Val(Val::List(vec![Val::Int(3), Val::Int(8)]))),
+      boxx("try {\n  1;\n  1 + (2 / 0)\n} catch (e) { [e.line, e.col] }")
+    );
   }
 
   #[test]
-  pub fn test_ternary() {
+  pub fn test_catch_unwinds_frames() {
     let _ = env_logger::init();
-    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("true ? 1 : 0"));
-    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("false ? 1 : 0"));
-    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("(false ? 1 : 0); 1 + 2"));
+
+    // the handler runs in the scope of the try, not the function that failed
+    assert_eq!(
+      Ok(Expr::Val(Val::Str(String::from("outer 4")))),
+      boxx("
+        var x = \"outer\";
+        fn f(n) { var x = n; n == 0 ? throw x : f(n - 1) };
+        try { f(4) } catch (e) { x + \" \" + str(len([1, 2, 3, 4])) }
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(1))),
+      boxx("
+        var x = 1;
+        fn f() {
+          for (i in 0..3) {
+            var x = i;
+            match i { 2 => { var x = 100; throw x }, _ => 0 }
+          }
+        };
+        try { f() } catch (e) { x }
+      ")
+    );
+
+    // later code still sees the right frames
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(12))),
+      boxx("
+        var total = 0;
+        fn add(n) { n < 0 ? throw n : n };
+        for (i in [5, -1, 7]) {
+          total += try { add(i) } catch (e) { 0 }
+        };
+        total
+      ")
+    );
+  }
+
+  #[test]
+  pub fn test_try_and_control_flow() {
+    let _ = env_logger::init();
+
+    // return, break and continue pass through a try
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("fn g() { try { return 1 } catch (e) { 2 }; 3 }; g()"));
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(3))),
+      boxx("var n = 0; for (i in 0..5) { try { if (i == 3) { break }; n += 1 } catch (e) { 0 } }; n")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(4))),
+      boxx("var n = 0; for (i in 0..5) { try { if (i == 3) { continue }; n += 1 } catch (e) { 0 } }; n")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(2))),
+      boxx("fn g() { try { throw 1 } catch (e) { return 2 }; 3 }; g()")
+    );
+  }
+
+  #[test]
+  pub fn test_failed_eval_restores_state() {
+    let _ = env_logger::init();
+
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).build();
+
+      interpreter.eval("var x = \"global\"; fn f(n) { var x = n; var local = n; n == 0 ? missing : f(n - 1) };").unwrap();
+
+      assert_eq!(
+        Err(RuntimeError::VariableNotFound(String::from("missing"))),
+        interpreter.eval("f(3)")
+      );
+      assert_eq!(0, interpreter.state.depth());
+
+      // the next line runs at the top level again
+      assert_eq!(Ok(Expr::Val(Val::Str(String::from("global")))), interpreter.eval("x"));
+      assert_eq!(
+        Err(RuntimeError::VariableNotFound(String::from("local"))),
+        interpreter.eval("local")
+      );
+
+      // failing inside loops, match arms and catch handlers
+      let failing = [
+        "for (i in 0..3) { var local = i; i == 2 ? 1 / 0 : 0 }",
+        "var n = 0; while (true) { n += 1; n == 3 ? f(n) : n }",
+        "match 1 { local => local + true }",
+        "try { throw 1 } catch (local) { f(0) }",
+        "fn g() { match [1] { [local] => for (i in [1]) { f(local - 1) } } }; g()",
+      ];
+
+      for input in failing.iter() {
+        assert!(interpreter.eval(input).is_err(), "{} should fail", input);
+        assert_eq!(0, interpreter.state.depth(), "{} should leave no scopes behind", input);
+        assert_eq!(
+          Err(RuntimeError::VariableNotFound(String::from("local"))),
+          interpreter.eval("local"),
+          "{} should not leak its bindings", input
+        );
+      }
+
+      // top level bindings made before the error are kept, as in any REPL
+      assert!(interpreter.eval("var y = 1; y + true").is_err());
+      assert_eq!(Ok(Expr::Val(Val::Int(1))), interpreter.eval("y"));
+    }
+  }
+
+  #[test]
+  pub fn test_tail_calls() {
+    let _ = env_logger::init();
+
+    // calls in tail position replace the caller's frame instead of nesting
+    // inside it, so these run in constant space on either engine, well past
+    // the most calls that may be nested
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).max_depth(10).build();
+
+      assert_eq!(
+        Ok(Expr::Val(Val::Int(10000))),
+        interpreter.eval("fn count(n, acc) { n == 0 ? acc : count(n - 1, acc + 1) }; count(10000, 0)")
+      );
+      assert_eq!(0, interpreter.state.depth());
+    }
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Str(String::from("done")))),
+      boxx("fn f(n) { var m = n - 1; n == 0 ? \"done\" : f(m) }; f(10)")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(0))),
+      boxx("fn f(n) { if (n == 0) { return 0 }; f(n - 1) }; f(10)")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(10))),
+      boxx("var n = 0; fn f() { n += 1; n < 10 ? f() : n }; f()")
+    );
+
+    // errors from the last call still reach a try around the first
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(0))),
+      boxx("fn f(n) { n == 0 ? throw n : f(n - 1) }; try { f(10) } catch (e) { e }")
+    );
+
+    // a call inside a try isn't in tail position, so each one catches
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(2))),
+      boxx("fn g(n) { try { n == 0 ? throw 1 : g(n - 1) } catch (e) { e + 1 } }; g(3)")
+    );
+  }
+
+  #[test]
+  pub fn test_fuel() {
+    let _ = env_logger::init();
+
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).build();
+      assert_eq!(Ok(Expr::Val(Val::Int(6))), interpreter.eval("fn f(x) { x * 2 }; f(3)"));
+      let needed = interpreter.fuel_used();
+      assert!(needed > 0);
+
+      // a value takes no steps
+      assert_eq!(Ok(Expr::Val(Val::Int(1))), interpreter.eval("1"));
+      assert_eq!(0, interpreter.fuel_used());
+
+      let mut interpreter = Interpreter::builder().engine(engine).fuel(needed).build();
+      assert_eq!(Ok(Expr::Val(Val::Int(6))), interpreter.eval("fn f(x) { x * 2 }; f(3)"));
+      assert_eq!(needed, interpreter.fuel_used());
+
+      let mut interpreter = Interpreter::builder().engine(engine).fuel(needed - 1).build();
+      assert_eq!(
+        Err(RuntimeError::TooManyIterations(needed - 1)),
+        interpreter.eval("fn f(x) { x * 2 }; f(3)")
+      );
+
+      // running out in the middle of a call leaves its scopes, and can't be caught
+      let mut interpreter = Interpreter::builder().engine(engine).fuel(10_000).build();
+      assert_eq!(
+        Err(RuntimeError::TooManyIterations(10_000)),
+        interpreter.eval("fn spin() { var local = 1; while (true) { local } }; try { spin() } catch (e) { 0 }")
+      );
+      assert_eq!(0, interpreter.state.depth());
+      assert_eq!(Err(RuntimeError::VariableNotFound(String::from("local"))), interpreter.eval("local"));
+
+      // each eval gets the full amount
+      assert_eq!(Ok(Expr::Val(Val::Int(2))), interpreter.eval("1 + 1"));
+    }
+  }
+
+  #[test]
+  pub fn test_timeout() {
+    use std::time::{Duration, Instant};
+
+    let _ = env_logger::init();
+
+    for &engine in ENGINES.iter() {
+      let timeout = Duration::from_millis(50);
+      let mut interpreter = Interpreter::builder().engine(engine).timeout(timeout).build();
+
+      let started = Instant::now();
+      assert_eq!(Err(RuntimeError::Timeout(timeout)), interpreter.eval("while (true) { 1 }"));
+      assert!(started.elapsed() >= timeout);
+
+      // the timeout applies to each eval separately
+      assert_eq!(Ok(Expr::Val(Val::Int(2))), interpreter.eval("1 + 1"));
+    }
+  }
+
+  #[test]
+  pub fn test_max_depth() {
+    let _ = env_logger::init();
+
+    let program = "fn f(n) { n == 0 ? 0 : 1 + f(n - 1) }; ";
+
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).max_depth(20).build();
+      assert_eq!(Ok(Expr::Val(Val::Int(19))), interpreter.eval(&format!("{}f(19)", program)));
+      assert_eq!(Err(RuntimeError::StackOverflow(20)), interpreter.eval("f(20)"));
+
+      // like the other limits it can't be caught, and leaves the calls it stopped
+      assert_eq!(Err(RuntimeError::StackOverflow(20)), interpreter.eval("try { f(10000) } catch (e) { 0 }"));
+      assert_eq!(0, interpreter.state.depth());
+
+      // tail calls don't nest
+      assert_eq!(
+        Ok(Expr::Val(Val::Int(0))),
+        interpreter.eval("fn g(n) { n == 0 ? 0 : g(n - 1) }; g(10000)")
+      );
+    }
+
+    // the default is the same on every engine, and leaves room for ordinary
+    // recursion
+    let sum = "fn sum(n) { n == 0 ? 0 : n + sum(n - 1) }; sum(100)";
+    assert_eq!(format!("{:?}", Ok::<Expr, RuntimeError>(Expr::Val(Val::Int(5050)))), deep_boxx(sum));
+
+    // and stops deeper recursion the same way, on the big step evaluator too
+    let overflowed = thread::Builder::new()
+      .stack_size(1 << 30)
+      .spawn(|| boxx("fn f() { 1 + f() }; f()") == Err(RuntimeError::StackOverflow(1000)))
+      .unwrap()
+      .join()
+      .unwrap();
+    assert!(overflowed);
+
+    // which a try can't catch
+    let overflowed = thread::Builder::new()
+      .stack_size(1 << 30)
+      .spawn(|| boxx("fn f() { 1 + f() }; try { f() } catch (e) { 0 }") == Err(RuntimeError::StackOverflow(1000)))
+      .unwrap()
+      .join()
+      .unwrap();
+    assert!(overflowed);
+  }
+
+  #[test]
+  pub fn test_cancellation() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let _ = env_logger::init();
+
+    for &engine in ENGINES.iter() {
+      let cancel = Arc::new(AtomicBool::new(false));
+      let mut interpreter = Interpreter::builder().engine(engine).cancel_flag(cancel.clone()).build();
+
+      let canceller = {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+          thread::sleep(Duration::from_millis(20));
+          cancel.store(true, Ordering::Relaxed);
+        })
+      };
+
+      assert_eq!(Err(RuntimeError::Cancelled), interpreter.eval("var i = 0; while (true) { i += 1 }"));
+      canceller.join().unwrap();
+
+      // the flag stays set until it is cleared
+      assert_eq!(Err(RuntimeError::Cancelled), interpreter.eval("1 + 1"));
+      cancel.store(false, Ordering::Relaxed);
+      assert_eq!(Ok(Expr::Val(Val::Int(2))), interpreter.eval("1 + 1"));
+    }
+  }
+
+  #[test]
+  pub fn test_misplaced_control_flow() {
+    let _ = env_logger::init();
+
+    let message = |input: &str| match boxx(input) {
+      Err(RuntimeError::ParserError(err)) => err.to_string(),
+      other => panic!("expected a parser error, found {:?}", other),
+    };
+
+    assert_eq!("'break' is only allowed inside a loop", message("break"));
+    assert_eq!("'continue' is only allowed inside a loop", message("if (true) { continue }"));
+    assert_eq!("'return' is only allowed inside a function", message("return 1"));
+    assert_eq!("'return' is only allowed inside a function", message("while (true) { return 1 }"));
+
+    // a function body starts outside of any loop
+    assert_eq!("'break' is only allowed inside a loop", message("while (true) { fn() { break } }; 1"));
+    assert_eq!("'break' is only allowed inside a loop", message("while (break) { 1 }; 1"));
+  }
+
+  #[test]
+  pub fn test_ranges() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Range(0, 10))), boxx("0..10"));
+    assert_eq!(Ok(Expr::Val(Val::Range(1, 4))), boxx("1..=3"));
+    assert_eq!(Ok(Expr::Val(Val::Range(0, 4))), boxx("let n = 5; 0..n - 1"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("len(2..5)"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("len(5..2)"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("0..3 == 0..=2"));
+    assert_eq!(Ok(Expr::Val(Val::Str("0..3".to_string()))), boxx("str(0..3)"));
+
+    assert_eq!(Err(RuntimeError::IntegerOverflow), boxx(&format!("0..={}", isize::MAX)));
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Float(2.0)))),
+      boxx("0..2.0")
+    );
+  }
+
+  #[test]
+  pub fn test_for_in() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(45))), boxx("var sum = 0; for (i in 0..10) { sum = sum + i }; sum"));
+    assert_eq!(Ok(Expr::Val(Val::Int(55))), boxx("var sum = 0; for (i in 1..=10) { sum = sum + i }; sum"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("var n = 0; for (i in 3..0) { n = n + 1 }; n"));
+    assert_eq!(Ok(Expr::Val(Val::Int(60))), boxx("var sum = 0; for (x in [10, 20, 30]) { sum = sum + x }; sum"));
+    assert_eq!(
+      Ok(Expr::Val(Val::Str("olleh".to_string()))),
+      boxx("var s = \"\"; for (c in \"hello\") { s = c + s }; s")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Str("\u{1f600}\u{e9}a".to_string()))),
+      boxx("var s = \"\"; for (c in \"a\u{e9}\u{1f600}\") { s = c + s }; s")
+    );
+
+    // the collection is evaluated once, before the first iteration
+    assert_eq!(
+      Ok(Expr::Val(Val::List(vec!(Val::Int(1), Val::Int(2), Val::Int(1), Val::Int(2))))),
+      boxx("var xs = [1, 2]; for (x in xs) { xs = xs + [x] }; xs")
+    );
+
+    // each iteration gets a fresh binding, which closures capture
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(12))),
+      boxx("
+        var fs = [];
+        for (i in 0..3) {
+          fs = fs + [fn() { i * 4 }]
+        };
+        fs[0]() + fs[1]() + fs[2]()
+      ")
+    );
+
+    // the loop variable and the body's declarations don't outlive the loop
+    assert_eq!(
+      Err(RuntimeError::VariableNotFound("i".to_string())),
+      boxx("for (i in 0..3) { var twice = i * 2; twice }; i")
+    );
+    assert_eq!(
+      Err(RuntimeError::VariableNotFound("twice".to_string())),
+      boxx("for (i in 0..3) { var twice = i * 2; twice }; twice")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var i = 3; for (i in 0..10) { i }; i"));
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("list".to_string(), Expr::Val(Val::Int(5)))),
+      boxx("for (x in 5) { x }; 0")
+    );
+  }
+
+  #[test]
+  pub fn test_for_in_control_flow() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(20))),
+      boxx("var sum = 0; for (i in 0..100) { if (i % 2 == 1) { continue }; if (i > 8) { break }; sum = sum + i }; sum")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(2))),
+      boxx("
+        fn index_of(xs, x) {
+          for (i in 0..len(xs)) {
+            if (xs[i] == x) { return i }
+          };
+          -1
+        };
+        index_of([5, 6, 7], 7)
+      ")
+    );
+
+    // leaving an iteration early also leaves its frame
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(111))),
+      boxx("
+        var x = 1;
+        fn f() {
+          var x = 10;
+          for (i in 0..5) {
+            for (j in 0..5) {
+              if (j == 1) { break };
+              if (i == 2) { return x + 100 }
+            }
+          };
+          0
+        };
+        f() + x
+      ")
+    );
+  }
+
+  #[test]
+  pub fn test_c_style_for() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(45))), boxx("var sum = 0; for (var i = 0; i < 10; i = i + 1) { sum = sum + i }; sum"));
+    assert_eq!(Ok(Expr::Val(Val::Int(10))), boxx("var i = 0; for (i = 0; i < 10; i = i + 1) { 0 }; i"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("var i = 0; for (; i < 4;) { i = i + 1 }; i"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("var n = 0; for (var i = 5; i < 3; i = i + 1) { n = n + 1 }; n"));
+
+    // continue still runs the update, break skips it
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(5))),
+      boxx("var odd = 0; for (var i = 0; i < 10; i = i + 1) { if (i % 2 == 0) { continue }; odd = odd + 1 }; odd")
+    );
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(3))),
+      boxx("var i = 0; for (;; i = i + 1) { if (i == 3) { break } }; i")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidConstAssignment(Expr::Val(Val::Int(1)), "i".to_string())),
+      boxx("for (let i = 0; i < 3; i = i + 1) { 0 }; 0")
+    );
+  }
+
+  #[test]
+  pub fn test_undefined() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Undefined)), boxx("var x = 2;"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(8))),
+      boxx("
+        var x = 4;
+        var foo = fn(z) {
+          x = z + 2;
+        };
+        foo(x);
+        foo(x);
+        x
+      ")
+    );
+  }
+
+  #[test]
+  pub fn test_mut_var() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(555))), boxx("var x = 55; var y = 500; x + y"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(2))),
+      boxx("var x = 1; var y = 2; x = y; y = 3; x")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("var x = 1; x = 2; x"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(5))),
+      boxx("var x = 3; var y = 2; x = y; y = x; let z = 1; z + x + y")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(20))),
+      boxx("
+        var x = 4;
+        fn foo(z) {
+          x * z 
+        };
+        foo(x) + x
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(15))),
+      boxx("var x = 4; fn foo(z) { var x = 7; x + z }; foo(x) + x")
+    );
+
+    /*
+       TODO: allow var bindings so that fn params can be reassigned
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(23))),
+      boxx("var x = 4; fn foo(z) { var x = 7; z = x; x = 12; x + z }; foo(x) + x")
+    );
+    */
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("var i = 1; i = i + 1; i"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(13))),
+      boxx("var x = 10; var foo = fn(x) { var foo = fn (y) { var x = 3; y + x }; foo(x) }; foo(x) ")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var x = 3; x = fn() { 4 + 1 }; x()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var x = fn() { 4 + 1 }; x = 3; x"));
+  }
+
+
+  #[test]
+  pub fn test_if_else() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(999))),
+      boxx("var b = 1; if (true) { b = 999; }; b ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(34))),
+      boxx("if (true && false) { 32 } else if (!true && true) { 33 } else { 34 }")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(32))),
+      boxx("if (true || false) { 32 } else if (!true && true) { 33 } else { 34 }")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(30))),
+      boxx("if (true && false) { 32 } else { 30 }")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(52))),
+      boxx("if (let x = 4; x > 3) { 52 } else { 30 }")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(22))),
+      boxx("if (true) { 11 } else { 0 }; 22")
+    );
+  }
+
+  #[test]
+  pub fn test_operator_precedence() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("8 / 4 / 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("10 - 4 - 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("1 + 7 % 5"));
+    assert_eq!(Ok(Expr::Val(Val::Int(14))), boxx("2 + 3 * 4"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("1 + 2 == 3 && true"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("var x = 0; x == 0 || 10 / x > 1"));
+    assert_eq!(Ok(Expr::Val(Val::Int(-6))), boxx("-2 * 3"));
+  }
+
+  #[test]
+  pub fn test_short_circuit() {
+    let _ = env_logger::init();
+
+    let calls = "var calls = 0; fn f() { calls = calls + 1; true };";
+
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx(&format!("{} false && f(); calls", calls)));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx(&format!("{} true || f(); calls", calls)));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx(&format!("{} true && f(); calls", calls)));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx(&format!("{} false || f(); calls", calls)));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx(&format!("{} (f() || f()) && (false && f()); calls", calls)));
+
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("var x = 0; (x != 0) && (10 / x > 1)"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("var xs = []; (len(xs) == 0) || xs[0]"));
+
+    // only the operand that is evaluated is type checked
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("false && 1"));
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("true && 1")
+    );
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(Val::Int(1)))),
+      boxx(&format!("{} 1 || f()", calls))
+    );
+  }
+
+  #[test]
+  pub fn test_func() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(8))),
+      boxx("
+        var x = 4;
+        fn foo(z) {
+          x = z + 2;
+        };
+        foo(x);
+        foo(x);
+        x
+      ")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("let x = 4; fn foo() { let x = 1; x + 1 }; foo()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(6))), boxx("let x = 5; fn foo() { x + 1 }; foo()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(60))), boxx("fn foo() { 5 }; fn bar() { fn foo() { 6 }; foo() * 10 }; bar()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(50))), boxx("fn foo() { 5 }; fn bar() { foo() * 10 }; bar()"));
+
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn sum(a, b) { a + b }; sum(sum(3, 4), 5)"));
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn tx_two(a) { 2 * a }; tx_two(tx_two(3))"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(41))),
+      boxx("
+        fn foo(a) {
+          a < 40 ? foo(a + 3) : a
+        };
+
+        foo(20)
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(21))),
+      boxx("
+        fn fib(n) {
+          n == 0 ? 0 : (n == 1 ? 1 : fib(n - 1) + fib(n - 2))
+        };
+
+        fib(8)
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(21))),
+      boxx("
+        var fib = fn(n) {
+          n == 0 ? 0 : (n == 1 ? 1 : fib(n - 1) + fib(n - 2))
+        };
+
+        fib(8)
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(28))),
+      boxx("
+        fn foo(a) {
+          1 + a
+        };
+
+        fn bar(b) {
+          5 * b
+        };
+        
+        foo(bar(4)) + 7
+      ")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn b() { 5 + 5 }; let a = b; a() + 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("let b = fn() { 5 + 5 }; let a = b; a() + 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("fn foo(a) { 1 + a }; foo(4) + 7"));
+    assert_eq!(Ok(Expr::Val(Val::Int(12))), boxx("let foo = fn(a) { 1 + a }; foo(4) + 7"));
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("fn foo() { 1 + 1 }; foo()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("fn foo() { 1 + 3 }; foo() + 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(9))), boxx("fn foo() { 1 + 3 }; fn bar() { foo() + 1}; 4 + bar()"));
+
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("let foo = fn() { 1 + 1 }; foo()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("let foo = fn() { 1 + 3 }; foo() + 3"));
+    assert_eq!(Ok(Expr::Val(Val::Int(9))), boxx("let foo = fn() { 1 + 3 }; let bar = fn() { foo() + 1}; 4 + bar()"));
+
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("fn() { 1 + 3 }()"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("let foo = fn() { 1 + 3 }(); foo"));
+  }
+
+  #[test]
+  pub fn test_closures() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(7))),
+      boxx("
+        fn make_adder(n) {
+          fn(x) { x + n }
+        };
+
+        let add_five = make_adder(5);
+        add_five(2)
+      ")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("fn make_adder(n) { fn(x) { x + n } }; make_adder(5)(2)"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(3))),
+      boxx("
+        fn make_counter() {
+          var count = 0;
+          fn() {
+            count = count + 1;
+            count
+          }
+        };
+
+        let counter = make_counter();
+        counter();
+        counter();
+        counter()
+      ")
+    );
+
+    // each call gets its own captured frame
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(13))),
+      boxx("
+        fn make_counter() {
+          var count = 0;
+          fn() {
+            count = count + 1;
+            count
+          }
+        };
+
+        let a = make_counter();
+        let b = make_counter();
+        a();
+        a();
+        b() * 10 + a()
+      ")
+    );
+
+    // callbacks see their own definition scope, not the caller's
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(11))),
+      boxx("
+        let x = 1;
+        fn get_x() { x };
+        fn apply(f) {
+          let x = 10;
+          f() + x
+        };
+        apply(get_x)
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(15))),
+      boxx("
+        fn compose(f, g) { fn(x) { f(g(x)) } };
+        let inc = fn(x) { x + 1 };
+        let double = fn(x) { x * 2 };
+        compose(inc, double)(7)
+      ")
+    );
+  }
+
+  #[test]
+  pub fn test_closures_are_freed() {
+    use std::rc::Rc;
+
+    let _ = env_logger::init();
+
+    let programs = [
+      "fn f() { 1 }; f",
+      "var g = fn() { 1 }; g",
+      "fn outer() { var n = 1; fn inner() { n }; inner }; outer()",
+      "fn even(n) { n == 0 ? true : odd(n - 1) }; fn odd(n) { n == 0 ? false : even(n - 1) }; even",
+    ];
+
+    for &engine in ENGINES.iter() {
+      for program in programs.iter() {
+        let mut interpreter = Interpreter::builder().engine(engine).build();
+
+        // a function bound in the scope it closes over doesn't keep it alive,
+        // so its body goes once nothing else holds on to the scope
+        let body = match interpreter.eval(program) {
+          Ok(Expr::Val(Val::Func(_, body, _, _))) => Rc::downgrade(&body),
+          other => panic!("expected a function from {}, found {:?}", program, other),
+        };
+
+        drop(interpreter);
+        assert!(body.upgrade().is_none(), "{} should be freed", program);
+      }
+
+      // nor does having called it, once the program lets go of it
+      let mut interpreter = Interpreter::builder().engine(engine).build();
+      let body = match interpreter.eval("var g = fn() { 1 }; g(); g") {
+        Ok(Expr::Val(Val::Func(_, body, _, _))) => Rc::downgrade(&body),
+        other => panic!("expected a function, found {:?}", other),
+      };
+
+      assert_eq!(Ok(Expr::Val(Val::Int(0))), interpreter.eval("g = 0"));
+      assert!(body.upgrade().is_none(), "a called function should be freed on {:?}", engine);
+    }
+  }
+
+  #[test]
+  pub fn test_closure_shadowing() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(2))),
+      boxx("
+        let x = 1;
+        fn outer() {
+          let x = 2;
+          fn() { x }
+        };
+        outer()()
+      ")
+    );
+
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(5))),
+      boxx("
+        let x = 1;
+        fn outer(x) {
+          fn(x) { x }
+        };
+        outer(3)(5)
+      ")
+    );
+
+    // variables of the caller are not visible to the callee
+    assert_eq!(
+      Err(RuntimeError::VariableNotFound("y".to_string())),
+      boxx("
+        fn foo() { y };
+        fn bar() { let y = 1; foo() };
+        bar()
+      ")
+    );
+
+    // later bindings in the defining scope are visible to the closure
+    assert_eq!(
+      Ok(Expr::Val(Val::Int(4))),
+      boxx("
+        fn get_x() { x };
+        var x = 3;
+        x = 4;
+        get_x()
+      ")
+    );
+  }
+
+  #[test]
+  pub fn test_const_decl() {
+    let _ = env_logger::init();
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let x = 1 + 2; x"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("let x = 1; x"));
+    assert_eq!(Ok(Expr::Val(Val::Int(8))), boxx("let x = 5; let y = 3; let z = x + y; z"));
+
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let x = (1 > 2) ? 0 : 3; x"));
+
+    // using let keyword again re-binds value
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("let x = 2; let x = 3; x + 2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(52))), boxx("let underscore_name = 51; 1 + underscore_name"));
+  }
+
+  #[test]
+  pub fn test_ternary() {
+    let _ = env_logger::init();
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("true ? 1 : 0"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("false ? 1 : 0"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("(false ? 1 : 0); 1 + 2"));
     assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("false ? 1 : 0; 1 + 2"));
     assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("((1 + 1) > 3) ? 1 : 0"));
     assert_eq!(Ok(Expr::Val(Val::Int(14))), boxx("((1 + 1) > 3) ? true && false : 12 + 2"));
@@ -556,7 +1685,388 @@ mod tests {
   #[test]
   pub fn test_eval_div() {
     assert_eq!(Ok(Expr::Val(Val::Int(6))), boxx("12/2"));
-    //assert_eq!(Ok(Expr::Float(1.5)), boxx("3/2"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("3/2"));
+    assert_eq!(Ok(Expr::Val(Val::Float(1.5))), boxx("3.0/2.0"));
+  }
+
+  #[test]
+  pub fn test_floats() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Float(1.5))), boxx("1.5"));
+    assert_eq!(Ok(Expr::Val(Val::Float(2e10))), boxx("2e10"));
+    assert_eq!(Ok(Expr::Val(Val::Float(0.025))), boxx("2.5e-2"));
+    assert_eq!(Ok(Expr::Val(Val::Float(150.0))), boxx("1.5E+2"));
+    assert_eq!(Ok(Expr::Val(Val::Float(-0.5))), boxx("-0.5"));
+
+    assert_eq!(Ok(Expr::Val(Val::Float(4.0))), boxx("1.5 + 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Float(-1.0))), boxx("1.5 - 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Float(3.75))), boxx("1.5 * 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Float(0.5))), boxx("1.25 / 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Float(1.5))), boxx("5.5 % 2.0"));
+    assert_eq!(Ok(Expr::Val(Val::Float(0.5))), boxx("-5.5 % 2.0"));
+    assert_eq!(Ok(Expr::Val(Val::Float(-0.5))), boxx("5.5 % -2.0"));
+    assert_eq!(Ok(Expr::Val(Val::Float(5.5))), boxx("5.5 % (1.0 / 0.0)"));
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::INFINITY))), boxx("-5.5 % (1.0 / 0.0)"));
+
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("1.5 < 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("1.5 >= 2.5"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("0.5 == 1.0 / 2.0"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("0.5 != 1.5"));
+
+    assert_eq!(Ok(Expr::Val(Val::Float(3.0))), boxx("fn half(x) { x / 2.0 }; half(6.0)"));
+  }
+
+  #[test]
+  pub fn test_float_nan_and_infinity() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::INFINITY))), boxx("1.0 / 0.0"));
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::NEG_INFINITY))), boxx("-1.0 / 0.0"));
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::INFINITY))), boxx("1e308 * 10.0"));
+
+    match boxx("0.0 / 0.0") {
+      Ok(Expr::Val(Val::Float(n))) => assert!(n.is_nan()),
+      other => panic!("expected NaN, found {:?}", other),
+    }
+
+    // NaN is not equal to anything, including itself
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("let nan = 0.0 / 0.0; nan == nan"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("let nan = 0.0 / 0.0; nan != nan"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("let nan = 0.0 / 0.0; nan < 1.0"));
+  }
+
+  #[test]
+  pub fn test_float_display() {
+    assert_eq!("1.0", format!("{}", Val::Float(1.0)));
+    assert_eq!("1.5", format!("{}", Val::Float(1.5)));
+    assert_eq!("-0.25", format!("{}", Val::Float(-0.25)));
+
+    assert_eq!("inf", format!("{}", Val::Float(f64::INFINITY)));
+    assert_eq!("-inf", format!("{}", Val::Float(f64::NEG_INFINITY)));
+    assert_eq!("NaN", format!("{}", Val::Float(f64::NAN)));
+
+    // but written back as source, they are the source that makes them,
+    // rather than names that would read back as variables
+    let source = |n: f64| Expr::Val(Val::Float(n)).to_string();
+    assert_eq!("1e999", source(f64::INFINITY));
+    assert_eq!("-1e999", source(f64::NEG_INFINITY));
+    assert_eq!("(0.0 / 0.0)", source(f64::NAN));
+
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::INFINITY))), boxx(&source(f64::INFINITY)));
+    assert_eq!(Ok(Expr::Val(Val::Float(f64::NEG_INFINITY))), boxx(&source(f64::NEG_INFINITY)));
+    match boxx(&source(f64::NAN)) {
+      Ok(Expr::Val(Val::Float(n))) if n.is_nan() => {}
+      other => panic!("expected NaN, found {:?}", other),
+    }
+
+    let pow = Expr::Bop(
+      BinOp::Pow,
+      Box::new(Expr::Val(Val::Float(f64::NEG_INFINITY))),
+      Box::new(Expr::Val(Val::List(vec![Val::Float(f64::NAN)]))),
+    );
+    assert_eq!("(-1e999) ** [(0.0 / 0.0)]", pow.to_string());
+  }
+
+  #[test]
+  pub fn test_strings() {
+    let _ = env_logger::init();
+
+    fn s(v: &str) -> Result<Expr, RuntimeError> {
+      Ok(Expr::Val(Val::Str(v.to_string())))
+    }
+
+    assert_eq!(s("hello"), boxx("\"hello\""));
+    assert_eq!(s(""), boxx("\"\""));
+    assert_eq!(s("hello world"), boxx("\"hello\" + \" \" + \"world\""));
+    assert_eq!(s("ab"), boxx("let a = \"a\"; var b = a + \"b\"; b"));
+    assert_eq!(s("héllo wörld"), boxx("\"héllo\" + \" wörld\""));
+
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("\"abc\" == \"abc\""));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("\"abc\" != \"abd\""));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("\"abc\" < \"abd\""));
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("\"b\" <= \"abc\""));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("\"b\" > \"abc\""));
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("\"1\" == 1"));
+
+    assert_eq!(
+      s("hello, boxx"),
+      boxx("
+        fn greet(name) { \"hello, \" + name };
+        greet(\"boxx\")
+      ")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("str".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("\"a\" + 1")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Str("a".to_string())))),
+      boxx("1 + \"a\"")
+    );
+  }
+
+  #[test]
+  pub fn test_string_escapes() {
+    let _ = env_logger::init();
+
+    fn s(v: &str) -> Result<Expr, RuntimeError> {
+      Ok(Expr::Val(Val::Str(v.to_string())))
+    }
+
+    assert_eq!(s("a\nb"), boxx(r#""a\nb""#));
+    assert_eq!(s("a\tb"), boxx(r#""a\tb""#));
+    assert_eq!(s("say \"hi\""), boxx(r#""say \"hi\"""#));
+    assert_eq!(s("back\\slash"), boxx(r#""back\\slash""#));
+    assert_eq!(s("A\u{e9}\u{1F600}"), boxx(r#""\u{41}\u{e9}\u{1F600}""#));
+    assert_eq!(s("// not a comment"), boxx(r#""// not a comment""#));
+
+    assert!(boxx(r#""unterminated"#).is_err());
+    assert!(boxx(r#""bad \q escape""#).is_err());
+    assert!(boxx(r#""\u{110000}""#).is_err());
+    assert!(boxx(r#""\u41""#).is_err());
+  }
+
+  #[test]
+  pub fn test_string_builtins() {
+    let _ = env_logger::init();
+
+    fn s(v: &str) -> Result<Expr, RuntimeError> {
+      Ok(Expr::Val(Val::Str(v.to_string())))
+    }
+
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("len(\"hello\")"));
+    assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("len(\"\")"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("len(\"héé!\")"));
+
+    assert_eq!(s("h"), boxx("\"hello\"[0]"));
+    assert_eq!(s("o"), boxx("let s = \"hello\"; s[len(s) - 1]"));
+    assert_eq!(s("é"), boxx("\"héllo\"[1]"));
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(5, 5)), boxx("\"hello\"[5]"));
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(-1, 5)), boxx("\"hello\"[-1]"));
+
+    assert_eq!(s("ell"), boxx("slice(\"hello\", 1, 4)"));
+    assert_eq!(s("hello"), boxx("slice(\"hello\", 0, 5)"));
+    assert_eq!(s(""), boxx("slice(\"hello\", 3, 2)"));
+    assert_eq!(s("ll"), boxx("slice(\"héllo\", 2, 4)"));
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(6, 5)), boxx("slice(\"hello\", 1, 6)"));
+
+    assert_eq!(s("42"), boxx("str(42)"));
+    assert_eq!(s("1.5"), boxx("str(1.5)"));
+    assert_eq!(s("n = 7"), boxx("\"n = \" + str(3 + 4)"));
+
+    assert_eq!(
+      Err(RuntimeError::ArityMismatch("len".to_string(), 1, 2)),
+      boxx("len(\"a\", \"b\")")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("str, list or range".to_string(), Expr::Val(Val::Int(3)))),
+      boxx("len(3)")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("str or list".to_string(), Expr::Val(Val::Int(3)))),
+      boxx("slice(3, 0, 1)")
+    );
+
+    // builtins can be shadowed
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("fn len(x) { 3 }; len(\"hello\")"));
+  }
+
+  #[test]
+  pub fn test_lists() {
+    let _ = env_logger::init();
+
+    fn ints(ns: &[isize]) -> Result<Expr, RuntimeError> {
+      Ok(Expr::Val(Val::List(ns.iter().map(|n| Val::Int(*n)).collect())))
+    }
+
+    assert_eq!(ints(&[]), boxx("[]"));
+    assert_eq!(ints(&[1, 2, 3]), boxx("[1, 2, 3]"));
+    assert_eq!(ints(&[2, 6, 5]), boxx("let x = 2; [x, x * 3, fn() { 5 }()]"));
+    assert_eq!(ints(&[1, 2, 3, 4]), boxx("[1, 2] + [3, 4]"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::List(vec!(Val::List(vec!(Val::Int(1))), Val::Str("a".to_string()), Val::Bool(true))))),
+      boxx("[[1], \"a\", true]")
+    );
+
+    assert_eq!(Ok(Expr::Val(Val::Int(20))), boxx("let xs = [10, 20, 30]; xs[1]"));
+    assert_eq!(Ok(Expr::Val(Val::Int(30))), boxx("let xs = [10, 20, 30]; xs[len(xs) - 1]"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("[[1, 2], [3, 4]][1][1]"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("fn xs() { [1, 2, 3] }; xs()[2]"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("len([1, 2, 3])"));
+    assert_eq!(ints(&[2, 3]), boxx("slice([1, 2, 3, 4], 1, 3)"));
+
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("[1, [2]] == [1, [2]]"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("[1, 2] != [2, 1]"));
+
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(3, 3)), boxx("[1, 2, 3][3]"));
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(-1, 3)), boxx("[1, 2, 3][-1]"));
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(0, 0)), boxx("[][0]"));
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Bool(true)))),
+      boxx("[1, 2][true]")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("list or str".to_string(), Expr::Val(Val::Int(5)))),
+      boxx("let n = 5; n[0]")
+    );
+  }
+
+  #[test]
+  pub fn test_list_assignment() {
+    let _ = env_logger::init();
+
+    fn ints(ns: &[isize]) -> Result<Expr, RuntimeError> {
+      Ok(Expr::Val(Val::List(ns.iter().map(|n| Val::Int(*n)).collect())))
+    }
+
+    assert_eq!(ints(&[1, 5, 3]), boxx("var xs = [1, 2, 3]; xs[1] = 5; xs"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var xs = [1, 2, 3]; xs[1] = 5"));
+    assert_eq!(ints(&[3, 2, 3]), boxx("var xs = [1, 2, 3]; var i = 0; xs[i] = xs[i + 2]; xs"));
+
+    assert_eq!(
+      Ok(Expr::Val(Val::List(vec!(Val::List(vec!(Val::Int(1), Val::Int(9))), Val::List(vec!(Val::Int(3))))))),
+      boxx("var m = [[1, 2], [3]]; m[0][1] = 9; m")
+    );
+
+    assert_eq!(
+      ints(&[0, 1, 4, 9]),
+      boxx("
+        var squares = [0, 0, 0, 0];
+        var i = 0;
+        while (i < len(squares)) {
+          squares[i] = i * i;
+          i = i + 1
+        };
+        squares
+      ")
+    );
+
+    // lists are values: assignment copies them
+    assert_eq!(ints(&[1, 2]), boxx("var a = [1, 2]; var b = a; b[0] = 7; a"));
+
+    // the index is evaluated before the assigned value
+    assert_eq!(
+      ints(&[10, 2]),
+      boxx("
+        var xs = [1, 2];
+        var i = 0;
+        fn next() { i = i + 1; i };
+        xs[i] = next() * 10;
+        xs
+      ")
+    );
+
+    assert_eq!(Err(RuntimeError::IndexOutOfBounds(2, 2)), boxx("var xs = [1, 2]; xs[2] = 3"));
+
+    assert_eq!(
+      Err(RuntimeError::InvalidConstAssignment(
+        Expr::Val(Val::List(vec!(Val::Int(3), Val::Int(2)))),
+        "xs".to_string()
+      )),
+      boxx("let xs = [1, 2]; xs[0] = 3")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("list".to_string(), Expr::Val(Val::Str("ab".to_string())))),
+      boxx("var s = \"ab\"; s[0] = \"c\"")
+    );
+  }
+
+  #[test]
+  pub fn test_records() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("let r = { name: 1, age: 2 }; r.name"));
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("{ name: 1, age: 2 }.age"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let r = { \"a b\": 1, c: 2 }; len([r.c, r.c, r.c])"));
+    assert_eq!(Ok(Expr::Val(Val::Int(7))), boxx("let r = { p: { x: 3, y: 4 } }; r.p.x + r.p.y"));
+    assert_eq!(Ok(Expr::Val(Val::Int(6))), boxx("let x = 2; let r = { double: fn(n) { n * x } }; r.double(3)"));
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("let rs = [{ v: 1 }, { v: 2 }]; rs[1].v"));
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("{}; 5"));
+
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("{ a: 1, b: [2] } == { b: [2], a: 1 }"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("{ a: 1 } == { a: 1, b: 2 }"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("{ a: 1 } != { a: 2 }"));
+    assert_eq!(Ok(Expr::Val(Val::Bool(true))), boxx("{} == {}"));
+
+    // blocks still work where a record isn't possible
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("let a = true; if (a) { a ? 3 : 4 } else { 5 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("let x = 1; { x }"));
+    assert_eq!(Ok(Expr::Val(Val::Undefined)), boxx("if (true) {} else { 1 }"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("var i = 0; while (i < 3) { i += 1 }; for (x in [1, 2]) {}; i"));
+    assert_eq!(Ok(Expr::Val(Val::Int(3))), boxx("true ? 3 else 4"));
+
+    assert_eq!(Err(RuntimeError::FieldNotFound("age".to_string())), boxx("let r = { name: 1 }; r.age"));
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("record".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("let r = 1; r.name")
+    );
+  }
+
+  #[test]
+  pub fn test_record_assignment() {
+    let _ = env_logger::init();
+
+    assert_eq!(Ok(Expr::Val(Val::Int(5))), boxx("var r = { a: 1, b: 2 }; r.a = 5; r.a"));
+    assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("var r = { a: 1, b: 2 }; r.a = 5; r.b"));
+    assert_eq!(Ok(Expr::Val(Val::Int(9))), boxx("var r = { p: { x: 1 } }; r.p.x = 9; r.p.x"));
+    assert_eq!(Ok(Expr::Val(Val::Int(8))), boxx("var r = { xs: [1, 2] }; r.xs[1] = 8; r.xs[1]"));
+    assert_eq!(Ok(Expr::Val(Val::Int(4))), boxx("var rs = [{ v: 1 }]; rs[0].v = 4; rs[0].v"));
+
+    // records are values: assignment copies them
+    assert_eq!(Ok(Expr::Val(Val::Int(1))), boxx("var a = { v: 1 }; var b = a; b.v = 2; a.v"));
+
+    assert_eq!(Err(RuntimeError::FieldNotFound("c".to_string())), boxx("var r = { a: 1 }; r.c = 1"));
+
+    match boxx("let r = { a: 1 }; r.a = 2") {
+      Err(ref err) => match *err.kind() {
+        RuntimeError::InvalidConstAssignment(_, ref x) => assert_eq!("r", x),
+        ref other => panic!("expected a const assignment error, found {:?}", other),
+      },
+      other => panic!("expected a const assignment error, found {:?}", other),
+    }
+  }
+
+  #[test]
+  pub fn test_no_type_coercion() {
+    let _ = env_logger::init();
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Bool(false)))),
+      boxx("1 + false")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("int".to_string(), Expr::Val(Val::Float(1.5)))),
+      boxx("1 + 1.5")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("float".to_string(), Expr::Val(Val::Int(2)))),
+      boxx("1.5 * 2")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("float".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("1.5 < 1")
+    );
+
+    assert_eq!(
+      Err(RuntimeError::InvalidTypeConversion("bool".to_string(), Expr::Val(Val::Int(1)))),
+      boxx("true && 1")
+    );
+
+    // equality never fails, values of different types are simply unequal
+    assert_eq!(Ok(Expr::Val(Val::Bool(false))), boxx("1 == 1.0"));
   }
 
   #[test]
@@ -579,4 +2089,183 @@ mod tests {
     assert_eq!(Ok(Expr::Val(Val::Int(2))), boxx("(1-1)+(2-2)+(3-3)+((1+2)-((3-2)+1)+1)"));
     assert_eq!(Ok(Expr::Val(Val::Int(0))), boxx("((((((((((1-1)))+1))))-1)))"));
   }
+
+  #[test]
+  pub fn test_error_locations() {
+    let _ = env_logger::init();
+
+    let span = |input: &str| boxx(input).unwrap_err().span().map(|s| (s.line, s.col, s.end - s.start));
+
+    // the innermost expression that failed
+    assert_eq!(Some((2, 5, 1)), span("var x = 1;\nx + y"));
+    assert_eq!(Some((1, 12, 8)), span("print(1 + (true + 1))"));
+    assert_eq!(Some((3, 3, 5)), span("let xs = [1];\n{\n  xs[3]\n}"));
+
+    // parser and lexer errors point at the offending token
+    assert_eq!(Some((1, 7, 1)), span("(1 + 2;"));
+    assert_eq!(Some((2, 1, 1)), span("1 +\n#"));
+    assert_eq!(Some((1, 5, 5)), span("1 + \"oops"));
+
+    // columns count characters rather than bytes
+    assert_eq!(Some((1, 8, 1)), span("\"\u{f6}\u{f6}\" + y"));
+
+    // locations don't affect which error it is
+    assert_eq!(Err(RuntimeError::VariableNotFound("y".to_string())), boxx("var x = 1;\nx + y"));
+  }
+
+  #[test]
+  pub fn test_error_rendering() {
+    let _ = env_logger::init();
+
+    let source = "var i = 0;\nwhile (i < 3) {\n  i = i + \"1\"\n};\ni";
+
+    assert_eq!(
+      "loop.bx:3:7: Invalid type conversion. Expected int and found \"1\"\n  |\n3 |   i = i + \"1\"\n  |       ^^^^^^^",
+      boxx(source).unwrap_err().render("loop.bx", source)
+    );
+
+    // the expressions in messages are printed as source
+    let message = |input: &str| boxx(input).unwrap_err().to_string();
+
+    assert_eq!("Division by zero: 7 % 0", message("7 % 0"));
+    assert_eq!("Negative exponent for an integer: (-2) ** -1", message("(-2) ** (0 - 1)"));
+    assert_eq!("Cannot assign [1, \"a\"] to const x", message("let x = 1; x = [1, \"a\"]"));
+    assert_eq!(
+      "Invalid type conversion. Expected int and found fn foo(x) { x + 1 }",
+      message("fn foo(x) { x + 1 }; foo + 4")
+    );
+    assert_eq!("No match arm matches { a: undefined }", message("match { a: undefined } { [] => 1 }"));
+  }
+
+  #[test]
+  pub fn test_print_values() {
+    let _ = env_logger::init();
+
+    let string = |input: &str| match boxx(input) {
+      Ok(Expr::Val(v)) => v.to_string(),
+      other => panic!("expected a value, found {:?}", other),
+    };
+
+    // functions print as their source, without what they close over
+    assert_eq!("fn add(a, b) { a + b }", string("fn add(a, b) { a + b }; add"));
+    assert_eq!("fn(x) { x ** y }", string("var y = 2; fn(x) { x ** y }"));
+    assert_eq!("[len, undefined]", string("[len, undefined]"));
+    assert_eq!("fn f() { for (var i = 0; i < 3; i += 1) { print i } }", string("fn f() { for (var i = 0; i < 3; i += 1) { print i } }; str(f)"));
+
+    // the REPL prints results as source, so strings are quoted
+    assert_eq!("[\"a\", 1..3]", format!("{}", boxx("[\"a\", 1..3]").unwrap()));
+    assert_eq!("undefined", format!("{}", boxx("var x = undefined; x").unwrap()));
+  }
+
+  #[test]
+  pub fn test_backtraces() {
+    let _ = env_logger::init();
+
+    let trace = |input: &str| -> Vec<(Option<String>, usize, usize)> {
+      boxx(input).unwrap_err().backtrace().iter().map(|frame| {
+        let call = frame.call.unwrap();
+        (frame.function.clone(), call.line, call.col)
+      }).collect()
+    };
+    let named = |name: &str| Some(name.to_string());
+
+    // the innermost call first, with where each one was made
+    assert_eq!(
+      vec![(named("g"), 2, 10), (named("f"), 3, 1)],
+      trace("fn g() { 1 / 0 };\nfn f() { g() + 1 };\nf()")
+    );
+    assert_eq!(
+      vec![(None, 2, 10), (named("f"), 3, 1)],
+      trace("var h = fn() { missing };\nfn f() { h() + 1 };\nf()")
+    );
+    assert_eq!(vec![(None, 1, 1)], trace("fn(x) { x + true }(1)"));
+
+    // errors outside of any function have none
+    assert!(boxx("1 / 0").unwrap_err().backtrace().is_empty());
+
+    // a tail call replaces the call it was made from, which is only counted
+    assert_eq!(vec![(named("g"), 2, 10)], trace("fn g() { 1 / 0 };\nfn f() { g() };\nf()"));
+
+    let tail_calls = |input: &str| -> Vec<usize> {
+      boxx(input).unwrap_err().backtrace().iter().map(|frame| frame.tail_calls).collect()
+    };
+    assert_eq!(vec![1], tail_calls("fn g(x) { x + y }; fn h() { g(1) }; h()"));
+    assert_eq!(
+      vec![3, 0],
+      tail_calls("fn g(n) { n == 0 ? 1 / 0 : g(n - 1) }; fn h() { 1 + g(3) }; h()")
+    );
+    assert_eq!(vec![0, 0], tail_calls("fn g() { 1 / 0 }; fn h() { g() + 1 }; h()"));
+
+    let source = "fn g(x) { x + y };\nfn h() { g(1) };\nh()";
+
+    assert_eq!(
+      "Variable \"y\" does not exist in memory\n  in g, called at 2:10\n  ... 1 tail call",
+      boxx(source).unwrap_err().to_string()
+    );
+    assert_eq!(
+      "calls.bx:1:15: Variable \"y\" does not exist in memory\n  |\n1 | fn g(x) { x + y };\n  |               ^\n  in g, called at calls.bx:2:10\n  ... 1 tail call",
+      boxx(source).unwrap_err().render("calls.bx", source)
+    );
+
+    // a caught error's backtrace goes with it, and throwing again starts anew
+    assert_eq!(
+      vec![(named("g"), 3, 1)],
+      trace("fn f() { throw 1 };\nfn g() { try { f() } catch (e) { throw e + 1 } };\ng()")
+    );
+
+    // errors from the limits on an eval are traced too
+    for &engine in ENGINES.iter() {
+      let mut interpreter = Interpreter::builder().engine(engine).fuel(1000).build();
+      let err = interpreter.eval("fn spin() { while (true) { 1 }; 0 };\nfn f() { spin() + 1 };\nf()").unwrap_err();
+
+      let functions: Vec<Option<String>> = err.backtrace().iter().map(|frame| frame.function.clone()).collect();
+      assert_eq!(vec![named("spin"), named("f")], functions);
+    }
+
+    let source = "fn f(x) {\n  x + y\n};\nf(1)";
+
+    assert_eq!(
+      "calls.bx:2:7: Variable \"y\" does not exist in memory\n  |\n2 |   x + y\n  |       ^\n  in f, called at calls.bx:4:1",
+      boxx(source).unwrap_err().render("calls.bx", source)
+    );
+    assert_eq!(
+      "Variable \"y\" does not exist in memory\n  in f, called at 4:1",
+      boxx(source).unwrap_err().to_string()
+    );
+  }
+
+  #[test]
+  pub fn test_long_backtraces() {
+    let _ = env_logger::init();
+
+    // the big step evaluator needs more stack than a test thread has for
+    // this many calls in a debug build
+    let run = || {
+      // the calls of a recursive function are shown once and counted
+      let source = "fn f(n) { n == 0 ? missing : 1 + f(n - 1) };\nf(30)";
+
+      assert_eq!(31, boxx(source).unwrap_err().backtrace().len());
+      assert_eq!(
+        "Variable \"missing\" does not exist in memory\n  in f, called at 1:34\n  ... repeated 29 more times\n  in f, called at 2:1",
+        boxx(source).unwrap_err().to_string()
+      );
+      assert_eq!(
+        "calls.bx:1:20: Variable \"missing\" does not exist in memory\n  |\n1 | fn f(n) { n == 0 ? missing : 1 + f(n - 1) };\n  |                    ^^^^^^^\n  in f, called at calls.bx:1:34\n  ... repeated 29 more times\n  in f, called at calls.bx:2:1",
+        boxx(source).unwrap_err().render("calls.bx", source)
+      );
+
+      // and past the first twenty frames shown, the rest are only counted
+      let source = "fn even(n) { n == 0 ? missing : 1 + odd(n - 1) };\nfn odd(n) { 1 + even(n - 1) };\neven(30)";
+      let err = boxx(source).unwrap_err();
+
+      assert_eq!(31, err.backtrace().len());
+      for shown in [err.to_string(), err.render("calls.bx", source)].iter() {
+        let lines: Vec<&str> = shown.lines().filter(|line| line.starts_with("  in ")).collect();
+        assert_eq!(20, lines.len());
+        assert!(shown.ends_with("\n  ... 11 more frames"), "{}", shown);
+      }
+    };
+
+    thread::Builder::new().stack_size(1 << 30).spawn(run).unwrap().join().unwrap();
+  }
 }
@@ -3,6 +3,9 @@ extern crate boxx;
 #[cfg(test)]
 mod test {
   use boxx::parser::parser::{parse};
+  use boxx::parser::lexer::Lexer;
+  use boxx::parser::lexer_error::LexerError;
+  use boxx::parser::token::Token;
   use boxx::expr::{Val, Expr, BinOp};
   extern crate env_logger;
 
@@ -121,4 +124,459 @@ mod test {
       parse("(4+7)-3").unwrap()
     );
   }
+
+  #[test]
+  fn test_record_literals() {
+    assert_eq!(Expr::Record(vec!()), parse("{}").unwrap());
+
+    assert_eq!(
+      Expr::Record(vec!(
+        (String::from("name"), Expr::Val(Val::Int(1))),
+        (String::from("age"), Expr::Bop(
+          BinOp::Plus,
+          Box::new(Expr::Var(String::from("x"))),
+          Box::new(Expr::Val(Val::Int(2))),
+        )),
+      )),
+      parse("{ name: 1, age: x + 2 }").unwrap()
+    );
+
+    assert_eq!(
+      Expr::Record(vec!((String::from("a b"), Expr::Val(Val::Bool(true))))),
+      parse("{ \"a b\": true }").unwrap()
+    );
+
+    assert_eq!(
+      Expr::Field(
+        Box::new(Expr::Field(
+          Box::new(Expr::Var(String::from("r"))),
+          String::from("p"),
+        )),
+        String::from("x"),
+      ),
+      parse("r.p.x").unwrap()
+    );
+
+    assert_eq!(
+      Expr::Field(
+        Box::new(Expr::Record(vec!((String::from("a"), Expr::Val(Val::Int(1)))))),
+        String::from("a"),
+      ),
+      parse("{ a: 1 }.a").unwrap()
+    );
+  }
+
+  #[test]
+  fn test_blocks_are_not_records() {
+    // a block is anything that isn't `{}` or `{ key: ...`
+    assert_eq!(Expr::Var(String::from("x")), parse("{ x }").unwrap());
+
+    assert_eq!(
+      Expr::Ternary(
+        Box::new(Expr::Var(String::from("a"))),
+        Box::new(Expr::Val(Val::Int(1))),
+        Box::new(Expr::Val(Val::Int(2))),
+      ),
+      parse("{ a ? 1 : 2 }").unwrap()
+    );
+
+    assert_eq!(
+      Expr::Bop(
+        BinOp::Seq,
+        Box::new(Expr::Var(String::from("a"))),
+        Box::new(Expr::Var(String::from("b"))),
+      ),
+      parse("{ a; b }").unwrap()
+    );
+
+    assert_eq!(
+      Expr::Bop(
+        BinOp::Assign,
+        Box::new(Expr::Field(Box::new(Expr::Var(String::from("r"))), String::from("a"))),
+        Box::new(Expr::Val(Val::Int(1))),
+      ),
+      parse("{ r.a = 1 }").unwrap()
+    );
+
+    // `{}` as a body is an empty block
+    parses_like("if (a) {} else {}", "a ? undefined : undefined");
+    parses_like("while (a) {}; b", "while (a) undefined; b");
+    parses_like("for (x in xs) {}", "for (x in xs) undefined");
+    parses_like("if (a) { {} }", "a ? {} : undefined");
+  }
+
+  #[test]
+  fn test_ternary_else() {
+    parses_like("a ? b else c", "a ? b : c");
+    parses_like("a ? b else c ? d else e", "a ? b : (c ? d : e)");
+  }
+
+  // binary operators from loosest to tightest; operators on the same level
+  // are left associative, except for `**`
+  const PRECEDENCE: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["..", "..="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+    &["**"],
+  ];
+
+  fn parses_like(input: &str, expected: &str) {
+    assert_eq!(parse(expected).unwrap(), parse(input).unwrap(), "{} should parse as {}", input, expected);
+  }
+
+  #[test]
+  fn test_precedence_table() {
+    for (i, ops1) in PRECEDENCE.iter().enumerate() {
+      for (j, ops2) in PRECEDENCE.iter().enumerate() {
+        for op1 in ops1.iter() {
+          for op2 in ops2.iter() {
+            let input = format!("a {} b {} c", op1, op2);
+
+            if j > i || (i == j && *op1 == "**") {
+              parses_like(&input, &format!("a {} (b {} c)", op1, op2));
+            } else {
+              parses_like(&input, &format!("(a {} b) {} c", op1, op2));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_associativity() {
+    assert_eq!(
+      Expr::Bop(
+        BinOp::Div,
+        Box::new(Expr::Bop(
+          BinOp::Div,
+          Box::new(Expr::Val(Val::Int(8))),
+          Box::new(Expr::Val(Val::Int(4))),
+        )),
+        Box::new(Expr::Val(Val::Int(2))),
+      ),
+      parse("8 / 4 / 2").unwrap()
+    );
+
+    parses_like("a - b + c - d", "((a - b) + c) - d");
+    parses_like("a * b / c % d", "((a * b) / c) % d");
+    parses_like("a == b != c", "(a == b) != c");
+    parses_like("a && b && c || d || e", "((a && b) && c) || d || e");
+    parses_like("a ** b ** c ** d", "a ** (b ** (c ** d))");
+  }
+
+  #[test]
+  fn test_mixed_precedence() {
+    assert_eq!(
+      Expr::Bop(
+        BinOp::And,
+        Box::new(Expr::Bop(
+          BinOp::Eq,
+          Box::new(Expr::Bop(
+            BinOp::Plus,
+            Box::new(Expr::Val(Val::Int(1))),
+            Box::new(Expr::Val(Val::Int(2))),
+          )),
+          Box::new(Expr::Val(Val::Int(3))),
+        )),
+        Box::new(Expr::Val(Val::Bool(true))),
+      ),
+      parse("1 + 2 == 3 && true").unwrap()
+    );
+
+    parses_like("a + b % c", "a + (b % c)");
+    parses_like("a + b * c - d / e", "(a + (b * c)) - (d / e)");
+    parses_like("a < b == c > d", "(a < b) == (c > d)");
+    parses_like("a || b && c == d + e * f", "a || (b && (c == (d + (e * f))))");
+    parses_like("a * b + c * d < e && f || g", "((((a * b) + (c * d)) < e) && f) || g");
+  }
+
+  #[test]
+  fn test_prefix_and_statement_precedence() {
+    // prefix operators bind tighter than any binary operator but `**`
+    parses_like("-a * b", "(-a) * b");
+    parses_like("!a && b", "(!a) && b");
+    parses_like("a - -b", "a - (-b)");
+    parses_like("!a == b", "(!a) == b");
+    parses_like("-a ** b", "-(a ** b)");
+    parses_like("-a ** b * c", "(-(a ** b)) * c");
+    parses_like("a ** -b ** c", "a ** (-(b ** c))");
+    parses_like("~a & b", "(~a) & b");
+    parses_like("~a ** b", "~(a ** b)");
+    parses_like("a | ~b << c", "a | ((~b) << c)");
+
+    // postfix operators tighter still
+    parses_like("-f(a)[0]", "-((f(a))[0])");
+
+    // ternaries and assignment bind loosest
+    parses_like("a || b ? c + d : e * f", "(a || b) ? (c + d) : (e * f)");
+    parses_like("x = a + b * c", "x = (a + (b * c))");
+    parses_like("x = y = a && b", "x = (y = (a && b))");
+  }
+
+  #[test]
+  fn test_doc_comments() {
+    let mut lexer = Lexer::new(String::from("
+      /// Doubles a number.
+      ///
+      ///   double(2) // => 4
+      fn double(x) { x * 2 };
+
+      // not a doc comment
+      //// nor this
+      let y = 1;
+      /// separated by code
+      /* and comments */
+      let z = 2;
+    "));
+
+    let mut docs = Vec::new();
+
+    loop {
+      let lexeme = lexer.get_next_token().unwrap();
+
+      if let Some(doc) = lexeme.doc {
+        docs.push((lexeme.token.clone(), doc));
+      }
+
+      if lexeme.token == Token::EOF {
+        break;
+      }
+    }
+
+    assert_eq!(
+      vec!(
+        (Token::FnDecl, String::from("Doubles a number.\n\n  double(2) // => 4")),
+        (Token::Let, String::from("separated by code")),
+      ),
+      docs
+    );
+  }
+
+  #[test]
+  fn test_unterminated_block_comment() {
+    let mut lexer = Lexer::new(String::from("1 +\n  /* outer /* inner */\n 2"));
+
+    assert_eq!(Token::Int(1), lexer.get_next_token().unwrap().token);
+    assert_eq!(Token::Plus, lexer.get_next_token().unwrap().token);
+
+    match lexer.get_next_token() {
+      Err(err @ LexerError::UnterminatedComment(..)) => {
+        let span = err.span();
+        assert_eq!((2, 3, 6, 8), (span.line, span.col, span.start, span.end));
+      },
+      other => panic!("expected an unterminated comment, found {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_for_loops() {
+    assert_eq!(
+      Expr::ForIn(
+        Box::new(Expr::Var(String::from("x"))),
+        Box::new(Expr::Bop(
+          BinOp::Range,
+          Box::new(Expr::Val(Val::Int(0))),
+          Box::new(Expr::Var(String::from("n"))),
+        )),
+        Box::new(Expr::Var(String::from("x"))),
+        Box::new(Expr::Val(Val::Undefined)),
+      ),
+      parse("for (x in 0..n) { x }").unwrap()
+    );
+
+    parses_like("for (;;) { a }; b", "for (; true; ) { a }; b");
+    parses_like("for (i = 0; i < n; i = i + 1) { a }", "i = 0; for (; i < n; i = i + 1) { a }");
+    parses_like("for (var i = 0; i < n;) { a }; b", "var i = 0; for (; i < n;) { a }; b");
+  }
+
+  #[test]
+  fn test_compound_assignment() {
+    assert_eq!(
+      Expr::AssignOp(
+        BinOp::Plus,
+        Box::new(Expr::Index(Box::new(Expr::Var(String::from("xs"))), Box::new(Expr::Val(Val::Int(0))))),
+        Box::new(Expr::Bop(
+          BinOp::Times,
+          Box::new(Expr::Var(String::from("a"))),
+          Box::new(Expr::Var(String::from("b"))),
+        )),
+      ),
+      parse("xs[0] += a * b").unwrap()
+    );
+
+    parses_like("x -= y = z", "x -= (y = z)");
+    parses_like("x **= 2; x /= 2; x %= 2; x *= 2", "(x **= 2); (x /= 2); (x %= 2); (x *= 2)");
+    parses_like("x &= 1; x |= 2; x ^= 3; x <<= 4; x >>= 5", "(x &= 1); (x |= 2); (x ^= 3); (x <<= 4); (x >>= 5)");
+  }
+
+  #[test]
+  fn test_match() {
+    use boxx::expr::Pattern;
+
+    assert_eq!(
+      Expr::Match(
+        Box::new(Expr::Var(String::from("x"))),
+        vec![
+          (Pattern::Lit(Val::Int(-1)), None, Expr::Val(Val::Int(0))),
+          (
+            Pattern::List(vec![Pattern::Bind(String::from("a")), Pattern::Wildcard], Some(Box::new(Pattern::Bind(String::from("rest"))))),
+            Some(Expr::Var(String::from("a"))),
+            Expr::Var(String::from("rest")),
+          ),
+          (
+            Pattern::Record(vec![
+              (String::from("x"), Pattern::Bind(String::from("x"))),
+              (String::from("y"), Pattern::Lit(Val::Bool(true))),
+            ]),
+            None,
+            Expr::Var(String::from("x")),
+          ),
+          (Pattern::List(vec![], Some(Box::new(Pattern::Wildcard))), None, Expr::Val(Val::Int(1))),
+        ],
+      ),
+      parse("match x { -1 => 0, [a, _, ..rest] if a => rest, { x, y: true } => x, [..] => 1 }").unwrap()
+    );
+
+    parses_like("match x { _ => a + b }; c", "(match x { _ => (a + b) }); c");
+
+    assert!(parse("match x { [a, a] => 1 }").is_err());
+    assert!(parse("match x { { a, b: a } => 1 }").is_err());
+    assert!(parse("match x { a + 1 => 1 }").is_err());
+  }
+
+  #[test]
+  fn test_try_catch() {
+    assert_eq!(
+      Expr::Try(
+        Box::new(Expr::Throw(Box::new(Expr::Bop(
+          BinOp::Plus,
+          Box::new(Expr::Var(String::from("a"))),
+          Box::new(Expr::Val(Val::Int(1))),
+        )))),
+        Box::new(Expr::Var(String::from("e"))),
+        Box::new(Expr::Var(String::from("e"))),
+      ),
+      parse("try { throw a + 1 } catch (e) { e }").unwrap()
+    );
+
+    parses_like("try { a; b } catch (e) { c }; d", "(try { a; b } catch (e) { c }); d");
+
+    assert!(parse("try { a }").is_err());
+    assert!(parse("try { a } catch { b }").is_err());
+    assert!(parse("try { a } catch (1) { b }").is_err());
+  }
+
+  #[test]
+  fn test_if_without_else() {
+    // the statements after an if are not part of its branch
+    assert_eq!(
+      Expr::Bop(
+        BinOp::Seq,
+        Box::new(Expr::Ternary(
+          Box::new(Expr::Var(String::from("a"))),
+          Box::new(Expr::Var(String::from("b"))),
+          Box::new(Expr::Val(Val::Undefined)),
+        )),
+        Box::new(Expr::Var(String::from("c"))),
+      ),
+      parse("if (a) { b }; c").unwrap()
+    );
+
+    parses_like("if (a) { b } else if (c) { d } else { e }", "a ? b : (c ? d : e)");
+  }
+
+  // prints the parsed input, which must parse back to the same expression
+  fn unparses_as(input: &str, expected: &str) {
+    let e = parse(input).unwrap();
+    assert_eq!(expected, e.to_string(), "{} should print as {}", input, expected);
+    assert_eq!(e, parse(&e.to_string()).unwrap(), "{} should parse back from {}", input, e);
+  }
+
+  #[test]
+  fn test_unparse_operators() {
+    unparses_as("((1 + 2)) * 3", "(1 + 2) * 3");
+    unparses_as("1 + (2 * 3)", "1 + 2 * 3");
+    unparses_as("(a - b) - c", "a - b - c");
+    unparses_as("a - (b - c)", "a - (b - c)");
+    unparses_as("2 ** (3 ** 2)", "2 ** 3 ** 2");
+    unparses_as("(2 ** 3) ** 2", "(2 ** 3) ** 2");
+    unparses_as("-(2 ** 2)", "-2 ** 2");
+    unparses_as("(-2) ** 2", "(-2) ** 2");
+    unparses_as("2 ** -1", "2 ** -1");
+    unparses_as("-(-x)", "- -x");
+    unparses_as("!(a && b) || c", "!(a && b) || c");
+    unparses_as("(a & b) == 0", "(a & b) == 0");
+    unparses_as("a | (b ^ (c & d))", "a | b ^ c & d");
+    unparses_as("(~a << 2) >> 1", "~a << 2 >> 1");
+    unparses_as("0..(n - 1)", "0..n - 1");
+    unparses_as("(0..=n)[0]", "(0..=n)[0]");
+    unparses_as("(1).x", "(1).x");
+  }
+
+  #[test]
+  fn test_unparse_statements() {
+    unparses_as("x = (y = 1)", "x = y = 1");
+    unparses_as("x += (a ? 1 : 2)", "x += a ? 1 : 2");
+    unparses_as("xs[i] **= 2; r.a |= 1", "xs[i] **= 2; r.a |= 1");
+    unparses_as("f((x = 1), [(y = 2)])", "f((x = 1), [(y = 2)])");
+    unparses_as("(a ? b : c) ? d : e", "(a ? b : c) ? d : e");
+    unparses_as("a ? b : (c ? d : e)", "a ? b : c ? d : e");
+    unparses_as("if (a) { b } else { c; d }", "a ? b : { c; d }");
+    unparses_as("if (a) { b }; c", "if (a) { b }; c");
+    unparses_as("{ a; b } + 1", "{ a; b } + 1");
+    unparses_as("({ a; b })(1)", "({ a; b })(1)");
+    unparses_as("(var x = 1; x) + 1", "(var x = 1; x) + 1");
+    unparses_as("print (1 + 2) * 3", "print (1 + 2) * 3");
+    unparses_as("var x = 1;", "var x = 1; undefined");
+    unparses_as("{ x; }", "x;");
+    unparses_as("undefined", "undefined");
+  }
+
+  #[test]
+  fn test_unparse_declarations_and_loops() {
+    unparses_as("fn f(a, b) { return a + b }; f(1, 2)", "fn f(a, b) { return a + b }; f(1, 2)");
+    unparses_as("fn f() { if (x) { return }; 1 }; f", "fn f() { if (x) { return }; 1 }; f");
+    unparses_as("let g = fn(x) { x }; g(1)", "let g = fn(x) { x }; g(1)");
+    unparses_as("fn(x) { x }(1)", "fn(x) { x }(1)");
+    unparses_as("while (i < 3) { i += 1 }; i", "while (i < 3) { i += 1 }; i");
+    unparses_as("for (var i = 0; i < 3; i += 1) { x }", "for (var i = 0; i < 3; i += 1) { x }");
+    unparses_as("for (i = 0;;) { break }; i", "i = 0; for (;;) { break }; i");
+    unparses_as("for (x in [1, 2]) { continue }", "for (x in [1, 2]) { continue }");
+    unparses_as("a ? { while (b) { c } } : d", "a ? { while (b) { c } } : d");
+  }
+
+  #[test]
+  fn test_unparse_literals_and_patterns() {
+    unparses_as("[1, 2.5, true, \"a\\\"b\\n\"]", "[1, 2.5, true, \"a\\\"b\\n\"]");
+    unparses_as("{ a: 1, \"b c\": {}, \"if\": [] }", "{ a: 1, \"b c\": {}, \"if\": [] }");
+    unparses_as(
+      "match x { [a, ..rest] if a > 0 => rest, [..] => -1, { k, \"a b\": _, n: -1.5 } => k, 1 => 2 }",
+      "match x { [a, ..rest] if a > 0 => rest, [..] => -1, { k, \"a b\": _, n: -1.5 } => k, 1 => 2 }"
+    );
+    unparses_as("try { throw 1 } catch (e) { e.message }", "try { throw 1 } catch (e) { e.message }");
+  }
+
+  #[test]
+  fn test_literal_and_block_spans() {
+    let span = |input: &str| match parse(input).unwrap() {
+      Expr::Located(span, _) => Some((span.start, span.end)),
+      _ => None,
+    };
+
+    assert_eq!(Some((2, 4)), span("  42"));
+    assert_eq!(Some((0, 3)), span("1.5"));
+    assert_eq!(Some((0, 5)), span("\"abc\""));
+    assert_eq!(Some((0, 4)), span("true"));
+    assert_eq!(Some((0, 9)), span("undefined"));
+    assert_eq!(Some((0, 8)), span("{ 1; 2 }"));
+  }
 }
@@ -0,0 +1,332 @@
+extern crate boxx;
+
+#[cfg(test)]
+mod tests {
+  extern crate boxx;
+  use boxx::evaluator::{BigStep, Evaluator};
+  use boxx::interpreter::{Engine, Interpreter};
+  use boxx::parser::parser::parse;
+  use std::{mem, slice};
+
+  // xorshift64, so that a failing program can be generated again from its
+  // seed without any dependencies
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+      (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+      items[self.below(items.len())]
+    }
+  }
+
+  const BINARY_OPS: &[&str] = &[
+    "+", "-", "*", "/", "%", "**", "&", "|", "^", "<<", ">>",
+    "<", ">", "<=", ">=", "==", "!=", "&&", "||", "..", "..=",
+  ];
+
+  // mostly integers, so that more programs get past their first operator
+  const LITERALS: &[&str] = &[
+    "0", "1", "2", "3", "7", "(-1)", "(-4)", "0", "1", "2", "3", "7", "(-1)", "(-4)",
+    "1.5", "0.0", "(-2.5)", "true", "false", "\"\"", "\"ab\"", "\"x\"",
+  ];
+
+  // only literals are iterated over, so that no loop runs for long
+  const ITERABLES: &[&str] = &["0..3", "0..=2", "[1, 2]", "[]", "[[1], [2, 3]]", "\"ab\"", "5"];
+
+  // Random well formed programs. Every one of them finishes: functions can
+  // only call the functions declared before them, and loops only run over
+  // literals or count up to a small bound with a counter the body can't see.
+  // They can still fail in any way a program can at runtime, by using names
+  // that aren't bound, mixing types, throwing, and so on.
+  struct Gen {
+    rng: Rng,
+    // the values in scope, and the functions with their number of params
+    vars: Vec<String>,
+    fns: Vec<(String, usize)>,
+    names: usize,
+    // whether break and continue, or return, can be used here
+    in_loop: bool,
+    in_fn: bool,
+  }
+
+  impl Gen {
+    fn new(seed: u64) -> Gen {
+      Gen {
+        rng: Rng(seed),
+        vars: Vec::new(),
+        fns: Vec::new(),
+        names: 0,
+        in_loop: false,
+        in_fn: false,
+      }
+    }
+
+    // names can't hold digits, so the counter is written in letters
+    fn fresh(&mut self, prefix: &str) -> String {
+      let mut name = format!("{}_", prefix);
+      let mut n = self.names;
+      self.names += 1;
+
+      loop {
+        name.push((b'a' + (n % 26) as u8) as char);
+        n /= 26;
+        if n == 0 {
+          return name;
+        }
+      }
+    }
+
+    fn program(&mut self) -> String {
+      let mut out = String::new();
+
+      for _ in 0..self.rng.below(3) {
+        let name = self.fresh("f");
+        let params: Vec<String> = (0..self.rng.below(3)).map(|_| self.fresh("p")).collect();
+        let body = self.function_body(&params, 3);
+        out += &format!("fn {}({}) {{ {} }}; ", name, params.join(", "), body);
+        self.fns.push((name, params.len()));
+      }
+
+      for _ in 0..self.rng.below(3) {
+        let name = self.fresh("v");
+        let decl = self.rng.pick(&["var", "let"]);
+        out += &format!("{} {} = {}; ", decl, name, self.expr(2));
+        self.vars.push(name);
+      }
+
+      out + &self.expr(3)
+    }
+
+    fn function_body(&mut self, params: &[String], depth: usize) -> String {
+      let vars = self.vars.len();
+      self.vars.extend(params.iter().cloned());
+      let in_loop = mem::replace(&mut self.in_loop, false);
+      let in_fn = mem::replace(&mut self.in_fn, true);
+
+      let body = self.expr(depth);
+
+      self.vars.truncate(vars);
+      self.in_loop = in_loop;
+      self.in_fn = in_fn;
+      body
+    }
+
+    // an expression with the names in scope while generating it
+    fn scoped(&mut self, names: &[String], depth: usize) -> String {
+      let vars = self.vars.len();
+      self.vars.extend(names.iter().cloned());
+      let e = self.expr(depth);
+      self.vars.truncate(vars);
+      e
+    }
+
+    fn loop_body(&mut self, names: &[String], depth: usize) -> String {
+      let in_loop = mem::replace(&mut self.in_loop, true);
+      let body = self.scoped(names, depth);
+      self.in_loop = in_loop;
+      body
+    }
+
+    fn atom(&mut self) -> String {
+      match self.rng.below(3) {
+        0 if !self.vars.is_empty() => {
+          let i = self.rng.below(self.vars.len());
+          self.vars[i].clone()
+        },
+        // a name that is never bound
+        1 if self.rng.below(10) == 0 => "nope".to_string(),
+        _ => self.rng.pick(LITERALS).to_string(),
+      }
+    }
+
+    // usually a comparison, so that it is a bool
+    fn condition(&mut self, depth: usize) -> String {
+      match self.rng.below(4) {
+        0 => self.expr(depth),
+        _ => {
+          let op = self.rng.pick(&["<", "<=", "==", "!="]);
+          format!("({} {} {})", self.expr(depth), op, self.expr(depth))
+        },
+      }
+    }
+
+    fn pattern(&mut self, names: &mut Vec<String>) -> String {
+      match self.rng.below(6) {
+        0 => "_".to_string(),
+        1 => {
+          let name = self.fresh("m");
+          names.push(name.clone());
+          name
+        },
+        2 => {
+          let first = self.pattern(names);
+          let rest = self.fresh("m");
+          names.push(rest.clone());
+          format!("[{}, ..{}]", first, rest)
+        },
+        3 => {
+          let items: Vec<String> = (0..self.rng.below(3)).map(|_| self.pattern(names)).collect();
+          format!("[{}]", items.join(", "))
+        },
+        4 => {
+          let key = self.rng.pick(&["a", "b"]);
+          let p = self.pattern(names);
+          format!("{{ {}: {} }}", key, p)
+        },
+        _ => self.rng.pick(&["0", "1", "-1", "true", "\"ab\"", "1.5"]).to_string(),
+      }
+    }
+
+    fn expr(&mut self, depth: usize) -> String {
+      if depth == 0 {
+        return self.atom();
+      }
+
+      let d = depth - 1;
+
+      match self.rng.below(22) {
+        0..=3 => match self.rng.pick(BINARY_OPS) {
+          op @ "&&" | op @ "||" => format!("({} {} {})", self.condition(d), op, self.condition(d)),
+          op => format!("({} {} {})", self.expr(d), op, self.expr(d)),
+        },
+        4 => {
+          let op = self.rng.pick(&["-", "!", "~"]);
+          format!("({}{})", op, self.expr(d))
+        },
+        5 => format!("({} ? {} : {})", self.condition(d), self.expr(d), self.expr(d)),
+        6 => {
+          let items: Vec<String> = (0..self.rng.below(4)).map(|_| self.expr(d)).collect();
+          format!("[{}]", items.join(", "))
+        },
+        7 => format!("{{ a: {}, b: {} }}", self.expr(d), self.expr(d)),
+        8 => format!("({})[{}]", self.expr(d), self.expr(d)),
+        9 => {
+          let field = self.rng.pick(&["a", "b", "c"]);
+          format!("({}).{}", self.expr(d), field)
+        },
+        10 => {
+          let (f, arity) = match self.fns.len() {
+            0 => (self.rng.pick(&["len", "str", "slice"]).to_string(), 1),
+            n => self.fns[self.rng.below(n)].clone(),
+          };
+
+          // sometimes with the wrong number of arguments
+          let n = match self.rng.below(8) {
+            0 => arity + 1,
+            1 => arity.saturating_sub(1),
+            _ => arity,
+          };
+
+          let args: Vec<String> = (0..n).map(|_| self.expr(d)).collect();
+          format!("{}({})", f, args.join(", "))
+        },
+        11 => format!("(if ({}) {{ {} }} else {{ {} }})", self.condition(d), self.expr(d), self.expr(d)),
+        12 => {
+          let scrutinee = self.expr(d);
+          let mut arms = Vec::new();
+
+          for _ in 0..1 + self.rng.below(3) {
+            let mut names = Vec::new();
+            let pattern = self.pattern(&mut names);
+            let guard = match self.rng.below(3) {
+              0 => {
+                let vars = self.vars.len();
+                self.vars.extend(names.iter().cloned());
+                let guard = self.condition(d);
+                self.vars.truncate(vars);
+                format!(" if {}", guard)
+              },
+              _ => String::new(),
+            };
+            arms.push(format!("{}{} => {}", pattern, guard, self.scoped(&names, d)));
+          }
+
+          format!("(match {} {{ {} }})", scrutinee, arms.join(", "))
+        },
+        13 => {
+          let e = self.fresh("e");
+          let body = self.expr(d);
+          let handler = self.scoped(slice::from_ref(&e), d);
+          format!("(try {{ {} }} catch ({}) {{ {} }})", body, e, handler)
+        },
+        14 => format!("(throw {})", self.expr(d)),
+        15 => {
+          let name = self.fresh("v");
+          let decl = self.rng.pick(&["var", "let"]);
+          let e = self.expr(d);
+          format!("{{ {} {} = {}; {} }}", decl, name, e, self.scoped(slice::from_ref(&name), d))
+        },
+        16 => {
+          let x = self.fresh("i");
+          let iterable = self.rng.pick(ITERABLES);
+          let body = self.loop_body(slice::from_ref(&x), d);
+          format!("{{ for ({} in {}) {{ {} }}; {} }}", x, iterable, body, self.expr(d))
+        },
+        17 => {
+          let counter = self.fresh("w");
+          let body = self.loop_body(&[], d);
+          let rest = self.expr(d);
+
+          match self.rng.below(2) {
+            0 => format!("{{ var {c} = 0; while ({c} < 3) {{ {c} += 1; {} }}; {} }}", body, rest, c = counter),
+            _ => format!("{{ for (var {c} = 0; {c} < 3; {c} += 1) {{ {} }}; {} }}", body, rest, c = counter),
+          }
+        },
+        18 if !self.vars.is_empty() => {
+          let x = self.vars[self.rng.below(self.vars.len())].clone();
+          let target = match self.rng.below(3) {
+            0 => format!("{}[{}]", x, self.expr(0)),
+            1 => format!("{}.a", x),
+            _ => x,
+          };
+          let op = self.rng.pick(&["=", "+=", "-=", "*=", "|="]);
+          format!("({} {} {})", target, op, self.expr(d))
+        },
+        19 => {
+          let p = self.fresh("p");
+          let body = self.function_body(slice::from_ref(&p), d);
+          format!("(fn({}) {{ {} }}({}))", p, body, self.expr(d))
+        },
+        20 if self.in_loop => self.rng.pick(&["break", "continue"]).to_string(),
+        21 if self.in_fn => format!("(return {})", self.expr(d)),
+        _ => self.atom(),
+      }
+    }
+  }
+
+  // Debug output is compared, since functions only equal themselves and
+  // errors ignore their spans and backtraces. The big step evaluator keeps
+  // no backtraces, so it is compared without them.
+  fn run(evaluator: &mut dyn Evaluator, program: &str) -> (String, String) {
+    let result = evaluator.eval(program);
+    let untraced = format!("{:?}", result.as_ref().map_err(|err| err.without_backtrace()));
+    (format!("{:?}", result), untraced)
+  }
+
+  #[test]
+  pub fn test_evaluators_agree() {
+    for seed in 1..=1000 {
+      let program = Gen::new(seed).program();
+
+      let parsed = parse(&program).unwrap();
+      assert_eq!(Ok(parsed.clone()), parse(&parsed.to_string()), "seed {} prints {} as {}", seed, program, parsed);
+
+      let (expected, untraced) = run(&mut Interpreter::new(), &program);
+      let (_, big_step) = run(&mut BigStep::new(), &program);
+      let (bytecode, _) = run(&mut Interpreter::builder().engine(Engine::Bytecode).build(), &program);
+
+      assert_eq!(untraced, big_step, "big step evaluation of seed {} disagrees on {}", seed, program);
+      assert_eq!(expected, bytecode, "bytecode evaluation of seed {} disagrees on {}", seed, program);
+    }
+  }
+}